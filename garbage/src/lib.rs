@@ -6,6 +6,7 @@ use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::fmt::Formatter;
 use std::marker::{PhantomData, Unsize};
+use std::mem;
 use std::ops::{CoerceUnsized, Deref, DispatchFromDyn};
 use std::ptr::NonNull;
 
@@ -38,7 +39,7 @@ impl<T: MarkTrace + ?Sized + 'static> Gc<T> {
     }
 }
 
-impl<T: MarkTrace + 'static> MarkTrace for Gc<T> {
+impl<T: MarkTrace + ?Sized + 'static> MarkTrace for Gc<T> {
     fn mark_trace(&self) {
         unsafe {
             let managed_box: &ManagedData<T> = self.ptr.as_ref();
@@ -70,6 +71,12 @@ impl<T: MarkTrace + ?Sized> Clone for Gc<T> {
 pub trait MarkTrace {
     /// Call mark_trace on all children
     fn mark_trace(&self);
+
+    /// Bytes owned outside of the pool, like the buffer of a `String`. This is counted in the
+    /// pool's size when the object is placed in it and whenever `ManagedPool::resize` is called.
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
 impl<T: MarkTrace + ?Sized + 'static> MarkTrace for dyn Deref<Target = T> {
@@ -82,10 +89,18 @@ impl<T: MarkTrace + ?Sized + 'static> MarkTrace for RefCell<T> {
     fn mark_trace(&self) {
         self.borrow().mark_trace();
     }
+
+    fn heap_size(&self) -> usize {
+        self.borrow().heap_size()
+    }
 }
 
 impl MarkTrace for String {
     fn mark_trace(&self) {}
+
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
 }
 
 impl<T: MarkTrace> MarkTrace for Vec<T> {
@@ -94,10 +109,16 @@ impl<T: MarkTrace> MarkTrace for Vec<T> {
             item.mark_trace();
         }
     }
+
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
 }
 
 struct ManagedData<T: MarkTrace + ?Sized> {
     flag: Cell<bool>,
+    /// What `data.heap_size()` was when it was last counted in the pool's size
+    counted: Cell<usize>,
     data: T,
 }
 
@@ -116,6 +137,7 @@ impl<T: MarkTrace + 'static> ManagedData<T> {
     fn wrap_data(data: T) -> NonNull<ManagedData<T>> {
         let contents = ManagedData {
             flag: Cell::new(false),
+            counted: Cell::new(data.heap_size()),
             data,
         };
         NonNull::new(Box::into_raw(Box::new(contents))).unwrap()
@@ -125,35 +147,60 @@ impl<T: MarkTrace + 'static> ManagedData<T> {
 #[derive(Debug)]
 pub struct ManagedPool {
     pool: Vec<NonNull<ManagedData<dyn MarkTrace>>>,
+    size: usize,
 }
 
 impl ManagedPool {
     pub fn new() -> Self {
-        ManagedPool { pool: Vec::new() }
+        ManagedPool {
+            pool: Vec::new(),
+            size: 0,
+        }
     }
 
     pub fn place_in_heap<T: MarkTrace + 'static>(&mut self, data: T) -> Gc<T> {
         let managed_box: NonNull<ManagedData<T>> = ManagedData::<T>::wrap_data(data);
         let into_pool: NonNull<ManagedData<dyn MarkTrace>> = managed_box;
         self.pool.push(into_pool);
+        let counted = unsafe { managed_box.as_ref().counted.get() };
+        self.size += mem::size_of::<ManagedData<T>>() + counted;
         Gc::new(managed_box)
     }
 
+    /// Counts the memory an object owns outside of the pool again, after it has grown or shrunk
+    pub fn resize<T: MarkTrace + ?Sized + 'static>(&mut self, object: &Gc<T>) {
+        let managed_box = unsafe { object.ptr.as_ref() };
+        let heap_size = managed_box.data.heap_size();
+        self.size = self.size - managed_box.counted.get() + heap_size;
+        managed_box.counted.set(heap_size);
+    }
+
+    /// Bytes taken up by the objects in the pool, including the memory they own outside of it
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     pub fn collect_garbage<'a, I>(&mut self, anchors: I)
     where
         I: IntoIterator<Item = &'a Gc<dyn MarkTrace>>,
     {
-        println!("Recursive Marking");
         // For every rooted object, recursively mark all objects, stopping a branch if an object is already marked
         for anchor in anchors {
-            let anchor = unsafe { anchor.ptr.as_ref() };
-            if !anchor.get_flag() {
-                anchor.set_flag(true);
-                anchor.data.mark_trace();
-            }
+            anchor.mark_trace();
         }
+        self.drop_unmarked();
+    }
+
+    /// Drops every object `root` can't reach, for roots which aren't in the pool themselves.
+    /// Returns how many bytes were freed.
+    pub fn collect_from(&mut self, root: &dyn MarkTrace) -> usize {
+        let before = self.size;
+        root.mark_trace();
+        self.drop_unmarked();
+        before - self.size
+    }
 
-        println!("Dropping Unmarked");
+    fn drop_unmarked(&mut self) {
         // Drop all non-marked objects, unmarking all objects in the process
         unsafe {
             let mut to_drop = Vec::new();
@@ -168,6 +215,8 @@ impl ManagedPool {
             });
 
             to_drop.into_iter().for_each(|nn_ptr| {
+                let obj = &*nn_ptr.as_ptr();
+                self.size -= mem::size_of_val(obj) + obj.counted.get();
                 drop(Box::from_raw(nn_ptr.as_ptr()));
             })
         }
@@ -74,3 +74,23 @@ fn basic_gc_test() {
 
     println!("Done");
 }
+
+#[test]
+fn owned_size_test() {
+    let mut pool = ManagedPool::new();
+    let list = pool.place_in_heap(RefCell::new(Vec::<String>::with_capacity(4)));
+    let empty = pool.size();
+    list.borrow_mut().push(String::from("counted"));
+    assert_eq!(pool.size(), empty);
+
+    pool.resize(&list);
+    let grown = pool.size();
+    assert!(grown >= empty + "counted".len());
+
+    list.borrow_mut().extend((0..1000).map(|n| n.to_string()));
+    pool.resize(&list);
+    assert!(pool.size() > grown + 1000 * std::mem::size_of::<String>());
+
+    pool.collect_garbage(std::iter::empty());
+    assert_eq!(pool.size(), 0);
+}
@@ -0,0 +1,61 @@
+//! `pusl check`, which reports the mistakes in source files without running them
+
+use std::fs;
+use std::io::{self, Write};
+use std::panic;
+use std::path::Path;
+
+use pusl_lang::backend::check::{check, Diagnostic, Severity};
+use serde_json::json;
+
+/// Whether an import in the file at `path` leads to a source or bytecode file. Imports are
+/// found relative to the directory of the file importing them.
+fn resolves(path: &Path, import: &[String]) -> bool {
+    let mut target = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    target.extend(import);
+    ["pusl", "puslc"]
+        .iter()
+        .any(|extension| target.with_extension(extension).is_file())
+}
+
+fn write_text(out: &mut impl Write, path: &str, diagnostic: &Diagnostic) -> io::Result<()> {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    writeln!(
+        out,
+        "{}:{}:{}: {}[{}]: {}",
+        path, diagnostic.line, diagnostic.column, severity, diagnostic.code, diagnostic.message
+    )
+}
+
+/// Checks each file, writing what's found as text or as a JSON list. Returns whether any file
+/// had errors.
+pub fn run(paths: &[&str], is_json: bool, out: &mut impl Write) -> io::Result<bool> {
+    // Mistakes are found by catching the compiler's panics, which shouldn't be printed
+    panic::set_hook(Box::new(|_| {}));
+    let mut has_errors = false;
+    let mut found = Vec::new();
+    for &path in paths {
+        let source = fs::read_to_string(path)?;
+        let diagnostics = check(&source, &|import| resolves(Path::new(path), import));
+        has_errors |= diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error);
+        for diagnostic in diagnostics {
+            if is_json {
+                let mut value = json!(diagnostic);
+                value["file"] = json!(path);
+                found.push(value);
+            } else {
+                write_text(out, path, &diagnostic)?;
+            }
+        }
+    }
+    let _ = panic::take_hook();
+    if is_json {
+        writeln!(out, "{}", json!(found))?;
+    }
+    Ok(has_errors)
+}
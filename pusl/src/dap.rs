@@ -0,0 +1,602 @@
+//! A Debug Adapter Protocol server, so editors can debug pusl scripts.
+//! Messages are read from stdin and written to stdout, each is a `Content-Length` header
+//! followed by a JSON body. There is one thread, and execution only happens while a request is
+//! being handled, so messages are answered in the order they arrive.
+
+use crate::read_program;
+use pusl_lang::backend::debug::{self, DebugEvent, Debugger, Resume};
+use pusl_lang::backend::object::Value;
+use pusl_lang::backend::{startup, ExecContext, ExecStateRef, ExecuteReturn};
+use serde_json::{json, Value as Json};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+const THREAD_ID: i64 = 1;
+
+struct Connection<'c> {
+    input: &'c mut dyn BufRead,
+    output: &'c mut dyn Write,
+    seq: i64,
+}
+
+impl<'c> Connection<'c> {
+    /// The next message, None once the client has closed the connection
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let length = length.ok_or_else(|| invalid_data("Missing Content-Length"))?;
+        let mut body = vec![0; length];
+        self.input.read_exact(&mut body)?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|err| invalid_data(&err.to_string()))
+    }
+
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Collects what the script prints so it can be sent as output events
+#[derive(Clone, Default)]
+struct ScriptOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for ScriptOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Settings the client sends before the script starts
+struct Configuration {
+    debugger: Debugger,
+    /// The breakpoint ids set in each source, so they can be replaced together
+    breakpoints: HashMap<String, Vec<usize>>,
+    /// Stop when an error isn't caught, rather than ending the script. On unless the client
+    /// turns it off, like the "uncaught" filter says.
+    break_on_uncaught: bool,
+    is_done: bool,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            debugger: Debugger::new(),
+            breakpoints: HashMap::new(),
+            break_on_uncaught: true,
+            is_done: false,
+        }
+    }
+}
+
+impl Configuration {
+    /// Handles the requests which don't need a running script, false if it wasn't one of them
+    fn handle(&mut self, connection: &mut Connection, request: &Json) -> io::Result<bool> {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                connection.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                        "exceptionBreakpointFilters": [{
+                            "filter": "uncaught",
+                            "label": "Uncaught Errors",
+                            "default": true,
+                        }],
+                    }),
+                )?;
+                connection.event("initialized", json!({}))?;
+            }
+            "setBreakpoints" => {
+                let source = arguments["source"]["path"]
+                    .as_str()
+                    .or_else(|| arguments["source"]["name"].as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                for id in self.breakpoints.remove(&source).unwrap_or_default() {
+                    self.debugger.remove_breakpoint(id);
+                }
+                let lines = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                let mut ids = Vec::new();
+                let mut breakpoints = Vec::new();
+                for line in lines {
+                    let id = self.debugger.add_breakpoint(source.clone(), line as usize);
+                    ids.push(id);
+                    breakpoints.push(json!({ "id": id, "verified": true, "line": line }));
+                }
+                self.breakpoints.insert(source, ids);
+                connection.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "setExceptionBreakpoints" => {
+                self.break_on_uncaught = arguments["filters"]
+                    .as_array()
+                    .is_some_and(|filters| filters.iter().any(|filter| filter == "uncaught"));
+                connection.respond(request, json!({}))?;
+            }
+            "configurationDone" => {
+                self.is_done = true;
+                connection.respond(request, json!({}))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// What the variables reference handed to the client refers to
+enum Container {
+    Locals(usize),
+    Bound(usize),
+    Stack(usize),
+    Value(Value),
+}
+
+struct Session<'c, 'a> {
+    connection: Connection<'c>,
+    configuration: Configuration,
+    state: ExecStateRef<'a>,
+    script_output: ScriptOutput,
+    /// The main module's path as the client gave it
+    program: String,
+    /// The main module's path as the state names it
+    program_module: String,
+    stop_on_entry: bool,
+    /// Handed out while stopped, the reference is the index plus one
+    containers: Vec<Container>,
+    /// The error which ended the script, while it can still be inspected
+    uncaught: Option<Value>,
+    is_finished: bool,
+}
+
+impl<'c, 'a> Session<'c, 'a> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut has_started = false;
+        loop {
+            if !has_started && self.configuration.is_done {
+                has_started = true;
+                if self.stop_on_entry {
+                    self.stopped("entry", json!({}))?;
+                } else {
+                    self.resume(Resume::Continue)?;
+                }
+            }
+            let request = match self.connection.read()? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            if request["type"] != "request"
+                || self.configuration.handle(&mut self.connection, &request)?
+            {
+                continue;
+            }
+            if !self.handle(&request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns false once the client disconnects
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let resume = match command {
+            "continue" => Some(Resume::Continue),
+            "next" => Some(Resume::StepOver),
+            "stepIn" => Some(Resume::StepIn),
+            "stepOut" => Some(Resume::StepOut),
+            _ => None,
+        };
+        if let Some(resume) = resume {
+            if self.is_finished {
+                return self
+                    .connection
+                    .respond_error(request, "The script is not running")
+                    .map(|_| true);
+            }
+            let body = if command == "continue" {
+                json!({ "allThreadsContinued": true })
+            } else {
+                json!({})
+            };
+            self.connection.respond(request, body)?;
+            self.resume(resume)?;
+            return Ok(true);
+        }
+        match command {
+            "disconnect" | "terminate" => {
+                self.connection.respond(request, json!({}))?;
+                if command == "terminate" && !self.is_finished {
+                    self.finish(1)?;
+                }
+                return Ok(command == "terminate");
+            }
+            "threads" => self.connection.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            )?,
+            "stackTrace" => {
+                let frames = self.stack_trace();
+                let total = frames.len();
+                self.connection.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                )?
+            }
+            "scopes" => {
+                let depth = self.depth(&arguments["frameId"]);
+                let locals = self.container(Container::Locals(depth));
+                let bound = self.container(Container::Bound(depth));
+                let stack = self.container(Container::Stack(depth));
+                self.connection.respond(
+                    request,
+                    json!({ "scopes": [
+                        { "name": "Locals", "variablesReference": locals, "expensive": false },
+                        { "name": "Bound", "variablesReference": bound, "expensive": false },
+                        { "name": "Operand Stack", "variablesReference": stack, "expensive": false },
+                    ]}),
+                )?
+            }
+            "variables" => {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or_default();
+                let variables = self.variables(reference as usize);
+                self.connection
+                    .respond(request, json!({ "variables": variables }))?
+            }
+            "evaluate" => {
+                let depth = self.depth(&arguments["frameId"]);
+                let expression = arguments["expression"].as_str().unwrap_or_default();
+                match debug::evaluate(self.state, depth, expression) {
+                    Ok(value) => {
+                        let variable = self.variable(String::new(), value);
+                        self.connection.respond(
+                            request,
+                            json!({
+                                "result": variable["value"],
+                                "type": variable["type"],
+                                "variablesReference": variable["variablesReference"],
+                            }),
+                        )?;
+                    }
+                    Err(message) => self.connection.respond_error(request, &message)?,
+                }
+                self.send_output()?;
+            }
+            "exceptionInfo" => match &self.uncaught {
+                Some(error) => {
+                    let description = debug::describe_error(&self.state.borrow(), error);
+                    self.connection.respond(
+                        request,
+                        json!({
+                            "exceptionId": description.split(':').next(),
+                            "description": description,
+                            "breakMode": "unhandled",
+                        }),
+                    )?
+                }
+                None => self.connection.respond_error(request, "No error")?,
+            },
+            _ => self
+                .connection
+                .respond_error(request, &format!("Unsupported request \"{}\"", command))?,
+        }
+        Ok(true)
+    }
+
+    fn resume(&mut self, resume: Resume) -> io::Result<()> {
+        self.containers.clear();
+        if let Some(error) = self.uncaught.take() {
+            // Resuming after an uncaught error ends the script
+            self.report_error(&error)?;
+            return self.finish(1);
+        }
+        let event = self.configuration.debugger.resume(self.state, resume);
+        self.send_output()?;
+        match event {
+            DebugEvent::Breakpoint(id) => {
+                self.stopped("breakpoint", json!({ "hitBreakpointIds": [id] }))
+            }
+            DebugEvent::Step | DebugEvent::RecordingStart => self.stopped("step", json!({})),
+            DebugEvent::Finished(ExecuteReturn::Error(error)) => {
+                if self.configuration.break_on_uncaught {
+                    let description = debug::describe_error(&self.state.borrow(), &error);
+                    self.uncaught = Some(error);
+                    self.stopped(
+                        "exception",
+                        json!({ "description": "Uncaught error", "text": description }),
+                    )
+                } else {
+                    self.report_error(&error)?;
+                    self.finish(1)
+                }
+            }
+            DebugEvent::Finished(ExecuteReturn::LimitExceeded(limit)) => {
+                let message = format!("Execution stopped: {:?} limit exceeded\n", limit);
+                self.connection
+                    .event("output", json!({ "category": "stderr", "output": message }))?;
+                self.finish(1)
+            }
+            DebugEvent::Finished(ExecuteReturn::Exit(code)) => self.finish(code as i32),
+            DebugEvent::Finished(_) => self.finish(0),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, mut body: Json) -> io::Result<()> {
+        body["reason"] = json!(reason);
+        body["threadId"] = json!(THREAD_ID);
+        body["allThreadsStopped"] = json!(true);
+        self.connection.event("stopped", body)
+    }
+
+    fn report_error(&mut self, error: &Value) -> io::Result<()> {
+        let message = {
+            let state = self.state.borrow();
+            format!(
+                "{}{}\n",
+                debug::traceback(&state),
+                debug::describe_error(&state, error)
+            )
+        };
+        self.connection
+            .event("output", json!({ "category": "stderr", "output": message }))
+    }
+
+    fn finish(&mut self, exit_code: i32) -> io::Result<()> {
+        self.is_finished = true;
+        self.connection
+            .event("exited", json!({ "exitCode": exit_code }))?;
+        self.connection.event("terminated", json!({}))
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let output = std::mem::take(&mut *self.script_output.0.borrow_mut());
+        if output.is_empty() {
+            return Ok(());
+        }
+        let output = String::from_utf8_lossy(&output).into_owned();
+        self.connection
+            .event("output", json!({ "category": "stdout", "output": output }))
+    }
+
+    /// Frame ids are one more than the frame's depth, so they're never 0
+    fn depth(&self, frame_id: &Json) -> usize {
+        frame_id.as_u64().unwrap_or(1).saturating_sub(1) as usize
+    }
+
+    fn stack_trace(&self) -> Vec<Json> {
+        let state = self.state.borrow();
+        (0..debug::frame_count(&state))
+            .filter_map(|depth| {
+                let location = if self.uncaught.is_some() {
+                    debug::error_location(&state, depth)
+                } else {
+                    debug::location(&state, depth)
+                };
+                location.map(|location| (depth, location))
+            })
+            .map(|(depth, location)| {
+                let name = match &location.function {
+                    Some(args) => format!("fn({})", args.join(", ")),
+                    None => format!("module {}", location.module),
+                };
+                let path = if location.module == self.program_module {
+                    self.program.clone()
+                } else {
+                    location.module.clone()
+                };
+                json!({
+                    "id": depth + 1,
+                    "name": name,
+                    "source": { "name": location.module, "path": path },
+                    "line": location.line.unwrap_or(0),
+                    "column": 1,
+                    "instructionPointerReference": location.index.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn container(&mut self, container: Container) -> usize {
+        self.containers.push(container);
+        self.containers.len()
+    }
+
+    fn variable(&mut self, name: String, value: Value) -> Json {
+        let description = debug::describe_value(&self.state.borrow(), &value);
+        let type_name = value.type_string();
+        let reference = if debug::children(&value).is_empty() {
+            0
+        } else {
+            self.container(Container::Value(value))
+        };
+        json!({
+            "name": name,
+            "value": description,
+            "type": type_name,
+            "variablesReference": reference,
+        })
+    }
+
+    fn variables(&mut self, reference: usize) -> Vec<Json> {
+        let values = {
+            let state = self.state.borrow();
+            match reference
+                .checked_sub(1)
+                .and_then(|index| self.containers.get(index))
+            {
+                Some(Container::Locals(depth)) => debug::frame_variables(&state, *depth)
+                    .map(|variables| {
+                        let this = variables.this.map(|this| ("this".to_string(), this));
+                        this.into_iter().chain(variables.locals).collect()
+                    })
+                    .unwrap_or_default(),
+                Some(Container::Bound(depth)) => debug::frame_variables(&state, *depth)
+                    .map(|variables| variables.bound)
+                    .unwrap_or_default(),
+                Some(Container::Stack(depth)) => debug::frame_variables(&state, *depth)
+                    .map(|variables| {
+                        variables
+                            .op_stack
+                            .into_iter()
+                            .enumerate()
+                            .map(|(index, value)| (index.to_string(), value))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                Some(Container::Value(value)) => debug::children(value),
+                None => Vec::new(),
+            }
+        };
+        values
+            .into_iter()
+            .map(|(name, value)| self.variable(name, value))
+            .collect()
+    }
+}
+
+/// Serves one debugging session, returning once the client disconnects
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+    let mut connection = Connection {
+        input,
+        output,
+        seq: 0,
+    };
+    let mut configuration = Configuration::default();
+
+    // Nothing can be inspected until the script is launched
+    let request = loop {
+        let request = match connection.read()? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if request["type"] != "request" || configuration.handle(&mut connection, &request)? {
+            continue;
+        }
+        match request["command"].as_str().unwrap_or_default() {
+            "launch" => break request,
+            "disconnect" => return connection.respond(&request, json!({})),
+            "threads" => connection.respond(&request, json!({ "threads": [] }))?,
+            _ => connection.respond_error(&request, "The script hasn't been launched")?,
+        }
+    };
+    let arguments = &request["arguments"];
+    let program = match arguments["program"].as_str() {
+        Some(program) => program.to_string(),
+        None => return connection.respond_error(&request, "Missing \"program\" to launch"),
+    };
+    let path = PathBuf::from(&program);
+    let bcf = match read_program(&path, 0) {
+        Ok(bcf) => bcf,
+        Err(err) => {
+            let message = format!("Unable to load {}: {}", program, err);
+            return connection.respond_error(&request, &message);
+        }
+    };
+    // Named the same way as startup names it
+    let program_module = path
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let script_output = ScriptOutput::default();
+    let mut stream = script_output.clone();
+    let args = arguments["args"]
+        .as_array()
+        .map(|args| {
+            args.iter()
+                .filter_map(|arg| arg.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    let ctx = ExecContext {
+        stream: Some(&mut stream),
+        args,
+        ..Default::default()
+    };
+    let state = startup(bcf, path, ctx);
+    connection.respond(&request, json!({}))?;
+
+    let mut session = Session {
+        connection,
+        configuration,
+        state: &state,
+        script_output,
+        program,
+        program_module,
+        stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        containers: Vec::new(),
+        uncaught: None,
+        is_finished: false,
+    };
+    session.run()
+}
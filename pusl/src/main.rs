@@ -1,19 +1,33 @@
+mod check;
+mod dap;
+mod repl;
+
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use pusl_lang::backend::{
-    execute,
+    assembly::{assemble, disassemble},
+    coverage::Coverage,
+    debug, execute,
     linearize::{linearize_file, ByteCodeFile},
-    startup, ExecContext, debug,
+    observe::{Observer, Tracer},
+    optimize::optimize,
+    profile::Profiler,
+    startup, ExecContext, ExecuteReturn,
 };
 use pusl_lang::lexer::lex;
 use pusl_lang::parser::parse;
+use serde::Serialize;
+use std::fmt::Debug;
 use std::io;
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use std::{fs::File, io::Seek};
 
-const MAJOR_VERSION: u16 = 1; // Bytecode to run must match
-const MINOR_VERSION: u16 = 1; // Ok to run bytecode where bytecode minor version < interpreter minor version
+const MAJOR_VERSION: u16 = 4; // Bytecode to run must match
+const MINOR_VERSION: u16 = 0; // Ok to run bytecode where bytecode minor version < interpreter minor version
 
 const MAGIC_NUMBER: &[u8] = "pusl".as_bytes();
 
@@ -64,6 +78,8 @@ fn write_to_code_path(path: &PathBuf, base_func: &ByteCodeFile, verbosity: u64)
     Ok(())
 }
 
+// The minor version check is always true while MINOR_VERSION is 0
+#[allow(clippy::absurd_extreme_comparisons)]
 fn load_code_from_path(
     path: &PathBuf,
     mut reader: impl BufRead,
@@ -93,6 +109,69 @@ fn load_code_from_path(
     Ok(function)
 }
 
+/// Loads bytecode or compiles source, depending on what the file is
+fn read_program(path: &PathBuf, verbosity: u64) -> io::Result<ByteCodeFile> {
+    let (reader, magic) = open_code_or_source(path, verbosity)?;
+    if magic == MAGIC_NUMBER {
+        load_code_from_path(path, reader, verbosity)
+    } else {
+        compile_from_source(path, reader, verbosity)
+    }
+}
+
+/// Finds an imported module as source or bytecode. Module paths are relative to the directory
+/// pusl is run from.
+fn resolve_import(import: Vec<String>) -> Option<ByteCodeFile> {
+    let target = import.iter().collect::<PathBuf>();
+    ["pusl", "puslc"]
+        .iter()
+        .map(|extension| target.with_extension(extension))
+        .find(|path| path.is_file())
+        .map(|path| read_program(&path, 0).expect("Unable to read import"))
+}
+
+/// Loads bytecode or compiles source piped to stdin, depending on what it is
+fn read_program_from_stdin(path: &PathBuf, verbosity: u64) -> io::Result<ByteCodeFile> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    if input.starts_with(MAGIC_NUMBER) {
+        load_code_from_path(path, input.as_slice(), verbosity)
+    } else {
+        compile_from_source(path, input.as_slice(), verbosity)
+    }
+}
+
+/// Writes what a stage of the compiler made, as JSON like the tests compare against or with
+/// `{:#?}`
+fn dump<T: Serialize + Debug>(out: &mut impl Write, value: &T, is_json: bool) -> io::Result<()> {
+    if is_json {
+        serde_json::to_writer_pretty(&mut *out, value)?;
+        writeln!(out)
+    } else {
+        writeln!(out, "{:#?}", value)
+    }
+}
+
+/// The arguments after the program, which the script gets as its args list
+fn script_args(matches: &ArgMatches) -> Vec<String> {
+    let mut args = Vec::new();
+    // With --eval there's no path, so what would be the path is the first argument
+    if matches.is_present("eval") {
+        args.extend(matches.value_of("PATH").map(str::to_string));
+    }
+    if let Some(values) = matches.values_of("ARGS") {
+        args.extend(values.map(str::to_string));
+    }
+    args
+}
+
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("\"{}\" is not a number", value))
+}
+
 fn main() -> io::Result<()> {
     let matches = App::new("pusl")
         .version("0.1.0")
@@ -118,34 +197,185 @@ fn main() -> io::Result<()> {
                         .short("a")
                         .long("analyze")
                         .help("print compiled bytecode rather than writing to disk"),
+                )
+                .arg(
+                    Arg::with_name("opt-level")
+                        .short("O")
+                        .takes_value(true)
+                        .possible_values(&["0", "1", "2"])
+                        .default_value("0")
+                        .help("optimization level: 0 for none, 1 to fold constants and remove redundant code, 2 to also thread jumps and remove unreachable code"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("run")
                 .about("execute a \".pusl\" source file or a compiled \".puslc\" bytecode file")
+                .setting(AppSettings::TrailingVarArg)
                 .arg(
                     Arg::with_name("PATH")
-                        .help("path to the file")
-                        .required(true)
+                        .help("path to the file, or - to read it from stdin")
+                        .required_unless("eval")
                         .index(1),
                 )
+                .arg(
+                    Arg::with_name("ARGS")
+                        .help("arguments given to the script as the args list")
+                        .multiple(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("eval")
+                        .short("e")
+                        .long("eval")
+                        .value_name("CODE")
+                        .help("run CODE as the source of the program instead of a file, PATH is then the first argument"),
+                )
                 .arg(
                     Arg::with_name("analyze")
                         .short("a")
                         .long("analyze")
                         .help("print compiled bytecode rather running it"),
+                )
+                .arg(
+                    Arg::with_name("fuel")
+                        .long("fuel")
+                        .takes_value(true)
+                        .validator(is_number)
+                        .help("stop after executing this many instructions"),
+                )
+                .arg(
+                    Arg::with_name("max-heap")
+                        .long("max-heap")
+                        .takes_value(true)
+                        .validator(is_number)
+                        .help("stop once the heap, counting the memory owned by lists, strings and objects still in use, grows past this many bytes"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .validator(is_number)
+                        .help("stop after running for this many milliseconds"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .help("print the time and instructions spent in each function and line to stderr once done"),
+                )
+                .arg(
+                    Arg::with_name("profile-folded")
+                        .long("profile-folded")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("write the time spent in each call stack to FILE as folded stacks in nanoseconds, for flamegraph tools"),
+                )
+                .arg(
+                    Arg::with_name("coverage")
+                        .long("coverage")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("write the lines, functions and branches of each module which ran to FILE as an lcov report"),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .help("print every instruction, call, return and error to stderr as it happens"),
+                )
+                .arg(
+                    Arg::with_name("trace-stack")
+                        .long("trace-stack")
+                        .help("like --trace, also printing the operand stack before each instruction"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("debug")
                 .about("execute a \".pusl\" source file or a compiled \".puslc.\" bytecode file with the pusl debugger")
+                .setting(AppSettings::TrailingVarArg)
                 .arg(
                     Arg::with_name("PATH")
                         .help("path to the file")
                         .required(true)
                         .index(1),
-                    ),
                 )
+                .arg(
+                    Arg::with_name("ARGS")
+                        .help("arguments given to the script as the args list")
+                        .multiple(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dap")
+                .about("serve the Debug Adapter Protocol over stdin and stdout, for debugging from an editor"),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("run pusl statements interactively, printing the value of each expression"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("report the mistakes in \".pusl\" source files without running them")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("paths to the files")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("how to print what's found"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("print what a stage of the compiler makes from a \".pusl\" source file")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("path to the file, a \".puslc\" bytecode file can be dumped as linear")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("stage")
+                        .long("stage")
+                        .takes_value(true)
+                        .possible_values(&["lex", "parse", "linear"])
+                        .required(true)
+                        .help("the tokens, the syntax tree or the bytecode"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "debug"])
+                        .default_value("json")
+                        .help("print as JSON, which can be read back, or in the Debug format"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("print a \".puslc\" bytecode file (or a \".pusl\" source file) as pusl assembly")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("path to the file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("asm")
+                .about("assemble a \".pasm\" pusl assembly file to a \".puslc\" bytecode file")
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .help("path to the assembly file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .get_matches();
 
     let verbosity = matches.occurrences_of("v");
@@ -155,7 +385,9 @@ fn main() -> io::Result<()> {
             let mut path = PathBuf::from(matches.value_of("SOURCE").unwrap());
 
             let (reader, _magic) = open_code_or_source(&path, verbosity)?;
-            let bcf = compile_from_source(&path, reader, verbosity)?;
+            let mut bcf = compile_from_source(&path, reader, verbosity)?;
+            let level = matches.value_of("opt-level").unwrap().parse().unwrap();
+            optimize(&mut bcf, level);
             if matches.is_present("analyze") {
                 println!("{:#?}", bcf.base_func);
             } else {
@@ -164,41 +396,161 @@ fn main() -> io::Result<()> {
             }
         }
         ("run", Some(matches)) => {
-            let path = PathBuf::from(matches.value_of("PATH").unwrap());
-
-            let (reader, magic) = open_code_or_source(&path, verbosity)?;
-            let bcf = if magic == MAGIC_NUMBER {
-                load_code_from_path(&path, reader, verbosity)?
+            let (path, bcf) = if let Some(code) = matches.value_of("eval") {
+                let path = PathBuf::from("<eval>");
+                let bcf = compile_from_source(&path, code.as_bytes(), verbosity)?;
+                (path, bcf)
+            } else if matches.value_of("PATH") == Some("-") {
+                let path = PathBuf::from("<stdin>");
+                let bcf = read_program_from_stdin(&path, verbosity)?;
+                (path, bcf)
             } else {
-                compile_from_source(&path, reader, verbosity)?
+                let path = PathBuf::from(matches.value_of("PATH").unwrap());
+                let bcf = read_program(&path, verbosity)?;
+                (path, bcf)
             };
             if matches.is_present("analyze") {
                 println!("{:#?}", bcf.base_func);
             } else {
-                let ctx = ExecContext::default();
-                let mut state = startup(bcf, path, ctx);
-                execute(&mut state);
+                let folded_path = matches.value_of("profile-folded");
+                let is_profiling = matches.is_present("profile") || folded_path.is_some();
+                let mut profiler = Profiler::new();
+                let coverage_path = matches.value_of("coverage");
+                let mut coverage = Coverage::new();
+                let show_stack = matches.is_present("trace-stack");
+                let mut tracer = Tracer::new(io::stderr(), show_stack);
+                let exit_code = {
+                    let mut observers: Vec<&mut dyn Observer> = Vec::new();
+                    if is_profiling {
+                        observers.push(&mut profiler);
+                    }
+                    if coverage_path.is_some() {
+                        observers.push(&mut coverage);
+                    }
+                    if matches.is_present("trace") || show_stack {
+                        observers.push(&mut tracer);
+                    }
+                    let ctx = ExecContext {
+                        fuel: matches.value_of("fuel").map(|fuel| fuel.parse().unwrap()),
+                        max_heap: matches.value_of("max-heap").map(|max| max.parse().unwrap()),
+                        deadline: matches.value_of("timeout").map(|timeout| {
+                            Instant::now() + Duration::from_millis(timeout.parse().unwrap())
+                        }),
+                        observers,
+                        args: script_args(matches),
+                        resolve: resolve_import,
+                        ..ExecContext::default()
+                    };
+                    let state = startup(bcf, path, ctx);
+                    let interrupt = state.borrow().interrupt_handle();
+                    ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))
+                        .expect("Unable to handle Ctrl-C");
+                    match execute(&state) {
+                        ExecuteReturn::Error(error) => {
+                            let state = state.borrow();
+                            eprint!("{}", debug::traceback(&state));
+                            eprintln!("{}", debug::describe_error(&state, &error));
+                            1
+                        }
+                        ExecuteReturn::LimitExceeded(limit) => {
+                            eprintln!("Execution stopped: {:?} limit exceeded", limit);
+                            1
+                        }
+                        ExecuteReturn::Exit(code) => code as i32,
+                        _ => 0,
+                    }
+                };
+                if is_profiling {
+                    profiler.finish();
+                    if matches.is_present("profile") {
+                        profiler.write_summary(&mut io::stderr())?;
+                    }
+                    if let Some(folded_path) = folded_path {
+                        let mut file = BufWriter::new(File::create(folded_path)?);
+                        profiler.write_folded(&mut file)?;
+                        file.flush()?;
+                    }
+                }
+                if let Some(coverage_path) = coverage_path {
+                    let mut file = BufWriter::new(File::create(coverage_path)?);
+                    coverage.write_lcov(&mut file)?;
+                    file.flush()?;
+                }
+                if exit_code != 0 {
+                    process::exit(exit_code);
+                }
             }
         }
         ("debug", Some(matches)) => {
             let path = PathBuf::from(matches.value_of("PATH").unwrap());
-
-            let (reader, magic) = open_code_or_source(&path, verbosity)?;
-            let bcf = if magic == MAGIC_NUMBER {
-                load_code_from_path(&path, reader, verbosity)?
-            } else {
-                compile_from_source(&path, reader, verbosity)?
+            let bcf = read_program(&path, verbosity)?;
+            let ctx = ExecContext {
+                args: script_args(matches),
+                resolve: resolve_import,
+                ..ExecContext::default()
             };
-            if matches.is_present("analyze") {
-                println!("{:#?}", bcf.base_func);
-            } else {
-                let mut ctx = ExecContext::default();
-                let mut interrupt = debug::make_interrupt();
-                ctx.interrupt = Some(&mut interrupt);
-                let mut state = startup(bcf, path, ctx);
-                execute(&mut state);
+            let state = startup(bcf, path, ctx);
+            let stdin = io::stdin();
+            let result = debug::run_session(&state, &mut stdin.lock(), &mut io::stdout())?;
+            match result {
+                Some(ExecuteReturn::Error(_)) | Some(ExecuteReturn::LimitExceeded(_)) => {
+                    process::exit(1)
+                }
+                Some(ExecuteReturn::Exit(code)) => process::exit(code as i32),
+                _ => {}
             }
         }
+        ("dap", Some(_)) => {
+            let stdin = io::stdin();
+            dap::serve(&mut stdin.lock(), &mut io::stdout())?;
+        }
+        ("repl", Some(_)) => repl::run()?,
+        ("check", Some(matches)) => {
+            let paths = matches.values_of("PATH").unwrap().collect::<Vec<_>>();
+            let is_json = matches.value_of("format") == Some("json");
+            if check::run(&paths, is_json, &mut io::stdout())? {
+                process::exit(1);
+            }
+        }
+        ("dump", Some(matches)) => {
+            let path = PathBuf::from(matches.value_of("PATH").unwrap());
+            let is_json = matches.value_of("format") == Some("json");
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            match matches.value_of("stage").unwrap() {
+                "linear" => dump(&mut out, &read_program(&path, verbosity)?, is_json)?,
+                stage => {
+                    let source = std::fs::read_to_string(&path)?;
+                    let units = lex(source.lines());
+                    if stage == "lex" {
+                        dump(&mut out, &units, is_json)?;
+                    } else {
+                        dump(&mut out, &parse(units), is_json)?;
+                    }
+                }
+            }
+        }
+        ("disasm", Some(matches)) => {
+            let path = PathBuf::from(matches.value_of("PATH").unwrap());
+            let bcf = read_program(&path, verbosity)?;
+            print!("{}", disassemble(&bcf));
+        }
+        ("asm", Some(matches)) => {
+            let mut path = PathBuf::from(matches.value_of("SOURCE").unwrap());
+            if verbosity >= 1 {
+                println!("Using input file: {}", path.display());
+            }
+
+            let source = std::fs::read_to_string(&path)?;
+            let bcf = assemble(&source).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}", path.display(), err),
+                )
+            })?;
+            path.set_extension("puslc");
+            write_to_code_path(&path, &bcf, verbosity)?;
+        }
         _ => println!("{}", matches.usage()),
     }
 
@@ -0,0 +1,110 @@
+//! `pusl repl`, an interactive prompt which keeps one state alive between inputs
+
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::Ordering;
+
+use pusl_lang::backend::linearize::linearize_file;
+use pusl_lang::backend::object::Value;
+use pusl_lang::backend::repl::{is_incomplete, Repl};
+use pusl_lang::backend::{debug, execute, startup, ExecContext, ExecStateRef};
+use pusl_lang::lexer::lex;
+use pusl_lang::parser::parse;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+/// The module path input typed at the prompt runs as
+const REPL_PATH: &str = "<repl>";
+
+const HELP: &str = "\
+Statements run as they are entered, and the value of an expression is printed. A statement
+which starts a block, like if, while or fn, goes on until an empty line. Imported modules are
+found relative to the directory the repl was started in.
+:load PATH   run a pusl source file, keeping the variables it makes
+:help        print this message
+:quit        leave the repl
+";
+
+/// Prints what an input gave back, or leaves the repl if it called exit
+fn show(st: ExecStateRef, result: Result<Value, String>) {
+    if let Some(code) = st.borrow().exit_code() {
+        process::exit(code as i32);
+    }
+    match result {
+        Ok(Value::Null) => {}
+        Ok(value) => println!("{}", debug::describe_value(&st.borrow(), &value)),
+        Err(message) => eprintln!("{}", message),
+    }
+}
+
+/// Reads input until end of file or `:quit`
+pub fn run() -> io::Result<()> {
+    let empty = linearize_file(parse(lex(std::iter::empty())));
+    let ctx = ExecContext {
+        resolve: crate::resolve_import,
+        ..ExecContext::default()
+    };
+    let state = startup(empty, PathBuf::from(REPL_PATH), ctx);
+    // The empty module finishes straight away, and each input runs on top of it after
+    execute(&state);
+    let interrupt = state.borrow().interrupt_handle();
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))
+        .expect("Unable to handle Ctrl-C");
+
+    // Mistakes in input are found by catching the VM's panics, and are printed by `show`
+    panic::set_hook(Box::new(|_| {}));
+    let mut repl = Repl::new();
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    let mut lines: Vec<String> = Vec::new();
+    loop {
+        let prompt = if lines.is_empty() { "pusl> " } else { "...   " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C at the prompt throws away unfinished input
+            Err(ReadlineError::Interrupted) => {
+                lines.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(error) => return Err(io::Error::other(error)),
+        };
+        if lines.is_empty() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(command) = line.strip_prefix(':') {
+                let _ = editor.add_history_entry(line);
+                let (command, argument) = match command.split_once(' ') {
+                    Some((command, argument)) => (command, argument.trim()),
+                    None => (command, ""),
+                };
+                match command {
+                    "load" | "l" => match fs::read_to_string(argument) {
+                        Ok(source) => show(&state, repl.run(&state, &source, Path::new(argument))),
+                        Err(error) => eprintln!("Unable to read {}: {}", argument, error),
+                    },
+                    "help" | "h" => print!("{}", HELP),
+                    "quit" | "q" => return Ok(()),
+                    _ => eprintln!("Unknown command :{}, try :help", command),
+                }
+                continue;
+            }
+        }
+        // Input recalled from history can be many lines at once
+        lines.extend(line.lines().map(str::to_string));
+        if line.is_empty() {
+            lines.push(line);
+        }
+        if is_incomplete(&lines) {
+            continue;
+        }
+        let source = lines.join("\n");
+        lines.clear();
+        let _ = editor.add_history_entry(source.trim_end());
+        show(&state, repl.run(&state, &source, Path::new(REPL_PATH)));
+    }
+}
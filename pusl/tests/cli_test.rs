@@ -0,0 +1,165 @@
+use pusl_lang::backend::linearize::ByteCodeFile;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const FIBB_PROGRAM: &str = "../resources/fibb.pusl";
+const SMALL_PROGRAM: &str = "../resources/small_source.pusl";
+
+/// Runs the pusl binary with `args`, writing `input` to its stdin, and returns its exit code,
+/// stdout and stderr
+fn run_pusl(args: &[&str], input: &str) -> (Option<i32>, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pusl"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Unable to start pusl");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes()).unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).expect("Invalid UTF8 in stdout"),
+        String::from_utf8(output.stderr).expect("Invalid UTF8 in stderr"),
+    )
+}
+
+#[test]
+fn run_eval_test() {
+    let (code, stdout, _) = run_pusl(&["run", "-e", "println(1 + 2)"], "");
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "3\n");
+
+    let (code, _, stderr) = run_pusl(&["run", "-e", "yeet 1"], "");
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("module <eval>"), "{}", stderr);
+}
+
+#[test]
+fn run_stdin_test() {
+    let source = std::fs::read_to_string(FIBB_PROGRAM).unwrap();
+    let (code, stdout, _) = run_pusl(&["run", "-"], &source);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "34\n");
+
+    let (code, stdout, _) = run_pusl(&["run", "-"], "let x = 2\nprintln(x * x)\n");
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "4\n");
+}
+
+#[test]
+fn run_args_test() {
+    let program = "println(args.len())\nprintln(args[args.len() - 1])\nexit(4)";
+    let (code, stdout, _) = run_pusl(&["run", "-e", program, "--", "first", "--last"], "");
+    assert_eq!(code, Some(4));
+    assert_eq!(stdout, "2\n--last\n");
+
+    let (code, stdout, _) = run_pusl(&["run", "-", "a", "b", "c"], "println(args[1])\nexit()\n");
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "b\n");
+}
+
+#[test]
+fn run_max_heap_test() {
+    // The list itself stays one object, only the memory it owns grows
+    let program = "\
+let l = []
+let i = 0
+while i < 2000000:
+    l.push(i)
+    i = i + 1
+println(l.len())
+";
+    let (code, stdout, stderr) = run_pusl(&["run", "--max-heap", "10000", "-"], program);
+    assert_eq!(code, Some(1));
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("Heap limit exceeded"), "{}", stderr);
+}
+
+#[test]
+fn run_max_heap_garbage_test() {
+    // Only one object is live at a time, so collecting keeps the heap under the limit
+    let program = "\
+let i = 0
+while i < 100000:
+    let o = Object()
+    i = i + 1
+println(i)
+";
+    let (code, stdout, stderr) = run_pusl(&["run", "--max-heap", "200000", "-"], program);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(stdout, "100000\n");
+}
+
+#[test]
+fn repl_mistake_test() {
+    let input = "let n = null\nn()\n1 < \"a\"\nprintln(n)\n";
+    let (code, stdout, stderr) = run_pusl(&["repl"], input);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(stdout.ends_with("null\n"), "{}", stdout);
+    assert!(stderr.contains("Value must be a function"), "{}", stderr);
+    assert!(stderr.contains("Cannot compare"), "{}", stderr);
+}
+
+#[test]
+fn check_test() {
+    let (code, stdout, _) = run_pusl(&["check", FIBB_PROGRAM], "");
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "");
+
+    let args = ["check", "--format", "json", FIBB_PROGRAM, SMALL_PROGRAM];
+    let (code, stdout, stderr) = run_pusl(&args, "");
+    assert_eq!(code, Some(1));
+    assert_eq!(stderr, "");
+    let diagnostics: Vec<Value> = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        diagnostics[0],
+        json!({
+            "file": SMALL_PROGRAM,
+            "line": 1,
+            "column": 1,
+            "severity": "error",
+            "code": "unresolved-import",
+            "message": "Unable to resolve import alpha.bravo.charlie",
+        })
+    );
+    assert!(diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic["file"] == SMALL_PROGRAM));
+}
+
+#[test]
+fn dump_test() {
+    for (stage, expect) in [
+        ("lex", "../resources/lexer/small-expect.json"),
+        ("parse", "../resources/parse/small-expect.json"),
+    ] {
+        let (code, stdout, _) = run_pusl(&["dump", "--stage", stage, SMALL_PROGRAM], "");
+        assert_eq!(code, Some(0));
+        let actual: Value = serde_json::from_str(&stdout).unwrap();
+        let expect = std::fs::read_to_string(expect).unwrap();
+        let expect: Value = serde_json::from_str(&expect).unwrap();
+        assert_eq!(actual, expect, "Dump of {} doesn't match", stage);
+    }
+
+    // Bytecode can be encoded more than one way, so it's only read back
+    let (code, stdout, _) = run_pusl(&["dump", "--stage", "linear", SMALL_PROGRAM], "");
+    assert_eq!(code, Some(0));
+    let bcf: ByteCodeFile = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(bcf.imports.len(), 2);
+
+    let args = [
+        "dump",
+        "--stage",
+        "parse",
+        "--format",
+        "debug",
+        FIBB_PROGRAM,
+    ];
+    let (code, stdout, _) = run_pusl(&args, "");
+    assert_eq!(code, Some(0));
+    assert!(stdout.starts_with("ParsedFile {"), "{}", stdout);
+}
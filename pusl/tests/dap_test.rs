@@ -0,0 +1,241 @@
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+const DEBUG_PROGRAM: &str = "../resources/debug.pusl";
+const ERRORS_PROGRAM: &str = "../resources/errors.pusl";
+
+/// Sends every request to `pusl dap` up front, and returns everything it sent back
+fn run_session(requests: &[Value]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pusl"))
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Unable to start pusl dap");
+    let mut stdin = child.stdin.take().unwrap();
+    for (seq, request) in requests.iter().enumerate() {
+        let mut request = request.clone();
+        request["seq"] = json!(seq + 1);
+        request["type"] = json!("request");
+        let body = request.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let mut messages = Vec::new();
+    let mut rest = output.as_str();
+    while let Some(start) = rest.find("\r\n\r\n") {
+        let length = rest[..start]
+            .trim()
+            .strip_prefix("Content-Length:")
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .expect("Bad header");
+        let body = &rest[start + 4..start + 4 + length];
+        messages.push(serde_json::from_str(body).unwrap());
+        rest = &rest[start + 4 + length..];
+    }
+    assert!(rest.is_empty(), "Trailing output {:?}", rest);
+    messages
+}
+
+fn request(command: &str, arguments: Value) -> Value {
+    json!({ "command": command, "arguments": arguments })
+}
+
+fn response<'m>(messages: &'m [Value], command: &str) -> Vec<&'m Value> {
+    messages
+        .iter()
+        .filter(|message| message["type"] == "response" && message["command"] == command)
+        .collect()
+}
+
+fn events<'m>(messages: &'m [Value], event: &str) -> Vec<&'m Value> {
+    messages
+        .iter()
+        .filter(|message| message["type"] == "event" && message["event"] == event)
+        .collect()
+}
+
+fn output(messages: &[Value], category: &str) -> String {
+    events(messages, "output")
+        .into_iter()
+        .filter(|event| event["body"]["category"] == category)
+        .map(|event| event["body"]["output"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn dap_breakpoint_test() {
+    let messages = run_session(&[
+        request("initialize", json!({ "adapterID": "pusl" })),
+        request(
+            "setBreakpoints",
+            json!({ "source": { "path": DEBUG_PROGRAM }, "breakpoints": [{ "line": 11 }] }),
+        ),
+        request("launch", json!({ "program": DEBUG_PROGRAM })),
+        request("configurationDone", json!({})),
+        request("threads", json!({})),
+        request("stackTrace", json!({ "threadId": 1 })),
+        request("scopes", json!({ "frameId": 1 })),
+        request("variables", json!({ "variablesReference": 1 })),
+        request("variables", json!({ "variablesReference": 2 })),
+        request("evaluate", json!({ "expression": "a()", "frameId": 1 })),
+        request(
+            "evaluate",
+            json!({ "expression": "1 < \"a\"", "frameId": 1 }),
+        ),
+        request(
+            "evaluate",
+            json!({ "expression": "a + b + offset", "frameId": 1 }),
+        ),
+        request("continue", json!({ "threadId": 1 })),
+        request("scopes", json!({ "frameId": 1 })),
+        request("variables", json!({ "variablesReference": 1 })),
+        request(
+            "setBreakpoints",
+            json!({ "source": { "path": DEBUG_PROGRAM }, "breakpoints": [] }),
+        ),
+        request("continue", json!({ "threadId": 1 })),
+        request("disconnect", json!({})),
+    ]);
+
+    assert!(
+        response(&messages, "initialize")[0]["body"]["supportsConfigurationDoneRequest"]
+            .as_bool()
+            .unwrap()
+    );
+    assert_eq!(events(&messages, "initialized").len(), 1);
+    let breakpoint = &response(&messages, "setBreakpoints")[0]["body"]["breakpoints"][0];
+    assert_eq!(breakpoint["verified"], true);
+
+    let stopped = events(&messages, "stopped");
+    assert_eq!(stopped.len(), 2);
+    for event in &stopped {
+        assert_eq!(event["body"]["reason"], "breakpoint");
+        assert_eq!(event["body"]["hitBreakpointIds"], json!([breakpoint["id"]]));
+    }
+
+    let threads = &response(&messages, "threads")[0]["body"]["threads"];
+    assert_eq!(threads, &json!([{ "id": 1, "name": "main" }]));
+
+    let frames = response(&messages, "stackTrace")[0]["body"]["stackFrames"]
+        .as_array()
+        .unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["name"], "fn(a, b)");
+    assert_eq!(frames[0]["line"], 11);
+    assert_eq!(frames[1]["line"], 17);
+    assert_eq!(frames[1]["source"]["path"], DEBUG_PROGRAM);
+
+    let scopes = response(&messages, "scopes")[0]["body"]["scopes"]
+        .as_array()
+        .unwrap();
+    let names = scopes
+        .iter()
+        .map(|scope| &scope["name"])
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Locals", "Bound", "Operand Stack"]);
+
+    let variables = response(&messages, "variables");
+    let value_of = |index: usize, name: &str| {
+        variables[index]["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variable| variable["name"] == name)
+            .map(|variable| variable["value"].clone())
+    };
+    assert_eq!(value_of(0, "a"), Some(json!("0")));
+    assert_eq!(value_of(0, "b"), Some(json!("0")));
+    assert_eq!(value_of(1, "offset"), Some(json!("10")));
+    // The second stop is the next call, and the old references were dropped on resume
+    assert_eq!(value_of(2, "a"), Some(json!("10")));
+    assert_eq!(value_of(2, "b"), Some(json!("1")));
+
+    // Mistakes in an expression are answered with an error, and the session carries on
+    let evaluations = response(&messages, "evaluate");
+    for evaluation in &evaluations[..2] {
+        assert_eq!(evaluation["success"], false);
+        assert!(evaluation["message"]
+            .as_str()
+            .is_some_and(|m| !m.is_empty()));
+    }
+    assert_eq!(evaluations[2]["body"]["result"], "10");
+
+    assert_eq!(output(&messages, "stdout"), "33\n66\n");
+    assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+    assert_eq!(events(&messages, "terminated").len(), 1);
+}
+
+#[test]
+fn dap_uncaught_error_test() {
+    let messages = run_session(&[
+        request("initialize", json!({ "adapterID": "pusl" })),
+        request(
+            "launch",
+            json!({ "program": ERRORS_PROGRAM, "stopOnEntry": true }),
+        ),
+        request(
+            "setExceptionBreakpoints",
+            json!({ "filters": ["uncaught"] }),
+        ),
+        request("configurationDone", json!({})),
+        request("stackTrace", json!({ "threadId": 1 })),
+        request("continue", json!({ "threadId": 1 })),
+        request("exceptionInfo", json!({ "threadId": 1 })),
+        request("stackTrace", json!({ "threadId": 1 })),
+        request("continue", json!({ "threadId": 1 })),
+        request("continue", json!({ "threadId": 1 })),
+        request("disconnect", json!({})),
+    ]);
+
+    let stopped = events(&messages, "stopped");
+    assert_eq!(stopped.len(), 2);
+    assert_eq!(stopped[0]["body"]["reason"], "entry");
+    assert_eq!(stopped[1]["body"]["reason"], "exception");
+
+    let traces = response(&messages, "stackTrace");
+    assert_eq!(traces[0]["body"]["stackFrames"][0]["line"], 1);
+    // Stopped where the error was thrown, with the calls that led there still on the stack
+    let frames = traces[1]["body"]["stackFrames"].as_array().unwrap();
+    assert_eq!(frames[0]["line"], 31);
+    assert!(frames.len() > 2);
+
+    let info = &response(&messages, "exceptionInfo")[0]["body"];
+    assert_eq!(info["breakMode"], "unhandled");
+
+    assert!(output(&messages, "stdout").ends_with("Done2!\n"));
+    assert!(!output(&messages, "stderr").is_empty());
+    assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 1);
+    let continues = response(&messages, "continue");
+    assert_eq!(continues[2]["success"], false);
+}
+
+#[test]
+fn dap_uncaught_default_test() {
+    // A client which never sends setExceptionBreakpoints gets the filter's default
+    let messages = run_session(&[
+        request("initialize", json!({ "adapterID": "pusl" })),
+        request("launch", json!({ "program": ERRORS_PROGRAM })),
+        request("configurationDone", json!({})),
+        request("continue", json!({ "threadId": 1 })),
+        request("continue", json!({ "threadId": 1 })),
+        request("disconnect", json!({})),
+    ]);
+
+    let initialize = &response(&messages, "initialize")[0]["body"];
+    assert_eq!(initialize["exceptionBreakpointFilters"][0]["default"], true);
+    let stopped = events(&messages, "stopped");
+    assert_eq!(stopped.len(), 1);
+    assert_eq!(stopped[0]["body"]["reason"], "exception");
+    assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 1);
+}
@@ -3,7 +3,7 @@ extern crate pusl_lang;
 extern crate shrust;
 extern crate simplelog;
 
-use pusl_lang::backend::debug::{DebugCommand, DebugResponse};
+use pusl_lang::backend::debug::{serve_commands, DebugCommand, DebugResponse};
 use pusl_lang::backend::linearize::{linearize_file, ByteCodeFile};
 use pusl_lang::backend::{startup, ExecContext};
 use pusl_lang::lexer::lex;
@@ -15,7 +15,7 @@ use std::process::exit;
 use std::sync::mpsc;
 use std::sync::mpsc::RecvError;
 use std::thread;
-use std::{num::ParseIntError, unimplemented};
+use std::num::ParseIntError;
 
 const SMALL_SOURCE: &'static str = include_str!("../resources/simple_program.pusl");
 const SECOND_SOURCE: &'static str = include_str!("../resources/secondary_source.pusl");
@@ -29,7 +29,7 @@ fn test_resolve(path: Vec<String>) -> Option<ByteCodeFile> {
     Some(code)
 }
 
-#[allow(unreachable_code, unused_variables)]
+#[allow(unused_variables)]
 fn main() {
     let mut config = ConfigBuilder::new();
     config
@@ -41,15 +41,21 @@ fn main() {
     let roots = lex(lines);
     let ast = parse(roots);
     let code = linearize_file(ast);
-    let mut ctx = ExecContext::default();
-    ctx.resolve = test_resolve;
     let (command_channel_send, command_channel_recv) = mpsc::channel::<DebugCommand>();
     let (response_channel_send, response_channel_recv) = mpsc::channel::<DebugResponse>();
     let cli_channels = (command_channel_send, response_channel_recv);
     let debug_channels = (command_channel_recv, response_channel_send);
 
-    unimplemented!();
-    // thread::spawn(move || startup(code, ctx, Some(debug_channels)));
+    // The state borrows its context and holds garbage collected pointers, so it is made on the
+    // thread that runs it
+    thread::spawn(move || {
+        let ctx = ExecContext {
+            resolve: test_resolve,
+            ..Default::default()
+        };
+        let state = startup(code, PathBuf::from("simple_program"), ctx);
+        serve_commands(&state, &debug_channels.0, &debug_channels.1);
+    });
 
     let line = if let DebugResponse::Paused(line) = cli_channels.1.recv().unwrap() {
         line
@@ -68,6 +74,7 @@ fn main() {
             DebugResponse::Done => {
                 exit(0);
             }
+            DebugResponse::Error(message) => return Err(ExecError::Other(message.into())),
         }
         Ok(())
     });
@@ -82,6 +89,7 @@ fn main() {
             DebugResponse::Done => {
                 exit(0);
             }
+            DebugResponse::Error(message) => return Err(ExecError::Other(message.into())),
         }
         Ok(())
     });
@@ -102,6 +110,7 @@ fn main() {
                 DebugResponse::Done => {
                     exit(0);
                 }
+                DebugResponse::Error(message) => return Err(ExecError::Other(message.into())),
             }
             Ok(())
         },
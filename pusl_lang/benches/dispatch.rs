@@ -0,0 +1,89 @@
+//! Times fibb.pusl style workloads: `cargo +nightly bench -p pusl_lang --bench dispatch`
+
+use pusl_lang::backend::linearize::linearize_file;
+use pusl_lang::backend::{execute, startup, ExecContext};
+use pusl_lang::lexer::lex;
+use pusl_lang::parser::parse;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const RECURSIVE_FIBB: &str = "\
+let fibbonacci = fn(num):
+    if num > 2:
+        return self(num-1) + self(num - 2)
+    else if num == 2:
+        return 1
+    else:
+        return 0
+let c = Object()
+let c.f = fibbonacci
+let b = c.f(24)
+";
+
+const LOOP_FIBB: &str = "\
+let count = 0
+while count < 2000:
+    let a = 0
+    let b = 1
+    let i = 0
+    while i < 80:
+        let c = a + b
+        a = b
+        b = c
+        i = i + 1
+    count = count + 1
+";
+
+const METHOD_CALLS: &str = "\
+let Point = Object()
+let Point.add = fn(other):
+    this.x = this.x + other.x
+    this.y = this.y + other.y
+let Point3 = Object(Point)
+let Point3.z = 0
+let p = Object(Point3)
+let p.x = 0
+let p.y = 0
+let q = Object(Point3)
+let q.x = 1
+let q.y = 2
+let i = 0
+while i < 40000:
+    p.add(q)
+    i = i + 1
+";
+
+const STRING_LITERALS: &str = "\
+let i = 0
+while i < 100000:
+    let separator = \", \"
+    let same = separator == \", \"
+    i = i + 1
+";
+
+fn run_once(source: &str) -> Duration {
+    let code = linearize_file(parse(lex(source.lines())));
+    let mut output = Vec::new();
+    let ctx = ExecContext {
+        stream: Some(&mut output),
+        ..ExecContext::default()
+    };
+    let state = startup(code, PathBuf::from("bench.pusl"), ctx);
+    let start = Instant::now();
+    execute(&state);
+    start.elapsed()
+}
+
+fn bench(name: &str, source: &str) {
+    const RUNS: u32 = 5;
+    run_once(source);
+    let best = (0..RUNS).map(|_| run_once(source)).min().unwrap();
+    println!("{:<16} best of {}: {:>8.2?}", name, RUNS, best);
+}
+
+fn main() {
+    bench("recursive_fibb", RECURSIVE_FIBB);
+    bench("loop_fibb", LOOP_FIBB);
+    bench("method_calls", METHOD_CALLS);
+    bench("string_literals", STRING_LITERALS);
+}
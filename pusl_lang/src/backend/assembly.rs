@@ -0,0 +1,682 @@
+//! A stable text format for compiled bytecode.
+//! `disassemble` turns a `ByteCodeFile` into pusl assembly and `assemble` turns it back.
+//! Jump targets and catch ranges are written as labels so that hand-written code
+//! doesn't need to know the size of each instruction.
+//!
+//! ```text
+//! .import secondary_source as ss
+//! .function
+//!     .args x
+//!     .locals x
+//!     .literal int 1
+//!     .code
+//!         .line 1
+//!         PushLocal 0 ; x
+//!         Literal 0 ; int 1
+//!         Addition
+//!         Return
+//! .end
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Write};
+
+use crate::backend::linearize::{BasicFunction, ByteCodeFile, ErrorCatch, Function};
+use crate::backend::opcode::{ByteCodeArray, OpCode};
+use crate::lexer::token::Literal;
+use crate::parser::expression::Compare;
+use crate::parser::Import;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+pub fn disassemble(bcf: &ByteCodeFile) -> String {
+    let mut out = String::new();
+    writeln!(out, "; pusl bytecode assembly").unwrap();
+    for Import { path, alias } in &bcf.imports {
+        writeln!(out, ".import {} as {}", path.join("."), alias).unwrap();
+    }
+    disassemble_function(&bcf.base_func, &mut out, 0).unwrap();
+    out
+}
+
+fn disassemble_function(bfunc: &BasicFunction, out: &mut String, depth: usize) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+    let function = bfunc.as_ref();
+    writeln!(out, "{}.function", indent)?;
+    if !function.args.is_empty() {
+        writeln!(out, "{}    .args {}", indent, function.args.join(" "))?;
+    }
+    if !function.binds.is_empty() {
+        writeln!(out, "{}    .binds {}", indent, function.binds.join(" "))?;
+    }
+    if !function.locals.is_empty() {
+        writeln!(out, "{}    .locals {}", indent, function.locals.join(" "))?;
+    }
+    if function.is_generator {
+        writeln!(out, "{}    .generator", indent)?;
+    }
+    for (index, literal) in function.literals.iter().enumerate() {
+        writeln!(
+            out,
+            "{}    .literal {} ; {}",
+            indent,
+            format_literal(literal),
+            index
+        )?;
+    }
+    for (index, reference) in function.references.iter().enumerate() {
+        writeln!(out, "{}    .reference {:?} ; {}", indent, reference, index)?;
+    }
+
+    let labels = collect_labels(function);
+    for ErrorCatch { begin, filter } in &function.catches {
+        writeln!(
+            out,
+            "{}    .catch {} {}",
+            indent, labels[begin], labels[filter]
+        )?;
+    }
+    writeln!(out, "{}    .code", indent)?;
+    let mut lines = function.lines.iter().peekable();
+    for (offset, op_code) in function.code.iter() {
+        if let Some(label) = labels.get(&offset) {
+            writeln!(out, "{}    {}:", indent, label)?;
+        }
+        if let Some((_, line)) = lines.next_if(|&&(start, _)| start == offset) {
+            writeln!(out, "{}        .line {}", indent, line)?;
+        }
+        write!(out, "{}        ", indent)?;
+        format_instruction(op_code, function, &labels, out)?;
+        writeln!(out)?;
+    }
+    if let Some(label) = labels.get(&function.code.len()) {
+        writeln!(out, "{}    {}:", indent, label)?;
+    }
+    for sub_function in &bfunc.sub_functions {
+        disassemble_function(sub_function, out, depth + 1)?;
+    }
+    writeln!(out, "{}.end", indent)
+}
+
+fn collect_labels(function: &Function) -> HashMap<usize, String> {
+    let mut targets = BTreeSet::new();
+    for (_, op_code) in function.code.iter() {
+        match op_code {
+            OpCode::Jump(target) | OpCode::ConditionalJump(target) => {
+                targets.insert(target);
+            }
+            OpCode::ComparisonJump(greater, less, equal) => {
+                targets.extend([greater, less, equal]);
+            }
+            _ => {}
+        }
+    }
+    for catch in &function.catches {
+        targets.insert(catch.begin);
+        targets.insert(catch.filter);
+    }
+    targets
+        .into_iter()
+        .enumerate()
+        .map(|(index, offset)| (offset, format!("L{}", index)))
+        .collect()
+}
+
+fn format_literal(literal: &Literal) -> String {
+    match literal {
+        Literal::Boolean(value) => format!("bool {}", value),
+        Literal::Integer(value) => format!("int {}", value),
+        Literal::Float(value) => format!("float {:?}", value),
+        Literal::String(value) => format!("str {:?}", value),
+        Literal::Null => "null".to_string(),
+    }
+}
+
+fn format_instruction(
+    op_code: OpCode,
+    function: &Function,
+    labels: &HashMap<usize, String>,
+    out: &mut String,
+) -> fmt::Result {
+    write!(out, "{}", mnemonic(&op_code))?;
+    match op_code {
+        OpCode::Literal(index) => write!(
+            out,
+            " {} ; {}",
+            index,
+            format_literal(&function.literals[index])
+        ),
+        OpCode::PushReference(index) | OpCode::FieldAccess(index) | OpCode::PushBuiltin(index) => {
+            write!(out, " {} ; {:?}", index, function.references[index])
+        }
+        OpCode::AssignReference(index, is_let) | OpCode::AssignField(index, is_let) => write!(
+            out,
+            " {} {} ; {:?}",
+            index, is_let, function.references[index]
+        ),
+        OpCode::PushLocal(slot) | OpCode::AssignLocal(slot) => {
+            write!(out, " {} ; {}", slot, function.locals[slot])
+        }
+        OpCode::PushBound(index) => write!(out, " {} ; {}", index, function.binds[index]),
+        OpCode::PushFunction(index)
+        | OpCode::FunctionCall(index)
+        | OpCode::TailCall(index)
+        | OpCode::DuplicateMany(index)
+        | OpCode::DuplicateDeep(index) => write!(out, " {}", index),
+        OpCode::Compare(compare) => write!(out, " {:?}", compare),
+        OpCode::ConditionalJump(target) | OpCode::Jump(target) => {
+            write!(out, " {}", labels[&target])
+        }
+        OpCode::ComparisonJump(greater, less, equal) => write!(
+            out,
+            " {} {} {}",
+            labels[&greater], labels[&less], labels[&equal]
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn mnemonic(op_code: &OpCode) -> &'static str {
+    match op_code {
+        OpCode::Modulus => "Modulus",
+        OpCode::Literal(_) => "Literal",
+        OpCode::PushReference(_) => "PushReference",
+        OpCode::PushFunction(_) => "PushFunction",
+        OpCode::PushThis => "PushThis",
+        OpCode::FunctionCall(_) => "FunctionCall",
+        OpCode::FieldAccess(_) => "FieldAccess",
+        OpCode::Addition => "Addition",
+        OpCode::Subtraction => "Subtraction",
+        OpCode::Negate => "Negate",
+        OpCode::Multiply => "Multiply",
+        OpCode::Divide => "Divide",
+        OpCode::AssignReference(_, _) => "AssignReference",
+        OpCode::AssignField(_, _) => "AssignField",
+        OpCode::DivideTruncate => "DivideTruncate",
+        OpCode::Exponent => "Exponent",
+        OpCode::Compare(_) => "Compare",
+        OpCode::And => "And",
+        OpCode::Or => "Or",
+        OpCode::Return => "Return",
+        OpCode::ConditionalJump(_) => "ConditionalJump",
+        OpCode::ComparisonJump(_, _, _) => "ComparisonJump",
+        OpCode::Jump(_) => "Jump",
+        OpCode::Pop => "Pop",
+        OpCode::IsNull => "IsNull",
+        OpCode::Duplicate => "Duplicate",
+        OpCode::DuplicateMany(_) => "DuplicateMany",
+        OpCode::PushBuiltin(_) => "PushBuiltin",
+        OpCode::DuplicateDeep(_) => "DuplicateDeep",
+        OpCode::PushSelf => "PushSelf",
+        OpCode::Yield => "Yield",
+        OpCode::Yeet => "Yeet",
+        OpCode::PushLocal(_) => "PushLocal",
+        OpCode::AssignLocal(_) => "AssignLocal",
+        OpCode::PushBound(_) => "PushBound",
+        OpCode::TailCall(_) => "TailCall",
+    }
+}
+
+/// An instruction whose jump targets are still label names
+struct PendingOp {
+    op_code: OpCode,
+    targets: Vec<String>,
+    line: usize,
+}
+
+#[derive(Default)]
+struct FunctionBuilder {
+    args: Vec<String>,
+    binds: Vec<String>,
+    locals: Vec<String>,
+    literals: Vec<Literal>,
+    references: Vec<String>,
+    catches: Vec<(String, String, usize)>,
+    /// (instruction position, source line) for each `.line`
+    lines: Vec<(usize, usize)>,
+    is_generator: bool,
+    code: Vec<PendingOp>,
+    labels: HashMap<String, usize>,
+    sub_functions: Vec<BasicFunction>,
+}
+
+impl FunctionBuilder {
+    fn finish(self) -> Result<BasicFunction, AssembleError> {
+        let err = |line: usize, message: String| AssembleError { line, message };
+
+        // Jump operands have a fixed width, so a dry run with zeroed targets gives the offsets
+        let mut offsets = Vec::with_capacity(self.code.len() + 1);
+        let mut sizing = ByteCodeArray::new();
+        for pending in &self.code {
+            offsets.push(sizing.len());
+            sizing.push(pending.op_code);
+        }
+        offsets.push(sizing.len());
+
+        let resolve = |label: &str, line: usize| {
+            self.labels
+                .get(label)
+                .map(|&position| offsets[position])
+                .ok_or_else(|| err(line, format!("undefined label '{}'", label)))
+        };
+
+        let mut code = ByteCodeArray::new();
+        for PendingOp {
+            op_code,
+            targets,
+            line,
+        } in &self.code
+        {
+            let line = *line;
+            let check_pool = |index: usize, len: usize, pool: &str| {
+                if index < len {
+                    Ok(())
+                } else {
+                    Err(err(
+                        line,
+                        format!("{} index {} is out of range", pool, index),
+                    ))
+                }
+            };
+            let op_code = match *op_code {
+                OpCode::Literal(index) => {
+                    check_pool(index, self.literals.len(), "literal")?;
+                    OpCode::Literal(index)
+                }
+                OpCode::PushReference(index)
+                | OpCode::FieldAccess(index)
+                | OpCode::PushBuiltin(index)
+                | OpCode::AssignReference(index, _)
+                | OpCode::AssignField(index, _) => {
+                    check_pool(index, self.references.len(), "reference")?;
+                    *op_code
+                }
+                OpCode::PushFunction(index) => {
+                    check_pool(index, self.sub_functions.len(), "function")?;
+                    OpCode::PushFunction(index)
+                }
+                OpCode::PushLocal(slot) | OpCode::AssignLocal(slot) => {
+                    check_pool(slot, self.locals.len(), "local")?;
+                    *op_code
+                }
+                OpCode::PushBound(index) => {
+                    check_pool(index, self.binds.len(), "bind")?;
+                    OpCode::PushBound(index)
+                }
+                OpCode::ConditionalJump(_) => OpCode::ConditionalJump(resolve(&targets[0], line)?),
+                OpCode::Jump(_) => OpCode::Jump(resolve(&targets[0], line)?),
+                OpCode::ComparisonJump(_, _, _) => OpCode::ComparisonJump(
+                    resolve(&targets[0], line)?,
+                    resolve(&targets[1], line)?,
+                    resolve(&targets[2], line)?,
+                ),
+                other => other,
+            };
+            code.push(op_code);
+        }
+
+        let catches = self
+            .catches
+            .iter()
+            .map(|(begin, filter, line)| {
+                Ok(ErrorCatch {
+                    begin: resolve(begin, *line)?,
+                    filter: resolve(filter, *line)?,
+                })
+            })
+            .collect::<Result<Vec<_>, AssembleError>>()?;
+
+        // A line with no instructions after it doesn't start anything
+        let lines = self
+            .lines
+            .iter()
+            .filter(|&&(position, _)| position < self.code.len())
+            .map(|&(position, line)| (offsets[position], line))
+            .collect();
+
+        let function = Function {
+            args: self.args,
+            binds: self.binds,
+            locals: self.locals,
+            literals: self.literals,
+            references: self.references,
+            catches,
+            code,
+            is_generator: self.is_generator,
+            lines,
+        };
+        Ok(BasicFunction::from_parts(function, self.sub_functions))
+    }
+}
+
+pub fn assemble(source: &str) -> Result<ByteCodeFile, AssembleError> {
+    let mut imports = Vec::new();
+    let mut stack: Vec<FunctionBuilder> = Vec::new();
+    let mut base_func = None;
+
+    for (line_index, raw_line) in source.lines().enumerate() {
+        let line = line_index + 1;
+        let err = |message: String| AssembleError { line, message };
+        let words = tokenize(raw_line).map_err(err)?;
+        let (head, rest) = match words.split_first() {
+            Some((head, rest)) => (head.as_str(), rest),
+            None => continue,
+        };
+
+        if base_func.is_some() {
+            return Err(err(format!(
+                "unexpected '{}' after the file function",
+                head
+            )));
+        }
+
+        if head == ".import" {
+            if !stack.is_empty() {
+                return Err(err("imports must come before the file function".to_string()));
+            }
+            match rest {
+                [path, as_kw, alias] if as_kw == "as" => imports.push(Import {
+                    path: path.split('.').map(str::to_string).collect(),
+                    alias: alias.clone(),
+                }),
+                _ => return Err(err("expected '.import <path> as <alias>'".to_string())),
+            }
+            continue;
+        }
+        if head == ".function" {
+            expect_operands(rest, 0).map_err(err)?;
+            stack.push(FunctionBuilder::default());
+            continue;
+        }
+
+        let builder = stack
+            .last_mut()
+            .ok_or_else(|| err(format!("'{}' outside of a function", head)))?;
+        match head {
+            ".end" => {
+                expect_operands(rest, 0).map_err(err)?;
+                let finished = stack.pop().unwrap().finish()?;
+                match stack.last_mut() {
+                    Some(parent) => parent.sub_functions.push(finished),
+                    None => base_func = Some(finished),
+                }
+            }
+            ".args" => builder.args.extend(rest.iter().cloned()),
+            ".binds" => builder.binds.extend(rest.iter().cloned()),
+            ".locals" => builder.locals.extend(rest.iter().cloned()),
+            ".generator" => {
+                expect_operands(rest, 0).map_err(err)?;
+                builder.is_generator = true;
+            }
+            ".literal" => builder.literals.push(parse_literal(rest).map_err(err)?),
+            ".reference" => {
+                expect_operands(rest, 1).map_err(err)?;
+                builder
+                    .references
+                    .push(parse_string(&rest[0]).map_err(err)?);
+            }
+            ".catch" => {
+                expect_operands(rest, 2).map_err(err)?;
+                builder
+                    .catches
+                    .push((rest[0].clone(), rest[1].clone(), line));
+            }
+            ".code" => expect_operands(rest, 0).map_err(err)?,
+            ".line" => {
+                expect_operands(rest, 1).map_err(err)?;
+                let source_line = parse_usize(&rest[0]).map_err(err)?;
+                let position = builder.code.len();
+                if builder
+                    .lines
+                    .last()
+                    .is_some_and(|&(last, _)| last == position)
+                {
+                    builder.lines.pop();
+                }
+                builder.lines.push((position, source_line));
+            }
+            label if label.ends_with(':') => {
+                expect_operands(rest, 0).map_err(err)?;
+                let label = label.trim_end_matches(':').to_string();
+                let position = builder.code.len();
+                if builder.labels.insert(label.clone(), position).is_some() {
+                    return Err(err(format!("label '{}' is defined twice", label)));
+                }
+            }
+            mnemonic => {
+                let pending = parse_instruction(mnemonic, rest, line).map_err(err)?;
+                builder.code.push(pending);
+            }
+        }
+    }
+
+    if !stack.is_empty() {
+        return Err(AssembleError {
+            line: source.lines().count(),
+            message: "missing '.end'".to_string(),
+        });
+    }
+    let base_func = base_func.ok_or_else(|| AssembleError {
+        line: 0,
+        message: "no function found".to_string(),
+    })?;
+    Ok(ByteCodeFile { base_func, imports })
+}
+
+fn expect_operands(operands: &[String], count: usize) -> Result<(), String> {
+    if operands.len() == count {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {} operands, found {}",
+            count,
+            operands.len()
+        ))
+    }
+}
+
+fn parse_usize(word: &str) -> Result<usize, String> {
+    word.parse()
+        .map_err(|_| format!("expected an index, found '{}'", word))
+}
+
+fn parse_bool(word: &str) -> Result<bool, String> {
+    word.parse()
+        .map_err(|_| format!("expected true or false, found '{}'", word))
+}
+
+fn parse_compare(word: &str) -> Result<Compare, String> {
+    Ok(match word {
+        "Less" => Compare::Less,
+        "LessEqual" => Compare::LessEqual,
+        "Greater" => Compare::Greater,
+        "GreaterEqual" => Compare::GreaterEqual,
+        "Equal" => Compare::Equal,
+        "NotEqual" => Compare::NotEqual,
+        other => return Err(format!("unknown comparison '{}'", other)),
+    })
+}
+
+fn parse_literal(operands: &[String]) -> Result<Literal, String> {
+    match operands {
+        [kind] if kind == "null" => Ok(Literal::Null),
+        [kind, value] => match kind.as_str() {
+            "bool" => parse_bool(value).map(Literal::Boolean),
+            "int" => value
+                .parse()
+                .map(Literal::Integer)
+                .map_err(|_| format!("invalid integer '{}'", value)),
+            "float" => value
+                .parse()
+                .map(Literal::Float)
+                .map_err(|_| format!("invalid float '{}'", value)),
+            "str" => parse_string(value).map(Literal::String),
+            other => Err(format!("unknown literal type '{}'", other)),
+        },
+        _ => Err("expected '<type> <value>' or 'null'".to_string()),
+    }
+}
+
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &[String],
+    line: usize,
+) -> Result<PendingOp, String> {
+    let index = |count: usize| -> Result<usize, String> {
+        expect_operands(operands, count)?;
+        parse_usize(&operands[0])
+    };
+    let mut targets = Vec::new();
+    let op_code = match mnemonic {
+        "Modulus" => simple(operands, OpCode::Modulus)?,
+        "Literal" => OpCode::Literal(index(1)?),
+        "PushReference" => OpCode::PushReference(index(1)?),
+        "PushFunction" => OpCode::PushFunction(index(1)?),
+        "PushThis" => simple(operands, OpCode::PushThis)?,
+        "FunctionCall" => OpCode::FunctionCall(index(1)?),
+        "FieldAccess" => OpCode::FieldAccess(index(1)?),
+        "Addition" => simple(operands, OpCode::Addition)?,
+        "Subtraction" => simple(operands, OpCode::Subtraction)?,
+        "Negate" => simple(operands, OpCode::Negate)?,
+        "Multiply" => simple(operands, OpCode::Multiply)?,
+        "Divide" => simple(operands, OpCode::Divide)?,
+        "AssignReference" => OpCode::AssignReference(index(2)?, parse_bool(&operands[1])?),
+        "AssignField" => OpCode::AssignField(index(2)?, parse_bool(&operands[1])?),
+        "DivideTruncate" => simple(operands, OpCode::DivideTruncate)?,
+        "Exponent" => simple(operands, OpCode::Exponent)?,
+        "Compare" => {
+            expect_operands(operands, 1)?;
+            OpCode::Compare(parse_compare(&operands[0])?)
+        }
+        "And" => simple(operands, OpCode::And)?,
+        "Or" => simple(operands, OpCode::Or)?,
+        "Return" => simple(operands, OpCode::Return)?,
+        "ConditionalJump" => {
+            expect_operands(operands, 1)?;
+            targets.extend_from_slice(operands);
+            OpCode::ConditionalJump(0)
+        }
+        "ComparisonJump" => {
+            expect_operands(operands, 3)?;
+            targets.extend_from_slice(operands);
+            OpCode::ComparisonJump(0, 0, 0)
+        }
+        "Jump" => {
+            expect_operands(operands, 1)?;
+            targets.extend_from_slice(operands);
+            OpCode::Jump(0)
+        }
+        "Pop" => simple(operands, OpCode::Pop)?,
+        "IsNull" => simple(operands, OpCode::IsNull)?,
+        "Duplicate" => simple(operands, OpCode::Duplicate)?,
+        "DuplicateMany" => OpCode::DuplicateMany(index(1)?),
+        "PushBuiltin" => OpCode::PushBuiltin(index(1)?),
+        "DuplicateDeep" => OpCode::DuplicateDeep(index(1)?),
+        "PushSelf" => simple(operands, OpCode::PushSelf)?,
+        "Yield" => simple(operands, OpCode::Yield)?,
+        "Yeet" => simple(operands, OpCode::Yeet)?,
+        "PushLocal" => OpCode::PushLocal(index(1)?),
+        "AssignLocal" => OpCode::AssignLocal(index(1)?),
+        "PushBound" => OpCode::PushBound(index(1)?),
+        "TailCall" => OpCode::TailCall(index(1)?),
+        other => return Err(format!("unknown instruction '{}'", other)),
+    };
+    Ok(PendingOp {
+        op_code,
+        targets,
+        line,
+    })
+}
+
+fn simple(operands: &[String], op_code: OpCode) -> Result<OpCode, String> {
+    expect_operands(operands, 0).map(|_| op_code)
+}
+
+/// Split a line into words and quoted strings, dropping any `;` comment
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            let mut word = String::new();
+            word.push(chars.next().unwrap());
+            let mut escaped = false;
+            loop {
+                let c = chars.next().ok_or("unterminated string")?;
+                word.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    break;
+                }
+            }
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+    Ok(words)
+}
+
+/// Parse a string written with rust escapes (the output of `{:?}`)
+fn parse_string(word: &str) -> Result<String, String> {
+    let inner = word
+        .strip_prefix('"')
+        .and_then(|word| word.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found {}", word))?;
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('u') => {
+                let code: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let code = code
+                    .strip_prefix('{')
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("invalid unicode escape in {}", word))?;
+                code
+            }
+            _ => return Err(format!("invalid escape in {}", word)),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
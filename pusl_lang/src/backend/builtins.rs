@@ -1,22 +1,34 @@
 use super::{
-    object::{self, NativeFn, ObjectPtr},
-    ExecStateRef,
+    nondeterministic,
+    object::{self, ObjectPtr, Registry, StringPtr},
+    ExecStateRef, ExecutionState,
 };
 use crate::backend::list;
 use crate::backend::object::{PuslObject, Value};
 use crate::backend::{argparse, generator};
 use anymap::AnyMap;
+use garbage::ManagedPool;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, io::Write};
 
-pub fn get_builtins(registry: &mut Vec<NativeFn>) -> (HashMap<&'static str, Value>, AnyMap) {
+pub fn get_builtins(registry: &mut Registry) -> (HashMap<&'static str, Value>, AnyMap) {
     let mut map = HashMap::new();
     let mut data_map = AnyMap::new();
-    map.insert("type_of", Value::native_fn(type_of, registry));
-    map.insert("instance_of", Value::native_fn(is_instance_of, registry));
-    map.insert("print", Value::native_fn(print, registry));
-    map.insert("println", Value::native_fn(println, registry));
-    map.insert("native", Value::native_fn(native_import, registry));
-    map.insert("Object", Value::native_fn(new_object, registry));
+    map.insert("type_of", Value::native_fn("type_of", type_of, registry));
+    map.insert(
+        "instance_of",
+        Value::native_fn("instance_of", is_instance_of, registry),
+    );
+    map.insert("print", Value::native_fn("print", print, registry));
+    map.insert("println", Value::native_fn("println", println, registry));
+    map.insert(
+        "native",
+        Value::native_fn("native", native_import, registry),
+    );
+    map.insert("Object", Value::native_fn("Object", new_object, registry));
+    map.insert("time", Value::native_fn("time", time, registry));
+    map.insert("env", Value::native_fn("env", env, registry));
+    map.insert("exit", Value::native_fn("exit", exit, registry));
 
     list::register(&mut map, registry, &mut data_map);
     generator::register(&mut map, registry, &mut data_map);
@@ -24,6 +36,45 @@ pub fn get_builtins(registry: &mut Vec<NativeFn>) -> (HashMap<&'static str, Valu
     (map, data_map)
 }
 
+/// Prototypes for the errors the runtime raises itself, these need the heap so they're added
+/// after the rest of the builtins
+pub fn register_errors(builtins: &mut HashMap<&'static str, Value>, gc: &mut ManagedPool) {
+    let stack_overflow = gc.place_in_heap(PuslObject::new()) as ObjectPtr;
+    builtins.insert("StackOverflowError", Value::Object(stack_overflow));
+    let interrupted = gc.place_in_heap(PuslObject::new()) as ObjectPtr;
+    builtins.insert("InterruptedError", Value::Object(interrupted));
+}
+
+/// The script's command line arguments as the `args` list of strings
+pub fn register_args(
+    builtins: &mut HashMap<&'static str, Value>,
+    builtin_data: &AnyMap,
+    gc: &mut ManagedPool,
+    args: &[String],
+) {
+    let args = args
+        .iter()
+        .map(|arg| Value::String(gc.place_in_heap(arg.clone())))
+        .collect();
+    let list = list::make_list(args, builtin_data, gc);
+    builtins.insert("args", Value::Object(list));
+}
+
+/// An instance of one of the error prototypes with a message field
+pub fn new_error(st: &mut ExecutionState, prototype: &str, message: String) -> Value {
+    let prototype = match st.builtins.get(prototype) {
+        Some(Value::Object(prototype)) => prototype.clone(),
+        _ => panic!("Missing error prototype {}", prototype),
+    };
+    let error = st.gc.place_in_heap(PuslObject::new_with_parent(prototype)) as ObjectPtr;
+    let message = st.gc.place_in_heap(message);
+    error
+        .borrow_mut()
+        .assign_field("message", Value::String(message), true);
+    st.gc.resize(&error);
+    Value::Object(error)
+}
+
 fn is_instance_of(args: Vec<Value>, _: Option<Value>, _: ExecStateRef) -> Value {
     let (obj, typ): (Value, Value) = argparse::parse2(args);
     Value::Boolean(match (typ, obj) {
@@ -81,3 +132,30 @@ fn new_object(args: Vec<Value>, _: Option<Value>, st: ExecStateRef) -> Value {
 
     Value::Object(gc_ptr)
 }
+
+/// Seconds since the unix epoch
+fn time(args: Vec<Value>, _: Option<Value>, st: ExecStateRef) -> Value {
+    argparse::parse0(args);
+    nondeterministic(st, |_| {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Value::Float(since_epoch.as_secs_f64())
+    })
+}
+
+/// The value of an environment variable, or null if it isn't set
+fn env(args: Vec<Value>, _: Option<Value>, st: ExecStateRef) -> Value {
+    let name: StringPtr = argparse::parse1(args);
+    nondeterministic(st, |st| match std::env::var(name.as_str()) {
+        Ok(value) => Value::String(st.borrow_mut().gc.place_in_heap(value)),
+        Err(_) => Value::Null,
+    })
+}
+
+/// Stops the script with an exit code, 0 if none is given
+fn exit(args: Vec<Value>, _: Option<Value>, st: ExecStateRef) -> Value {
+    let code: Option<i64> = argparse::parse_option(args);
+    st.borrow_mut().exit_code = Some(code.unwrap_or(0));
+    Value::Null
+}
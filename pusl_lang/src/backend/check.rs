@@ -0,0 +1,360 @@
+//! Finding mistakes in source code without running it. The lexer, parser and linearizer stop
+//! at the first mistake by panicking, so each stage is run over smaller and smaller pieces of
+//! the source to find which line it stopped at.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+
+use garbage::ManagedPool;
+use serde::Serialize;
+
+use super::linearize::{linearize_file, BasicFunction, ByteCodeFile};
+use super::new_builtins;
+use super::opcode::OpCode;
+use crate::lexer::lex;
+use crate::lexer::token::{BlockType, LexUnit};
+use crate::parser::{parse, ParsedFile};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The source can't be run
+    Error,
+    /// The source can be run, but probably doesn't do what was meant
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// Counting from 1
+    pub line: usize,
+    /// Counting from 1, in characters
+    pub column: usize,
+    pub severity: Severity,
+    /// The kind of mistake, like "parse-error" or "unused-import"
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Runs `stage`, giving back the panic message if it panics
+fn attempt<T>(stage: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(stage)).map_err(|payload| panic_message(&*payload))
+}
+
+/// The useful part of a panic's message, empty if it doesn't have one
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else {
+        ""
+    };
+    let message = message.lines().next().unwrap_or_default();
+    if message.starts_with("assertion") {
+        // Only the message given to the assertion is meant for people writing pusl
+        message
+            .split_once("failed: ")
+            .map_or("", |(_, message)| message)
+            .to_string()
+    } else if message == "explicit panic" {
+        String::new()
+    } else {
+        message.to_string()
+    }
+}
+
+/// The column of the first character on a line which isn't indentation
+fn statement_column(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).count() + 1
+}
+
+/// Splits units into the pieces the parser reads together, a unit and any `else` or `yoink`
+/// blocks after it
+fn statement_groups(units: &[LexUnit]) -> Vec<&[LexUnit]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (index, unit) in units.iter().enumerate().skip(1) {
+        let continues = matches!(
+            unit,
+            LexUnit::Block(block)
+                if matches!(block.kind, BlockType::Else | BlockType::ElseIf | BlockType::Yoink)
+        );
+        if !continues {
+            groups.push(&units[start..index]);
+            start = index;
+        }
+    }
+    if start < units.len() {
+        groups.push(&units[start..]);
+    }
+    groups
+}
+
+fn parse_units(units: &[LexUnit]) -> Result<ParsedFile, String> {
+    let units = units.to_vec();
+    attempt(|| parse(units))
+}
+
+/// The line of the innermost unit in a group which doesn't parse
+fn failing_line(group: &[LexUnit]) -> usize {
+    for end in 1..=group.len() {
+        if parse_units(&group[..end]).is_ok() {
+            continue;
+        }
+        let unit = &group[end - 1];
+        if let LexUnit::Block(block) = unit {
+            for child_group in statement_groups(&block.children) {
+                if parse_units(child_group).is_err() {
+                    return failing_line(child_group);
+                }
+            }
+        }
+        return unit.get_line_number();
+    }
+    group[0].get_line_number()
+}
+
+struct Checker<'s> {
+    lines: Vec<&'s str>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'s> Checker<'s> {
+    fn report(
+        &mut self,
+        line: usize,
+        column: usize,
+        severity: Severity,
+        code: &'static str,
+        message: String,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            column,
+            severity,
+            code,
+            message,
+        });
+    }
+
+    /// An error about a whole statement, with `fallback` if the panic had no message
+    fn statement_error(
+        &mut self,
+        line: usize,
+        code: &'static str,
+        message: String,
+        fallback: &str,
+    ) {
+        let text = self.lines.get(line - 1).copied().unwrap_or_default();
+        let message = if message.is_empty() {
+            fallback.to_string()
+        } else {
+            message
+        };
+        self.report(line, statement_column(text), Severity::Error, code, message);
+    }
+
+    /// Lexes each line by itself, so a mistake on one line doesn't hide the ones after it
+    fn check_tokens(&mut self) {
+        for (index, text) in self.lines.clone().into_iter().enumerate() {
+            let message = match attempt(|| lex(iter::once(text))) {
+                Ok(_) => continue,
+                Err(message) => message,
+            };
+            // The mistake is just after the longest start of the line which still lexes
+            let column = text
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .rev()
+                .find(|&offset| attempt(|| lex(iter::once(&text[..offset]))).is_ok())
+                .map_or(1, |offset| text[..offset].chars().count() + 1);
+            let message = if message.is_empty() {
+                "Invalid token".to_string()
+            } else {
+                message
+            };
+            self.report(index + 1, column, Severity::Error, "lex-error", message);
+        }
+    }
+
+    /// Groups the lines into blocks by indentation, reporting the first line it goes wrong on
+    fn check_blocks(&mut self) -> Option<Vec<LexUnit>> {
+        let lines = &self.lines;
+        let message = match attempt(|| lex(lines.iter().copied())) {
+            Ok(units) => return Some(units),
+            Err(message) => message,
+        };
+        // Adding lines can only add mistakes, so search for the shortest start which fails
+        let (mut good, mut bad) = (0, lines.len());
+        while bad - good > 1 {
+            let middle = (good + bad) / 2;
+            if attempt(|| lex(lines[..middle].iter().copied())).is_ok() {
+                good = middle;
+            } else {
+                bad = middle;
+            }
+        }
+        self.statement_error(bad, "block-error", message, "Invalid indentation");
+        None
+    }
+
+    /// Parses each statement by itself, reporting one mistake for each statement that has any
+    fn check_statements(&mut self, units: &[LexUnit]) -> bool {
+        let mut is_valid = true;
+        for group in statement_groups(units) {
+            if let Err(message) = parse_units(group) {
+                let line = failing_line(group);
+                self.statement_error(line, "parse-error", message, "Invalid syntax");
+                is_valid = false;
+            }
+        }
+        is_valid
+    }
+
+    fn check_imports(
+        &mut self,
+        parsed: &ParsedFile,
+        units: &[LexUnit],
+        resolves: &dyn Fn(&[String]) -> bool,
+    ) {
+        // Imports can only be at the start, one on each of the first lines
+        for (import, unit) in parsed.imports.iter().zip(units) {
+            if !resolves(&import.path) {
+                let line = unit.get_line_number();
+                let message = format!("Unable to resolve import {}", import.path.join("."));
+                self.statement_error(line, "unresolved-import", message, "");
+            }
+        }
+    }
+
+    fn check_compile(&mut self, parsed: ParsedFile, units: &[LexUnit]) -> Option<ByteCodeFile> {
+        let message = match attempt(|| linearize_file(parsed)) {
+            Ok(code) => return Some(code),
+            Err(message) => message,
+        };
+        // Statements compile the same by themselves, apart from which names are locals
+        let line = statement_groups(units)
+            .into_iter()
+            .find(|group| {
+                parse_units(group)
+                    .and_then(|parsed| attempt(|| linearize_file(parsed)))
+                    .is_err()
+            })
+            .map_or(1, |group| group[0].get_line_number());
+        self.statement_error(line, "compile-error", message, "Unable to compile");
+        None
+    }
+
+    /// Warns about names which aren't locals, builtins or imports, and imports never used
+    fn check_names(&mut self, code: &ByteCodeFile, units: &[LexUnit]) {
+        let mut gc = ManagedPool::new();
+        let (builtins, _, _) = new_builtins(&mut gc, &[]);
+        let aliases = code
+            .imports
+            .iter()
+            .map(|import| import.alias.as_str())
+            .collect::<HashSet<_>>();
+        let mut used = HashSet::new();
+        let mut unknown = Vec::new();
+        let mut to_visit = vec![&code.base_func];
+        while let Some(function) = to_visit.pop() {
+            to_visit.extend(&function.sub_functions);
+            for (line, name) in references(function) {
+                if aliases.contains(name.as_str()) {
+                    used.insert(name);
+                } else if !builtins.contains_key(name.as_str()) {
+                    unknown.push((line, name));
+                }
+            }
+        }
+        unknown.sort();
+        unknown.dedup();
+        for (line, name) in unknown {
+            let text = self.lines.get(line - 1).copied().unwrap_or_default();
+            let column = find_name(text, &name).unwrap_or_else(|| statement_column(text));
+            let message = format!("Unknown variable \"{}\"", name);
+            self.report(line, column, Severity::Warning, "unknown-variable", message);
+        }
+        for (import, unit) in code.imports.iter().zip(units) {
+            if !used.contains(&import.alias) {
+                let line = unit.get_line_number();
+                let column = statement_column(self.lines[line - 1]);
+                let message = format!("Import {} is never used", import.alias);
+                self.report(line, column, Severity::Warning, "unused-import", message);
+            }
+        }
+    }
+}
+
+/// Each name a function looks up by name rather than as a local, with the line it's on
+fn references(function: &BasicFunction) -> Vec<(usize, String)> {
+    let function = function.as_ref();
+    let (ops, offsets) = function.code.decode();
+    ops.iter()
+        .zip(offsets)
+        .filter_map(|(op, offset)| {
+            let index = match *op {
+                OpCode::PushReference(index) | OpCode::AssignReference(index, _) => index,
+                _ => return None,
+            };
+            let entry = function
+                .lines
+                .partition_point(|&(start, _)| start <= offset);
+            let line = entry
+                .checked_sub(1)
+                .map_or(1, |entry| function.lines[entry].1);
+            Some((line, function.references[index].clone()))
+        })
+        .collect()
+}
+
+/// The column `name` first appears at on a line as a whole word
+fn find_name(text: &str, name: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '@';
+    text.match_indices(name)
+        .find(|&(offset, _)| {
+            let before = text[..offset].chars().next_back();
+            let after = text[offset + name.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })
+        .map(|(offset, _)| text[..offset].chars().count() + 1)
+}
+
+/// Every mistake found in `source` in order of where they are, without running it.
+/// `resolves` tells whether an import's path leads to a module. Later stages only run once
+/// the earlier ones have no errors, so fixing errors can show new ones.
+pub fn check(source: &str, resolves: &dyn Fn(&[String]) -> bool) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        lines: source.lines().collect(),
+        diagnostics: Vec::new(),
+    };
+    checker.check_tokens();
+    if !checker.diagnostics.is_empty() {
+        return checker.diagnostics;
+    }
+    let units = match checker.check_blocks() {
+        Some(units) => units,
+        None => return checker.diagnostics,
+    };
+    if !checker.check_statements(&units) {
+        return checker.diagnostics;
+    }
+    let parsed = match parse_units(&units) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            checker.statement_error(1, "parse-error", message, "Invalid syntax");
+            return checker.diagnostics;
+        }
+    };
+    checker.check_imports(&parsed, &units, resolves);
+    if let Some(code) = checker.check_compile(parsed, &units) {
+        checker.check_names(&code, &units);
+    }
+    checker
+        .diagnostics
+        .sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    checker.diagnostics
+}
@@ -0,0 +1,182 @@
+//! Which instructions and branches of every loaded module ran, written out as an lcov report.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use super::debug::module_of;
+use super::linearize::ResolvedFunction;
+use super::observe::Observer;
+use super::opcode::OpCode;
+use super::ExecutionState;
+
+#[derive(Default)]
+struct FunctionCoverage {
+    /// How many times each instruction ran
+    hits: Vec<u64>,
+    /// How many times each way out of a branch instruction was taken, by the instruction's index.
+    /// A ConditionalJump jumps or falls through, a ComparisonJump goes greater, less or equal.
+    branches: BTreeMap<usize, Vec<u64>>,
+}
+
+#[derive(Default)]
+pub struct Coverage {
+    /// The path and code of each module seen, in the order they were first run
+    modules: Vec<(Vec<String>, &'static ResolvedFunction)>,
+    functions: HashMap<*const ResolvedFunction, FunctionCoverage>,
+}
+
+fn branch_count(op: &OpCode) -> Option<usize> {
+    match op {
+        OpCode::ConditionalJump(_) => Some(2),
+        OpCode::ComparisonJump(_, _, _) => Some(3),
+        _ => None,
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    fn function(&mut self, function: &ResolvedFunction) -> &mut FunctionCoverage {
+        self.functions
+            .entry(function as *const ResolvedFunction)
+            .or_insert_with(|| FunctionCoverage {
+                hits: vec![0; function.code.len()],
+                branches: BTreeMap::new(),
+            })
+    }
+
+    /// How many times each instruction of a function ran, None if it never ran
+    pub fn hits(&self, function: &ResolvedFunction) -> Option<&[u64]> {
+        self.functions
+            .get(&(function as *const ResolvedFunction))
+            .map(|coverage| coverage.hits.as_slice())
+    }
+
+    /// An lcov tracefile with a record for each module. A line's count is how many times its
+    /// most run instruction ran, and a function's is how many times its first instruction ran.
+    pub fn write_lcov(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        for (path, base) in &self.modules {
+            let mut functions = Vec::new();
+            let mut lines = BTreeMap::<usize, u64>::new();
+            let mut branches = Vec::new();
+            let mut pending = vec![*base];
+            while let Some(function) = pending.pop() {
+                pending.extend(function.sub_functions.iter().rev());
+                let coverage = self.functions.get(&(function as *const ResolvedFunction));
+                let hits = |index: usize| coverage.map_or(0, |coverage| coverage.hits[index]);
+                if !std::ptr::eq(function, *base) {
+                    if let Some(line) = function.line_at(0) {
+                        let name =
+                            format!("fn({}) line {}", function.as_ref().args.join(" "), line);
+                        let name = if functions.iter().any(|(_, seen, _)| seen == &name) {
+                            format!("{} #{}", name, functions.len())
+                        } else {
+                            name
+                        };
+                        functions.push((line, name, hits(0)));
+                    }
+                }
+                for (index, op) in function.code.iter().enumerate() {
+                    let line = match function.line_at(index) {
+                        Some(line) => line,
+                        None => continue,
+                    };
+                    let count = lines.entry(line).or_insert(0);
+                    *count = (*count).max(hits(index));
+                    if let Some(ways) = branch_count(op) {
+                        let taken = if hits(index) == 0 {
+                            None
+                        } else {
+                            coverage.and_then(|coverage| coverage.branches.get(&index))
+                        };
+                        branches.push((line, ways, taken));
+                    }
+                }
+            }
+
+            writeln!(out, "SF:{}", path.iter().collect::<PathBuf>().display())?;
+            for (line, name, _) in &functions {
+                writeln!(out, "FN:{},{}", line, name)?;
+            }
+            for (_, name, count) in &functions {
+                writeln!(out, "FNDA:{},{}", count, name)?;
+            }
+            writeln!(out, "FNF:{}", functions.len())?;
+            let functions_hit = functions.iter().filter(|(_, _, count)| *count > 0).count();
+            writeln!(out, "FNH:{}", functions_hit)?;
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (block, (line, ways, taken)) in branches.iter().enumerate() {
+                for way in 0..*ways {
+                    branches_found += 1;
+                    match taken {
+                        // A branch which never ran at all
+                        None => writeln!(out, "BRDA:{},{},{},-", line, block, way)?,
+                        Some(taken) => {
+                            let count = taken.get(way).copied().unwrap_or(0);
+                            if count > 0 {
+                                branches_hit += 1;
+                            }
+                            writeln!(out, "BRDA:{},{},{},{}", line, block, way, count)?
+                        }
+                    }
+                }
+            }
+            writeln!(out, "BRF:{}", branches_found)?;
+            writeln!(out, "BRH:{}", branches_hit)?;
+            for (line, count) in &lines {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|count| **count > 0).count()
+            )?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Observer<'a> for Coverage {
+    fn instruction(&mut self, state: &ExecutionState<'a>, index: usize) {
+        let function = state.current_frame.bfunc.target;
+        if !self
+            .functions
+            .contains_key(&(function as *const ResolvedFunction))
+        {
+            if let Some(module) = module_of(state, function) {
+                let base = state.module_functions[module];
+                if !self
+                    .modules
+                    .iter()
+                    .any(|(_, seen)| std::ptr::eq(*seen, base))
+                {
+                    self.modules.push((state.imports[module].0.clone(), base));
+                }
+            }
+        }
+        self.function(function).hits[index] += 1;
+    }
+
+    fn branch(
+        &mut self,
+        _state: &ExecutionState<'a>,
+        function: &'static ResolvedFunction,
+        index: usize,
+        way: usize,
+    ) {
+        let ways = branch_count(&function.code[index]).unwrap_or(way + 1);
+        let counts = self
+            .function(function)
+            .branches
+            .entry(index)
+            .or_insert_with(|| vec![0; ways]);
+        counts[way] += 1;
+    }
+}
@@ -1,17 +1,991 @@
-use super::ExecutionState;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{Receiver, Sender};
 
+use super::linearize::{linearize_file, ByteCodeFile, ResolvedFunction};
+use super::list::List;
+use super::object::{self, FunctionTarget, Object, PuslObject, Value};
+use super::opcode::OpCode;
+use super::snapshot::{self, SnapshotError};
+use super::{
+    execute_for, run_frame_caught, ExecStateRef, ExecuteReturn, ExecutionState, StackFrame,
+    WriteOption,
+};
+use crate::lexer::lex;
+use crate::parser::parse;
+
+/// Sent to `serve_commands` by a front end on another thread
 pub enum DebugCommand {
+    /// Run until the innermost frame is about to execute this instruction
     RunToIndex(usize),
     Run,
 }
 
 pub enum DebugResponse {
+    /// The instruction the innermost frame is about to execute
     Paused(usize),
     Done,
+    /// The command couldn't be carried out, the state hasn't moved
+    Error(String),
+}
+
+/// Runs a state as another thread's `DebugCommand`s say, answering each with where it paused.
+/// A `RunToIndex` past the end of the innermost function is answered with an error.
+///
+/// The state can't move between threads, so it has to be started on the thread that calls this.
+/// Returns once the script is done or the front end hangs up.
+pub fn serve_commands<'a>(
+    st: ExecStateRef<'a>,
+    commands: &Receiver<DebugCommand>,
+    responses: &Sender<DebugResponse>,
+) {
+    let index = st.borrow().current_frame.index;
+    if responses.send(DebugResponse::Paused(index)).is_err() {
+        return;
+    }
+    while let Ok(command) = commands.recv() {
+        let target = match command {
+            DebugCommand::RunToIndex(index) => {
+                let length = st.borrow().current_frame.bfunc.target.code.len();
+                if index >= length {
+                    let message = format!(
+                        "Index {} is past the end of the function, which has {} instructions",
+                        index, length
+                    );
+                    if responses.send(DebugResponse::Error(message)).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(index)
+            }
+            DebugCommand::Run => None,
+        };
+        let response = loop {
+            if let ExecuteReturn::Suspended = execute_for(st, 1) {
+                let index = st.borrow().current_frame.index;
+                if target == Some(index) {
+                    break DebugResponse::Paused(index);
+                }
+            } else {
+                break DebugResponse::Done;
+            }
+        };
+        let is_done = matches!(response, DebugResponse::Done);
+        if responses.send(response).is_err() || is_done {
+            return;
+        }
+    }
+}
+
+fn describe_frame(state: &ExecutionState, frame: &StackFrame, is_bottom: bool) -> String {
+    // Modules are run one at a time at the bottom of the stack
+    if is_bottom && state.reentrant_depth == 0 {
+        if let Some((path, _)) = state.imports.last() {
+            return format!("module {}", path.join("/"));
+        }
+    }
+    format!("fn({})", frame.bfunc.target.as_ref().args.join(", "))
+}
+
+/// The frames currently executing, most recent call last
+pub fn traceback(state: &ExecutionState) -> String {
+    let mut out = String::from("Traceback (most recent call last):\n");
+    let frames = state
+        .execution_stack
+        .iter()
+        .chain(std::iter::once(&state.current_frame));
+    for (depth, frame) in frames.enumerate() {
+        // Each frame's index has already moved past the instruction it is running
+        let index = frame.index.saturating_sub(1);
+        let op = frame.bfunc.target.code[index];
+        writeln!(
+            out,
+            "  {}, instruction {}: {:?}",
+            describe_frame(state, frame, depth == 0),
+            index,
+            op
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// The error's builtin type and message if it has them
+pub fn describe_error(state: &ExecutionState, error: &Value) -> String {
+    let object = match error {
+        Value::Object(object) => object,
+        other => return other.to_string(),
+    };
+    let mut names = state
+        .builtins
+        .iter()
+        .filter_map(|(name, value)| match value {
+            Value::Object(prototype) if object::is_instance_of(object.clone(), prototype) => {
+                Some(*name)
+            }
+            _ => None,
+        });
+    let name = names.next().unwrap_or("Error");
+    let message = object
+        .borrow()
+        .get_native_data()
+        .downcast_ref::<PuslObject>()
+        .map(|pusl_obj| pusl_obj.get_field("message"));
+    match message {
+        Some(Value::String(message)) => format!("{}: {}", name, *message),
+        _ => format!("{}: {}", name, error),
+    }
+}
+
+/// A short description of a value for the debugger, only looking one level into objects
+pub fn describe_value(state: &ExecutionState, value: &Value) -> String {
+    describe_nested(state, value, true)
+}
+
+fn describe_nested(state: &ExecutionState, value: &Value, expand: bool) -> String {
+    match value {
+        Value::String(string) => format!("{:?}", **string),
+        Value::Function((FunctionTarget::Pusl(function), _)) => {
+            format!("fn({})", function.target.as_ref().args.join(", "))
+        }
+        Value::Function((FunctionTarget::Native(handle), _)) => match state.registry.get(*handle) {
+            Some((name, _)) => format!("native {}", name),
+            None => format!("native {}", handle),
+        },
+        Value::Object(object) => {
+            let borrow = match object.try_borrow() {
+                Ok(borrow) => borrow,
+                Err(_) => return "{...}".to_string(),
+            };
+            let data = borrow.get_native_data();
+            if let Some(pusl_obj) = data.downcast_ref::<PuslObject>() {
+                if !expand {
+                    return "{...}".to_string();
+                }
+                let fields = pusl_obj
+                    .own_fields()
+                    .into_iter()
+                    .map(|(name, value)| {
+                        format!("{}: {}", name, describe_nested(state, value, false))
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(", "))
+            } else if let Some(list) = data.downcast_ref::<List>() {
+                if !expand {
+                    return "[...]".to_string();
+                }
+                let values = list
+                    .vec
+                    .iter()
+                    .map(|value| describe_nested(state, value, false))
+                    .collect::<Vec<_>>();
+                format!("[{}]", values.join(", "))
+            } else {
+                format!("{:?}", borrow)
+            }
+        }
+        other => other.to_string(),
+    }
+}
+
+/// The values inside an object or list, which a debugger can expand the value into
+pub fn children(value: &Value) -> Vec<(String, Value)> {
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Vec::new(),
+    };
+    let borrow = match object.try_borrow() {
+        Ok(borrow) => borrow,
+        Err(_) => return Vec::new(),
+    };
+    let data = borrow.get_native_data();
+    if let Some(pusl_obj) = data.downcast_ref::<PuslObject>() {
+        let super_ptr = pusl_obj
+            .super_ptr()
+            .map(|super_ptr| ("super".to_string(), Value::Object(super_ptr.clone())));
+        let fields = pusl_obj
+            .own_fields()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.clone()));
+        super_ptr.into_iter().chain(fields).collect()
+    } else if let Some(list) = data.downcast_ref::<List>() {
+        list.vec
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (index.to_string(), value.clone()))
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// The executing frames, innermost first, so frame 0 is the one that is running
+fn frames<'s>(state: &'s ExecutionState) -> impl Iterator<Item = &'s StackFrame> {
+    std::iter::once(&state.current_frame).chain(state.execution_stack.iter().rev())
+}
+
+pub fn frame_count(state: &ExecutionState) -> usize {
+    state.execution_stack.len() + 1
+}
+
+/// The index of the module whose code contains `function`
+pub(super) fn module_of(state: &ExecutionState, function: &ResolvedFunction) -> Option<usize> {
+    fn contains(parent: &ResolvedFunction, function: &ResolvedFunction) -> bool {
+        std::ptr::eq(parent, function)
+            || parent
+                .sub_functions
+                .iter()
+                .any(|sub_function| contains(sub_function, function))
+    }
+    state
+        .module_functions
+        .iter()
+        .position(|module| contains(module, function))
+}
+
+/// Where a frame is in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// The path of the module the frame's function is from
+    pub module: String,
+    /// The function's arguments, None for a module's own code
+    pub function: Option<Vec<String>>,
+    pub line: Option<usize>,
+    /// The instruction the frame is on
+    pub index: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.module)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        match &self.function {
+            Some(args) => write!(f, " in fn({})", args.join(", ")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where the frame `depth` calls out from the innermost frame is, None if there is no such frame
+pub fn location(state: &ExecutionState, depth: usize) -> Option<Location> {
+    // Callers have already moved past the call they are waiting on
+    frame_location(state, depth, depth > 0)
+}
+
+/// Like `location`, for a state an uncaught error ended, whose innermost frame has also moved
+/// past the instruction that threw
+pub fn error_location(state: &ExecutionState, depth: usize) -> Option<Location> {
+    frame_location(state, depth, true)
+}
+
+fn frame_location(state: &ExecutionState, depth: usize, is_past: bool) -> Option<Location> {
+    let frame = frames(state).nth(depth)?;
+    let function = frame.bfunc.target;
+    let index = if is_past {
+        frame.index.saturating_sub(1)
+    } else {
+        frame.index
+    };
+    let module = module_of(state, function);
+    let is_module_code =
+        module.is_some_and(|module| std::ptr::eq(state.module_functions[module], function));
+    Some(Location {
+        module: module
+            .map(|module| state.imports[module].0.join("/"))
+            .unwrap_or_else(|| "<unknown>".to_string()),
+        function: if is_module_code {
+            None
+        } else {
+            Some(function.as_ref().args.clone())
+        },
+        line: function.line_at(index),
+        index,
+    })
+}
+
+/// The variables a frame can see, by name
+#[derive(Debug, Clone)]
+pub struct FrameVariables {
+    /// Every local variable slot, arguments first. Slots whose `let` hasn't run yet are null.
+    pub locals: Vec<(String, Value)>,
+    pub this: Option<Value>,
+    /// Values captured when the function was created
+    pub bound: Vec<(String, Value)>,
+    /// Intermediate values of the expression being evaluated
+    pub op_stack: Vec<Value>,
+}
+
+pub fn frame_variables(state: &ExecutionState, depth: usize) -> Option<FrameVariables> {
+    let frame = frames(state).nth(depth)?;
+    let function = frame.bfunc.target.as_ref();
+    Some(FrameVariables {
+        locals: function
+            .locals
+            .iter()
+            .cloned()
+            .zip(frame.locals.iter().cloned())
+            .collect(),
+        this: frame.this_obj.clone().map(Value::Object),
+        bound: function
+            .binds
+            .iter()
+            .cloned()
+            .zip(frame.bfunc.bound_values.iter().cloned())
+            .collect(),
+        op_stack: frame.op_stack.clone(),
+    })
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Evaluates an expression as if it were written in the frame `depth` calls out from the
+/// innermost frame. The frame's variables are copied in, so assigning to them has no effect on
+/// the frame, but objects are shared.
+pub fn evaluate<'a>(st: ExecStateRef<'a>, depth: usize, expression: &str) -> Result<Value, String> {
+    let (mut frame, rfunc) = {
+        let mut state = st.borrow_mut();
+        let variables = frame_variables(&state, depth).ok_or("No such frame")?;
+        let (imports, this) = {
+            let frame = frames(&state).nth(depth).unwrap();
+            (frame.bfunc.target.imports, frame.this_obj.clone())
+        };
+        // Later slots shadow earlier ones with the same name
+        let mut names: Vec<(String, Value)> = Vec::new();
+        for (name, value) in variables.bound.into_iter().chain(variables.locals) {
+            if !is_identifier(&name) {
+                continue;
+            }
+            names.retain(|(existing, _)| existing != &name);
+            names.push((name, value));
+        }
+
+        let args = names
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let source = [
+            format!("let eval = fn({}):", args),
+            format!(" return {}", expression),
+        ];
+        // The parser reports mistakes by panicking
+        let code = panic::catch_unwind(AssertUnwindSafe(|| {
+            linearize_file(parse(lex(source.iter().map(String::as_str))))
+        }))
+        .map_err(|_| format!("Unable to parse \"{}\"", expression))?;
+
+        let ExecutionState {
+            gc,
+            strings,
+            builtins,
+            ..
+        } = &mut *state;
+        let rfunc = code
+            .base_func
+            .resolve_with_objects(imports.clone(), gc, strings);
+        let function = rfunc.get_function(0);
+        // Unknown names panic when they are reached, so find them first
+        for op in &function.code {
+            let reference = match *op {
+                OpCode::PushReference(index) | OpCode::AssignReference(index, _) => {
+                    &function.as_ref().references[index]
+                }
+                _ => continue,
+            };
+            if !imports.iter().any(|(alias, _)| alias == reference)
+                && !builtins.contains_key(reference.as_str())
+            {
+                let message = format!("Unknown variable \"{}\"", reference);
+                // Nothing has been made from it yet
+                unsafe { ResolvedFunction::free(rfunc) };
+                return Err(message);
+            }
+        }
+        let mut frame = StackFrame::from_function(function.bind(Vec::new(), gc), this);
+        for (slot, (_, value)) in names.iter().enumerate() {
+            frame.locals[slot] = value.clone();
+        }
+        (frame, rfunc)
+    };
+    let result = run_frame_caught(&mut frame, st);
+    drop(frame);
+    // Observers can keep pointers to the functions they saw run
+    if rfunc.is_done_with() && st.borrow().observers.is_empty() {
+        unsafe { ResolvedFunction::free(rfunc) };
+    }
+    match result? {
+        ExecuteReturn::Return(value) => Ok(value),
+        ExecuteReturn::Error(error) => Err(describe_error(&st.borrow(), &error)),
+        ExecuteReturn::LimitExceeded(limit) => Err(format!("{:?} limit exceeded", limit)),
+        other => Err(format!("Unexpected result {:?}", other)),
+    }
+}
+
+/// How far to run before stopping again
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resume {
+    /// Until a breakpoint
+    Continue,
+    /// Until the start of the next line, in any function
+    StepIn,
+    /// Until the start of the next line in this function or one of its callers
+    StepOver,
+    /// Until this function returns
+    StepOut,
+}
+
+/// How far back to go in a recording
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reverse {
+    /// To the start of the line before, in any function
+    Step,
+    /// To the breakpoint before
+    Continue,
+}
+
+#[derive(Debug)]
+pub enum DebugEvent {
+    /// Stopped at a breakpoint, by its id
+    Breakpoint(usize),
+    /// Finished a step
+    Step,
+    /// Went back as far as the recording goes
+    RecordingStart,
+    /// The script finished, with an error if the stack is still there to be inspected
+    Finished(ExecuteReturn),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    /// A module path or the end of one, with or without the ".pusl"
+    pub file: String,
+    pub line: usize,
+}
+
+/// Does `file`, as the user wrote it, name the module at `path`
+fn is_module_file(path: &[String], file: &str) -> bool {
+    let module = path.join("/");
+    let module = module.trim_start_matches('/').trim_end_matches(".pusl");
+    let file = file
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches(".pusl");
+    module == file || module.ends_with(&format!("/{}", file))
+}
+
+/// Instructions run between the snapshots taken while recording
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// The most snapshots a recording keeps. Once there are this many the oldest is dropped for
+/// each new one, so a script can be taken back at most this many intervals of instructions.
+pub const MAX_CHECKPOINTS: usize = 100;
+
+/// What's kept while recording so a state can be taken back to any instruction it ran since
+/// the oldest snapshot: a snapshot every so often, which is rewound to and run forward from.
+struct Recording {
+    interval: u64,
+    /// Instructions run since recording started, the same after running again to get back here
+    step: u64,
+    /// The most instructions run, output from before this was already written
+    furthest: u64,
+    /// The modules waiting to run when recording started, the only code snapshots leave out
+    /// which the state might not have when it's rewound
+    waiting: Vec<(Vec<String>, ByteCodeFile)>,
+    /// Never empty, the oldest is as far back as the state can go
+    checkpoints: VecDeque<Checkpoint>,
+    /// Each step since the oldest checkpoint the state was at the start of a line after, so
+    /// earlier stops can be found without running again
+    line_starts: VecDeque<LineStart>,
+}
+
+struct Checkpoint {
+    step: u64,
+    snapshot: Vec<u8>,
+    /// Where the state was in its recorded inputs
+    input_position: usize,
 }
 
-pub fn make_interrupt() -> impl FnMut(&mut ExecutionState) {
-    |state| {
-        println!("Idx: {}", state.current_frame.index);
+struct LineStart {
+    step: u64,
+    /// The index of the module in the state's imports
+    module: usize,
+    line: usize,
+}
+
+/// Runs a state one instruction at a time, stopping at breakpoints and after steps.
+///
+/// Code run by native functions, like a generator's body while it is iterated, runs as a
+/// single instruction and can't be stopped in.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    recording: Option<Recording>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, file: String, line: usize) -> usize {
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id: self.next_id,
+            file,
+            line,
+        });
+        self.next_id
     }
-}
\ No newline at end of file
+
+    /// Returns false if there was no breakpoint with that id
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// The module and line the state is stopped at the start of
+    fn line_start(state: &ExecutionState) -> Option<(usize, usize)> {
+        let frame = &state.current_frame;
+        let function = frame.bfunc.target;
+        if !function.starts_line(frame.index) {
+            return None;
+        }
+        Some((module_of(state, function)?, function.line_at(frame.index)?))
+    }
+
+    /// The breakpoint on a line of the module at `module` in the state's imports
+    fn breakpoint_on(&self, state: &ExecutionState, module: usize, line: usize) -> Option<usize> {
+        let path = &state.imports[module].0;
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| breakpoint.line == line && is_module_file(path, &breakpoint.file))
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    /// The breakpoint the state is stopped at the start of
+    fn breakpoint_at(&self, state: &ExecutionState) -> Option<usize> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let (module, line) = Debugger::line_start(state)?;
+        self.breakpoint_on(state, module, line)
+    }
+
+    /// Starts keeping what's needed to go back to any instruction run from now on, with a
+    /// snapshot taken every `interval` instructions. Only the last `MAX_CHECKPOINTS` snapshots
+    /// are kept, so older instructions can't be gone back to. Results of native functions which
+    /// can differ between runs are recorded too, so going forward again does what it did before.
+    pub fn start_recording<'a>(
+        &mut self,
+        st: ExecStateRef<'a>,
+        interval: u64,
+    ) -> Result<(), SnapshotError> {
+        let mut state = st.borrow_mut();
+        let snapshot = snapshot::checkpoint(&state)?;
+        state.inputs.is_recording = true;
+        let line_starts = Debugger::line_start(&state)
+            .map(|(module, line)| LineStart {
+                step: 0,
+                module,
+                line,
+            })
+            .into_iter()
+            .collect();
+        self.recording = Some(Recording {
+            interval: interval.max(1),
+            step: 0,
+            furthest: 0,
+            waiting: state.resolve_stack.clone(),
+            checkpoints: VecDeque::from(vec![Checkpoint {
+                step: 0,
+                snapshot,
+                input_position: state.inputs.position,
+            }]),
+            line_starts,
+        });
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Runs one instruction, keeping the recording up to date if there is one. Output from
+    /// instructions which already ran once is thrown away.
+    fn execute_one<'a>(&mut self, st: ExecStateRef<'a>) -> ExecuteReturn {
+        let recording = match &mut self.recording {
+            Some(recording) => recording,
+            None => return execute_for(st, 1),
+        };
+        let is_replay = recording.step < recording.furthest;
+        let stream = if is_replay {
+            let sink = WriteOption::Sink(io::sink());
+            Some(std::mem::replace(&mut st.borrow_mut().stream, sink))
+        } else {
+            None
+        };
+        let result = execute_for(st, 1);
+        if let Some(stream) = stream {
+            st.borrow_mut().stream = stream;
+        }
+        recording.step += 1;
+        if recording.step <= recording.furthest {
+            return result;
+        }
+        recording.furthest = recording.step;
+        if let ExecuteReturn::Suspended = result {
+            let state = st.borrow();
+            if let Some((module, line)) = Debugger::line_start(&state) {
+                recording.line_starts.push_back(LineStart {
+                    step: recording.step,
+                    module,
+                    line,
+                });
+            }
+            let last = recording.checkpoints.back().map_or(0, |last| last.step);
+            if recording.step - last >= recording.interval {
+                // A state that can't be saved here is tried again after the next instruction
+                if let Ok(snapshot) = snapshot::checkpoint(&state) {
+                    recording.checkpoints.push_back(Checkpoint {
+                        step: recording.step,
+                        snapshot,
+                        input_position: state.inputs.position,
+                    });
+                    if recording.checkpoints.len() > MAX_CHECKPOINTS {
+                        recording.checkpoints.pop_front();
+                        let oldest = recording.checkpoints[0].step;
+                        while recording
+                            .line_starts
+                            .front()
+                            .is_some_and(|start| start.step < oldest)
+                        {
+                            recording.line_starts.pop_front();
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Takes the state back to how it was `step` instructions into the recording
+    fn rewind<'a>(&mut self, st: ExecStateRef<'a>, step: u64) -> Result<(), SnapshotError> {
+        let recording = self.recording.as_mut().unwrap();
+        let checkpoint = recording
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|checkpoint| checkpoint.step <= step)
+            .unwrap();
+        {
+            let mut state = st.borrow_mut();
+            snapshot::rewind_checkpoint(&mut state, &checkpoint.snapshot, &recording.waiting)?;
+            state.inputs.position = checkpoint.input_position;
+        }
+        recording.step = checkpoint.step;
+        while self.recording.as_ref().unwrap().step < step {
+            self.execute_one(st);
+        }
+        Ok(())
+    }
+
+    /// Goes back to an earlier stop in the recording. Fails if nothing is being recorded.
+    pub fn reverse<'a>(
+        &mut self,
+        st: ExecStateRef<'a>,
+        reverse: Reverse,
+    ) -> Result<DebugEvent, String> {
+        let recording = match &self.recording {
+            Some(recording) => recording,
+            None => return Err("Not recording, start with \"record\"".to_string()),
+        };
+        let stop = {
+            let state = st.borrow();
+            recording
+                .line_starts
+                .iter()
+                .rev()
+                .filter(|start| start.step < recording.step)
+                .find_map(|start| match reverse {
+                    Reverse::Step => Some((
+                        start.step,
+                        self.breakpoint_on(&state, start.module, start.line),
+                    )),
+                    Reverse::Continue => self
+                        .breakpoint_on(&state, start.module, start.line)
+                        .map(|id| (start.step, Some(id))),
+                })
+        };
+        let (step, event) = match stop {
+            Some((step, Some(id))) => (step, DebugEvent::Breakpoint(id)),
+            Some((step, None)) => (step, DebugEvent::Step),
+            None => (recording.checkpoints[0].step, DebugEvent::RecordingStart),
+        };
+        self.rewind(st, step).map_err(|err| err.to_string())?;
+        Ok(event)
+    }
+
+    /// Runs until the next stop. At least one instruction is run, so resuming from a breakpoint
+    /// doesn't stop at it again.
+    pub fn resume<'a>(&mut self, st: ExecStateRef<'a>, resume: Resume) -> DebugEvent {
+        let start_depth = st.borrow().call_depth();
+        loop {
+            match self.execute_one(st) {
+                ExecuteReturn::Suspended => {}
+                other => return DebugEvent::Finished(other),
+            }
+            let state = st.borrow();
+            if let Some(id) = self.breakpoint_at(&state) {
+                return DebugEvent::Breakpoint(id);
+            }
+            let frame = &state.current_frame;
+            let starts_line = frame.bfunc.target.starts_line(frame.index);
+            let depth = state.call_depth();
+            let is_done = match resume {
+                Resume::Continue => false,
+                Resume::StepIn => starts_line,
+                Resume::StepOver => starts_line && depth <= start_depth,
+                Resume::StepOut => depth < start_depth,
+            };
+            if is_done {
+                return DebugEvent::Step;
+            }
+        }
+    }
+}
+
+const HELP: &str = "\
+break FILE:LINE, b     stop when a line is reached
+delete ID              remove a breakpoint
+breakpoints            list breakpoints
+continue, c            run until a breakpoint
+step, s                run to the next line, stepping into calls
+next, n                run to the next line, stepping over calls
+finish                 run until the current function returns
+record [N]             record from here, with a snapshot every N instructions, so
+                       the script can be run backwards
+reverse-step, rs       go back to the start of the line before
+reverse-continue, rc   go back to the breakpoint before
+backtrace, bt          list the frames, innermost first
+frame N, f N           select a frame to inspect
+locals                 print the selected frame's local variables
+this                   print the selected frame's this
+bound                  print the selected frame's bound values
+stack                  print the selected frame's operand stack
+print EXPR, p EXPR     evaluate an expression in the selected frame
+quit, q                stop debugging
+";
+
+/// An interactive debugger reading commands from `input`. The script is stopped before its
+/// first instruction. Returns how the script finished, or None if the user quit first.
+pub fn run_session<'a>(
+    st: ExecStateRef<'a>,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> io::Result<Option<ExecuteReturn>> {
+    let mut debugger = Debugger::new();
+    let mut selected = 0;
+    let mut finished = None;
+    writeln!(output, "Stopped at {}", location(&st.borrow(), 0).unwrap())?;
+    loop {
+        write!(output, "(pusl) ")?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(finished);
+        }
+        let line = line.trim();
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let resume = match command {
+            "" => continue,
+            "continue" | "c" => Some(Resume::Continue),
+            "step" | "s" => Some(Resume::StepIn),
+            "next" | "n" => Some(Resume::StepOver),
+            "finish" => Some(Resume::StepOut),
+            _ => None,
+        };
+        let reverse = match command {
+            "reverse-step" | "rs" => Some(Reverse::Step),
+            "reverse-continue" | "rc" => Some(Reverse::Continue),
+            _ => None,
+        };
+        let event = if let Some(resume) = resume {
+            if finished.is_some() {
+                writeln!(output, "The script is not running")?;
+                continue;
+            }
+            Some(debugger.resume(st, resume))
+        } else if let Some(reverse) = reverse {
+            match debugger.reverse(st, reverse) {
+                Ok(event) => {
+                    finished = None;
+                    Some(event)
+                }
+                Err(message) => {
+                    writeln!(output, "{}", message)?;
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(event) = event {
+            selected = 0;
+            let state = st.borrow();
+            match event {
+                DebugEvent::Breakpoint(id) => writeln!(
+                    output,
+                    "Breakpoint {}, {}",
+                    id,
+                    location(&state, 0).unwrap()
+                )?,
+                DebugEvent::Step => writeln!(output, "{}", location(&state, 0).unwrap())?,
+                DebugEvent::RecordingStart => writeln!(
+                    output,
+                    "Reached the start of the recording, {}",
+                    location(&state, 0).unwrap()
+                )?,
+                DebugEvent::Finished(result) => {
+                    match &result {
+                        ExecuteReturn::Error(error) => {
+                            write!(output, "{}", traceback(&state))?;
+                            writeln!(output, "{}", describe_error(&state, error))?;
+                        }
+                        ExecuteReturn::LimitExceeded(limit) => {
+                            writeln!(output, "Execution stopped: {:?} limit exceeded", limit)?
+                        }
+                        ExecuteReturn::Exit(code) => {
+                            writeln!(output, "The script exited with code {}", code)?
+                        }
+                        _ => writeln!(output, "The script finished")?,
+                    }
+                    finished = Some(result);
+                }
+            }
+            continue;
+        }
+
+        match command {
+            "record" => {
+                let interval = if argument.is_empty() {
+                    Ok(DEFAULT_CHECKPOINT_INTERVAL)
+                } else {
+                    argument.parse()
+                };
+                match interval {
+                    Ok(_) if finished.is_some() => writeln!(output, "The script is not running")?,
+                    Ok(interval) => match debugger.start_recording(st, interval) {
+                        Ok(()) => writeln!(output, "Recording")?,
+                        Err(err) => writeln!(output, "Unable to record: {}", err)?,
+                    },
+                    Err(_) => writeln!(output, "Expected a number of instructions")?,
+                }
+            }
+            "break" | "b" => {
+                let parsed = argument
+                    .rsplit_once(':')
+                    .and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?)));
+                match parsed {
+                    Some((file, line)) => {
+                        let id = debugger.add_breakpoint(file.to_string(), line);
+                        writeln!(output, "Breakpoint {} at {}:{}", id, file, line)?;
+                    }
+                    None => writeln!(output, "Expected FILE:LINE")?,
+                }
+            }
+            "delete" => match argument.parse() {
+                Ok(id) if debugger.remove_breakpoint(id) => {
+                    writeln!(output, "Deleted breakpoint {}", id)?
+                }
+                _ => writeln!(output, "No breakpoint {}", argument)?,
+            },
+            "breakpoints" => {
+                for breakpoint in debugger.breakpoints() {
+                    writeln!(
+                        output,
+                        "{}: {}:{}",
+                        breakpoint.id, breakpoint.file, breakpoint.line
+                    )?;
+                }
+            }
+            "backtrace" | "bt" => {
+                let state = st.borrow();
+                for depth in 0..frame_count(&state) {
+                    let marker = if depth == selected { '*' } else { ' ' };
+                    writeln!(
+                        output,
+                        "{}{} {}",
+                        marker,
+                        depth,
+                        location(&state, depth).unwrap()
+                    )?;
+                }
+            }
+            "frame" | "f" => {
+                let state = st.borrow();
+                match argument
+                    .parse()
+                    .ok()
+                    .filter(|&depth| depth < frame_count(&state))
+                {
+                    Some(depth) => {
+                        selected = depth;
+                        writeln!(output, "{} {}", depth, location(&state, depth).unwrap())?;
+                    }
+                    None => writeln!(output, "No frame {}", argument)?,
+                }
+            }
+            "locals" | "this" | "bound" | "stack" => {
+                let state = st.borrow();
+                let variables = frame_variables(&state, selected).unwrap();
+                let describe = |value: &Value| describe_value(&state, value);
+                match command {
+                    "locals" => {
+                        for (name, value) in &variables.locals {
+                            writeln!(output, "{} = {}", name, describe(value))?;
+                        }
+                    }
+                    "this" => match &variables.this {
+                        Some(this) => writeln!(output, "{}", describe(this))?,
+                        None => writeln!(output, "null")?,
+                    },
+                    "bound" => {
+                        for (name, value) in &variables.bound {
+                            writeln!(output, "{} = {}", name, describe(value))?;
+                        }
+                    }
+                    _ => {
+                        for (index, value) in variables.op_stack.iter().enumerate() {
+                            writeln!(output, "{}: {}", index, describe(value))?;
+                        }
+                    }
+                }
+            }
+            "print" | "p" => match evaluate(st, selected, argument) {
+                Ok(value) => writeln!(output, "{}", describe_value(&st.borrow(), &value))?,
+                Err(message) => writeln!(output, "{}", message)?,
+            },
+            "help" | "h" => write!(output, "{}", HELP)?,
+            "quit" | "q" => return Ok(finished),
+            _ => writeln!(output, "Unknown command \"{}\", try \"help\"", command)?,
+        }
+    }
+}
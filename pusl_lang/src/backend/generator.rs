@@ -1,6 +1,6 @@
 use crate::backend::object::Value::Boolean;
-use crate::backend::object::{NativeFn, NativeFnHandle, Object, Value};
-use crate::backend::{execute, ExecuteReturn};
+use crate::backend::object::{NativeFnHandle, Object, Registry, Value};
+use crate::backend::{run_frame, ExecuteReturn};
 use garbage::MarkTrace;
 use std::any::Any;
 use std::{cell::RefCell, collections::HashMap};
@@ -14,16 +14,17 @@ use super::ExecutionState;
 use super::StackFrame;
 use super::{argparse, ExecStateRef};
 
-struct Generator {
-    stack: Option<StackFrame>,
-    next_val: Option<Value>,
-    fn_table: GeneratorBuiltin,
+pub(super) struct Generator {
+    pub(super) stack: Option<StackFrame>,
+    pub(super) next_val: Option<Value>,
+    pub(super) fn_table: GeneratorBuiltin,
 }
 
 impl MarkTrace for Generator {
     fn mark_trace(&self) {
-        // TODO:
-        // self.stack.map(|stack| stack)
+        if let Some(stack) = &self.stack {
+            stack.mark_trace();
+        }
         if let Some(val) = &self.next_val {
             val.mark_trace()
         }
@@ -56,7 +57,7 @@ impl Object for Generator {
 }
 
 #[derive(Debug)]
-struct IterationEnd;
+pub(super) struct IterationEnd;
 
 impl MarkTrace for IterationEnd {
     fn mark_trace(&self) {}
@@ -75,20 +76,20 @@ impl Object for IterationEnd {
 }
 
 #[derive(Copy, Clone)]
-struct GeneratorBuiltin {
+pub(super) struct GeneratorBuiltin {
     has_next: NativeFnHandle,
     next: NativeFnHandle,
 }
 
 pub fn register(
     builtins: &mut HashMap<&str, Value>,
-    registry: &mut Vec<NativeFn>,
+    registry: &mut Registry,
     data_map: &mut AnyMap,
 ) {
-    builtins.insert("is_end", Value::native_fn(is_end, registry));
+    builtins.insert("is_end", Value::native_fn("is_end", is_end, registry));
     data_map.insert::<GeneratorBuiltin>(GeneratorBuiltin {
-        has_next: Value::native_fn_handle(has_next, registry),
-        next: Value::native_fn_handle(next, registry),
+        has_next: Value::native_fn_handle("Generator.hasNext", has_next, registry),
+        next: Value::native_fn_handle("Generator.next", next, registry),
     });
 }
 
@@ -146,13 +147,24 @@ fn has_next<'a>(args: Vec<Value>, this: Option<Value>, st: ExecStateRef<'a>) ->
                         .expect("No stack in generator object"),
                     st,
                 );
-                // TODO: Pass through exceptions
-                if let Yield(val) = ex_return {
-                    generator.next_val = Some(val);
-                    true
-                } else {
-                    generator.next_val = Some(assemble_end(st));
-                    false
+                match ex_return {
+                    Yield(val) => {
+                        generator.next_val = Some(val);
+                        true
+                    }
+                    ExecuteReturn::Error(error) => {
+                        generator.next_val = Some(assemble_end(st));
+                        st.borrow_mut().raise(error);
+                        false
+                    }
+                    ExecuteReturn::Return(_) => {
+                        generator.next_val = Some(assemble_end(st));
+                        false
+                    }
+                    // The limit stays exceeded or the exit code stays set, so the caller stops
+                    // before using this
+                    ExecuteReturn::LimitExceeded(_) | ExecuteReturn::Exit(_) => false,
+                    ExecuteReturn::Suspended => unreachable!("Reentrant calls aren't suspended"),
                 }
             };
             Value::Boolean(has_next)
@@ -164,22 +176,6 @@ fn has_next<'a>(args: Vec<Value>, this: Option<Value>, st: ExecStateRef<'a>) ->
     }
 }
 
-fn run_frame<'a>(frame: &mut StackFrame, st: ExecStateRef<'a>) -> ExecuteReturn {
-    let mut old_stack = Vec::new();
-    {
-        let mut stb = st.borrow_mut();
-        std::mem::swap(frame, &mut stb.current_frame);
-        std::mem::swap(&mut old_stack, &mut stb.execution_stack);
-    }
-    let ret_val = execute(st);
-    {
-        let mut stb = st.borrow_mut();
-        std::mem::swap(frame, &mut stb.current_frame);
-        std::mem::swap(&mut old_stack, &mut stb.execution_stack);
-    }
-    ret_val
-}
-
 pub fn next<'a>(args: Vec<Value>, this: Option<Value>, st: ExecStateRef<'a>) -> Value {
     argparse::parse0(args);
     if let Some(Value::Object(obj_ptr)) = &this {
@@ -201,12 +197,19 @@ pub fn next<'a>(args: Vec<Value>, this: Option<Value>, st: ExecStateRef<'a>) ->
                         .expect("No stack in generator object"),
                     st,
                 );
-                // TODO: aass through exceptions
-                if let Yield(val) = ex_return {
-                    val
-                } else {
-                    generator.next_val = Some(assemble_end(st));
-                    assemble_end(st)
+                match ex_return {
+                    Yield(val) => val,
+                    ExecuteReturn::Error(error) => {
+                        generator.next_val = Some(assemble_end(st));
+                        st.borrow_mut().raise(error);
+                        Value::Null
+                    }
+                    ExecuteReturn::Return(_) => {
+                        generator.next_val = Some(assemble_end(st));
+                        assemble_end(st)
+                    }
+                    ExecuteReturn::LimitExceeded(_) | ExecuteReturn::Exit(_) => Value::Null,
+                    ExecuteReturn::Suspended => unreachable!("Reentrant calls aren't suspended"),
                 }
             }
         } else {
@@ -1,5 +1,6 @@
 use crate::backend::object::ObjectPtr;
-use crate::backend::object::{FnPtr, PuslObject, Value};
+use crate::backend::object::{FnPtr, InternTable, PuslObject, Value};
+use crate::backend::shape::InlineCache;
 use crate::backend::BoundFunction;
 use crate::lexer::token::Literal;
 use crate::parser::branch::{Branch, ConditionBody};
@@ -7,18 +8,20 @@ use crate::parser::expression::Expression;
 use crate::parser::expression::{AssignAccess, AssignmentFlags};
 use crate::parser::{Eval, ExpRef, Import, ParsedFile};
 
-use garbage::ManagedPool;
+use garbage::{ManagedPool, MarkTrace};
 use pad_adapter::PadAdapter;
 
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::fmt::Write;
 use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, DerefMut};
 
-use std::{env, fmt};
+use std::fmt;
 
 use super::opcode::{ByteCodeArray, OpCode};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ByteCodeFile {
     pub base_func: BasicFunction,
     pub imports: Vec<Import>,
@@ -48,6 +51,18 @@ impl Debug for ByteCodeFile {
 #[derive(Clone)]
 pub struct ResolvedFunction {
     function: Function,
+    /// The function's code decoded once, jump targets are instruction indexes
+    pub code: Vec<OpCode>,
+    /// Byte offset into `function.code` of each instruction in `code`
+    pub offsets: Vec<usize>,
+    /// The function's catches with instruction indexes rather than byte offsets
+    pub catches: Vec<ErrorCatch>,
+    /// The function's line table with instruction indexes rather than byte offsets
+    pub lines: Vec<(usize, usize)>,
+    /// An inline cache for each instruction in `code`, only used by `FieldAccess`
+    pub field_caches: Vec<Cell<InlineCache>>,
+    /// The literal pool as runtime values, string literals are interned
+    pub literals: Vec<Value>,
     pub imports: &'static Vec<(String, ObjectPtr)>,
     pub sub_functions: Vec<ResolvedFunction>,
 }
@@ -79,11 +94,136 @@ impl Debug for ResolvedFunction {
     }
 }
 
+// Resolved functions are never freed, so the literals they hold have to be kept alive with them
+impl MarkTrace for ResolvedFunction {
+    fn mark_trace(&self) {
+        self.literals
+            .iter()
+            .for_each(|literal| literal.mark_trace());
+        self.imports
+            .iter()
+            .for_each(|(_, object)| object.mark_trace());
+        self.sub_functions
+            .iter()
+            .for_each(|function| function.mark_trace());
+    }
+}
+
 impl ResolvedFunction {
+    fn new(
+        function: Function,
+        imports: &'static Vec<(String, ObjectPtr)>,
+        sub_functions: Vec<ResolvedFunction>,
+        gc: &mut ManagedPool,
+        strings: &mut InternTable,
+    ) -> Self {
+        let (code, offsets) = function.code.decode();
+        let to_index = |offset| {
+            offsets
+                .binary_search(&offset)
+                .expect("Catch is not on an instruction")
+        };
+        let catches = function
+            .catches
+            .iter()
+            .map(|catch| ErrorCatch {
+                begin: to_index(catch.begin),
+                filter: to_index(catch.filter),
+            })
+            .collect();
+        let lines = function
+            .lines
+            .iter()
+            .map(|&(offset, line)| (to_index(offset), line))
+            .collect();
+        let field_caches = vec![Cell::default(); code.len()];
+        let literals = function
+            .literals
+            .iter()
+            .map(|literal| match literal {
+                Literal::String(string) => {
+                    let ptr = match strings.get(string) {
+                        Some(ptr) => ptr.clone(),
+                        None => {
+                            let ptr = gc.place_in_heap(string.clone());
+                            strings.insert(string.clone(), ptr.clone());
+                            ptr
+                        }
+                    };
+                    Value::String(ptr)
+                }
+                other => other.clone().into_value(gc),
+            })
+            .collect();
+        ResolvedFunction {
+            function,
+            code,
+            offsets,
+            catches,
+            lines,
+            field_caches,
+            literals,
+            imports,
+            sub_functions,
+        }
+    }
+
+    /// The function as it was before being resolved, including its sub functions
+    pub fn to_basic(&self) -> BasicFunction {
+        BasicFunction::from_parts(
+            self.function.clone(),
+            self.sub_functions
+                .iter()
+                .map(ResolvedFunction::to_basic)
+                .collect(),
+        )
+    }
+
+    /// The source line the instruction at `index` came from, if it is known
+    pub fn line_at(&self, index: usize) -> Option<usize> {
+        let entry = self.lines.partition_point(|&(start, _)| start <= index);
+        entry.checked_sub(1).map(|entry| self.lines[entry].1)
+    }
+
+    /// Is the instruction at `index` the first one from its source line
+    pub fn starts_line(&self, index: usize) -> bool {
+        self.lines
+            .binary_search_by_key(&index, |&(start, _)| start)
+            .is_ok()
+    }
+
     pub fn get_function(&self, pool_index: usize) -> &ResolvedFunction {
         &self.sub_functions[pool_index]
     }
 
+    /// Whether a base function holding just the one function that was run can be freed once
+    /// that function's frame is gone. Nothing can still refer to it unless the function made
+    /// functions, is a generator or pushed itself.
+    pub(crate) fn is_done_with(&self) -> bool {
+        match self.sub_functions.as_slice() {
+            [function] => {
+                function.sub_functions.is_empty()
+                    && !function.as_ref().is_generator
+                    && !function
+                        .code
+                        .iter()
+                        .any(|op| matches!(op, OpCode::PushSelf))
+            }
+            _ => false,
+        }
+    }
+
+    /// Frees a base function made by `resolve_with_objects`, along with the imports it was given
+    ///
+    /// # Safety
+    ///
+    /// Nothing can refer to the function, its sub functions or its imports afterwards
+    pub(crate) unsafe fn free(function: &'static ResolvedFunction) {
+        let imports = function.imports as *const Vec<(String, ObjectPtr)>;
+        drop(Box::from_raw(function as *const _ as *mut ResolvedFunction));
+        drop(Box::from_raw(imports as *mut Vec<(String, ObjectPtr)>));
+    }
+
     pub fn bind(&'static self, bound_values: Vec<Value>, gc: &mut ManagedPool) -> FnPtr {
         let bfunc = BoundFunction {
             target: self,
@@ -109,11 +249,15 @@ pub struct ErrorCatch {
 pub struct Function {
     pub args: Vec<String>,
     pub binds: Vec<String>,
+    /// The name of each local variable slot, arguments take the first slots
+    pub locals: Vec<String>,
     pub literals: Vec<Literal>,
     pub references: Vec<String>,
     pub catches: Vec<ErrorCatch>,
     pub code: ByteCodeArray,
     pub is_generator: bool,
+    /// (byte offset, line number) for where the code from each source line starts, in order
+    pub lines: Vec<(usize, usize)>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -128,6 +272,12 @@ impl AsRef<Function> for BasicFunction {
     }
 }
 
+impl AsMut<Function> for BasicFunction {
+    fn as_mut(&mut self) -> &mut Function {
+        &mut self.function
+    }
+}
+
 impl Debug for BasicFunction {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         if !f.alternate() {
@@ -157,11 +307,19 @@ impl From<Function> for BasicFunction {
 }
 
 impl BasicFunction {
+    pub fn from_parts(function: Function, sub_functions: Vec<BasicFunction>) -> Self {
+        BasicFunction {
+            function,
+            sub_functions,
+        }
+    }
+
     pub fn resolve<'a, I>(
         self,
         global_imports: I,
         target_imports: Vec<Import>,
         gc: &mut ManagedPool,
+        strings: &mut InternTable,
     ) -> &'static ResolvedFunction
     where
         I: IntoIterator<Item = &'a (Vec<String>, ObjectPtr)>,
@@ -183,35 +341,50 @@ impl BasicFunction {
             imports.push((alias, import_ptr));
         }
 
+        BasicFunction {
+            function,
+            sub_functions,
+        }
+        .resolve_with_objects(imports, gc, strings)
+    }
+
+    /// Resolves a module's base function where the objects for its imports already exist
+    pub fn resolve_with_objects(
+        self,
+        imports: Vec<(String, ObjectPtr)>,
+        gc: &mut ManagedPool,
+        strings: &mut InternTable,
+    ) -> &'static ResolvedFunction {
+        let BasicFunction {
+            function,
+            sub_functions,
+        } = self;
         let imports: &Vec<_> = Box::leak(Box::new(imports));
 
         let sub_functions = sub_functions
             .into_iter()
-            .map(|f| f.sub_resolve(imports))
+            .map(|f| f.sub_resolve(imports, gc, strings))
             .collect();
 
-        let result = ResolvedFunction {
-            function,
-            imports,
-            sub_functions,
-        };
+        let result = ResolvedFunction::new(function, imports, sub_functions, gc, strings);
         Box::leak(Box::new(result))
     }
 
-    fn sub_resolve(self, imports: &'static Vec<(String, ObjectPtr)>) -> ResolvedFunction {
+    fn sub_resolve(
+        self,
+        imports: &'static Vec<(String, ObjectPtr)>,
+        gc: &mut ManagedPool,
+        strings: &mut InternTable,
+    ) -> ResolvedFunction {
         let BasicFunction {
             function,
             sub_functions,
         } = self;
         let sub_functions = sub_functions
             .into_iter()
-            .map(|f| f.sub_resolve(imports))
+            .map(|f| f.sub_resolve(imports, gc, strings))
             .collect();
-        ResolvedFunction {
-            function,
-            sub_functions,
-            imports,
-        }
+        ResolvedFunction::new(function, imports, sub_functions, gc, strings)
     }
 }
 
@@ -229,9 +402,10 @@ impl Debug for Function {
         if !f.alternate() {
             write!(
                 f,
-                " - lits: {}, refs: {}, catches: {}, code: {}",
+                " - lits: {}, refs: {}, locals: {}, catches: {}, code: {}",
                 self.literals.len(),
                 self.references.len(),
+                self.locals.len(),
                 self.catches.len(),
                 self.code.len(),
             )?;
@@ -244,6 +418,10 @@ impl Debug for Function {
             for (index, reference) in self.references.iter().enumerate() {
                 writeln!(f, "    {:3}; {}", index, reference)?;
             }
+            writeln!(f, "Locals:")?;
+            for (index, local) in self.locals.iter().enumerate() {
+                writeln!(f, "    {:3}; {}", index, local)?;
+            }
             writeln!(f, "Catches:")?;
             for (index, catch) in self.catches.iter().enumerate() {
                 writeln!(
@@ -255,6 +433,10 @@ impl Debug for Function {
                     catch.filter
                 )?;
             }
+            writeln!(f, "Lines:")?;
+            for (offset, line) in &self.lines {
+                writeln!(f, "    {:3}; line {}", offset, line)?;
+            }
             writeln!(f, "Code:")?;
             let mut code_iter = self.code.iter().peekable();
 
@@ -310,6 +492,7 @@ impl Function {
 
     fn new(args: Vec<String>, binds: Vec<String>) -> Function {
         Function {
+            locals: args.clone(),
             args,
             binds,
             literals: vec![],
@@ -317,32 +500,147 @@ impl Function {
             code: ByteCodeArray::new(),
             catches: vec![],
             is_generator: false,
+            lines: vec![],
         }
     }
+
+    /// Code pushed from now on comes from `line`
+    fn start_line(&mut self, line: usize) {
+        let offset = self.code.len();
+        // A line with no code of its own is replaced by the one after it
+        if self.lines.last().is_some_and(|&(start, _)| start == offset) {
+            self.lines.pop();
+        }
+        if self.current_line() != Some(line) {
+            self.lines.push((offset, line));
+        }
+    }
+
+    fn current_line(&self) -> Option<usize> {
+        self.lines.last().map(|&(_, line)| line)
+    }
 }
 
 pub fn linearize_file(file: ParsedFile) -> ByteCodeFile {
     let ParsedFile { expr, imports } = file;
     let func = linearize(expr, vec![], vec![]);
-    let bcf = ByteCodeFile {
+    ByteCodeFile {
         base_func: func,
         imports,
-    };
-    if env::var("PUSL_TRACE_CODE").is_ok() {
-        println!("Code:\n{:#?}", &bcf)
     }
-    bcf
+}
+
+/// A function being linearized along with the local variables currently in scope
+struct FunctionScope {
+    basic: BasicFunction,
+    /// Each block's variables as (name, slot), innermost block last
+    scopes: Vec<Vec<(String, usize)>>,
+    /// How many try bodies the code being linearized is inside of
+    try_depth: usize,
+}
+
+impl Deref for FunctionScope {
+    type Target = BasicFunction;
+
+    fn deref(&self) -> &Self::Target {
+        &self.basic
+    }
+}
+
+impl DerefMut for FunctionScope {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.basic
+    }
+}
+
+impl FunctionScope {
+    fn new(args: Vec<String>, binds: Vec<String>) -> Self {
+        let arg_scope = args
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(slot, name)| (name, slot))
+            .collect();
+        FunctionScope {
+            basic: BasicFunction::from(Function::new(args, binds)),
+            scopes: vec![arg_scope],
+            try_depth: 0,
+        }
+    }
+
+    fn scope_up(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn scope_down(&mut self) {
+        self.scopes.pop().expect("Unbalanced scopes");
+    }
+
+    /// Give a `let` variable a new slot, shadowing any variable with the same name
+    fn declare(&mut self, name: String) -> usize {
+        let slot = self.basic.function.locals.len();
+        self.basic.function.locals.push(name.clone());
+        self.scopes
+            .last_mut()
+            .expect("Unbalanced scopes")
+            .push((name, slot));
+        slot
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| local == name)
+            .map(|&(_, slot)| slot)
+    }
+
+    /// Push the value of a variable, only falling back to a name lookup at runtime
+    /// for things that aren't known until then (imports and builtins)
+    fn push_variable(&mut self, name: String) {
+        let op_code = if let Some(slot) = self.lookup(&name) {
+            OpCode::PushLocal(slot)
+        } else if let Some(index) = self.basic.function.binds.iter().position(|b| b == &name) {
+            OpCode::PushBound(index)
+        } else {
+            OpCode::PushReference(self.basic.function.add_reference(name))
+        };
+        self.basic.function.code.push(op_code);
+    }
+
+    /// Pop the top of the stack into a variable
+    fn assign_variable(&mut self, name: String, is_let: bool) {
+        let op_code = if is_let {
+            OpCode::AssignLocal(self.declare(name))
+        } else if let Some(slot) = self.lookup(&name) {
+            OpCode::AssignLocal(slot)
+        } else {
+            // Not a local, this will fail at runtime
+            OpCode::AssignReference(self.basic.function.add_reference(name), false)
+        };
+        self.basic.function.code.push(op_code);
+    }
 }
 
 fn linearize(expr: ExpRef, args: Vec<String>, binds: Vec<String>) -> BasicFunction {
-    let code = Function::new(args, binds);
-    let mut function = BasicFunction::from(code);
+    let mut function = FunctionScope::new(args, binds);
     linearize_exp_ref(expr, &mut function, false);
+    // Going back to an outer line at the very end leaves an entry with no code
+    let code_len = function.function.code.len();
+    if function
+        .function
+        .lines
+        .last()
+        .is_some_and(|&(start, _)| start == code_len)
+    {
+        function.function.lines.pop();
+    }
 
-    function
+    function.basic
 }
 
-fn linearize_exp_ref(exp_ref: ExpRef, func: &mut BasicFunction, expand_stack: bool) {
+fn linearize_exp_ref(exp_ref: ExpRef, func: &mut FunctionScope, expand_stack: bool) {
     match *exp_ref {
         Eval::Branch(branch) => {
             assert!(!expand_stack);
@@ -352,7 +650,7 @@ fn linearize_exp_ref(exp_ref: ExpRef, func: &mut BasicFunction, expand_stack: bo
     }
 }
 
-fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool) {
+fn linearize_expr(expr: Expression, func: &mut FunctionScope, expand_stack: bool) {
     let created_value = match expr {
         Expression::Modulus { lhs, rhs } => {
             linearize_exp_ref(lhs, func, true);
@@ -370,12 +668,20 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
             true
         }
         Expression::Reference { target } => {
-            let reference_index = func.function.add_reference(target);
-            func.function
-                .code
-                .push(OpCode::PushReference(reference_index));
+            func.push_variable(target);
             true
         }
+        Expression::Line { number, expression } => {
+            let outer_line = func.function.current_line();
+            func.function.start_line(number);
+            linearize_exp_ref(expression, func, expand_stack);
+            // Code the enclosing statement emits after this one, like a loop's jump back, is
+            // part of the enclosing statement's line
+            if let Some(outer_line) = outer_line {
+                func.function.start_line(outer_line);
+            }
+            expand_stack
+        }
         Expression::Joiner { expressions } => {
             assert!(!expand_stack);
             expressions
@@ -468,9 +774,8 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
                     }
                 }
                 AssignAccess::Reference { name } => {
-                    let target_index = func.function.add_reference(name);
                     let skip_setter = if flags.intersects(AssignmentFlags::CONDITIONAL) {
-                        func.function.code.push(OpCode::PushReference(target_index));
+                        func.push_variable(name.clone());
                         func.function.code.push(OpCode::IsNull);
                         func.function.code.push(OpCode::Negate);
                         Some(func.function.code.place_jump(true))
@@ -478,10 +783,7 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
                         None
                     };
                     linearize_exp_ref(expression, func, true);
-                    func.function.code.push(OpCode::AssignReference(
-                        target_index,
-                        flags.intersects(AssignmentFlags::LET),
-                    ));
+                    func.assign_variable(name, flags.intersects(AssignmentFlags::LET));
                     if let Some(jump_setter) = skip_setter {
                         jump_setter(&mut func.function.code, None);
                     }
@@ -558,6 +860,11 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
             params,
             body,
         } => {
+            // Bound values are captured from the stack
+            binds
+                .iter()
+                .cloned()
+                .for_each(|bind| func.push_variable(bind));
             let new_func = linearize(body, params, binds);
             let index = func.sub_functions.len();
             func.sub_functions.push(new_func);
@@ -565,7 +872,20 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
             true
         }
         Expression::Return { value } => {
-            linearize_exp_ref(value, func, true);
+            match *value {
+                // The frame can't be reused if a catch in it has to see errors from the call
+                Eval::Expression(Expression::FunctionCall { target, arguments })
+                    if func.try_depth == 0 =>
+                {
+                    linearize_exp_ref(target, func, true);
+                    let num_args = arguments.len();
+                    arguments
+                        .into_iter()
+                        .for_each(|argument| linearize_exp_ref(argument, func, true));
+                    func.function.code.push(OpCode::TailCall(num_args));
+                }
+                value => linearize_exp_ref(Box::new(value), func, true),
+            }
             func.function.code.push(OpCode::Return);
             false
         }
@@ -611,7 +931,7 @@ fn linearize_expr(expr: Expression, func: &mut BasicFunction, expand_stack: bool
     }
 }
 
-fn linearize_branch(branch: Branch, func: &mut BasicFunction) {
+fn linearize_branch(branch: Branch, func: &mut FunctionScope) {
     match branch {
         Branch::WhileLoop { condition, body } => linearize_while(condition, body, func),
         Branch::IfElseBlock { conditions, last } => linearize_if_else(conditions, last, func),
@@ -645,7 +965,7 @@ fn linearize_compare(
     equal: u8,
     less: u8,
     body: Vec<ExpRef>,
-    func: &mut BasicFunction,
+    func: &mut FunctionScope,
 ) {
     assert!((greater as usize) < body.len());
     assert!((equal as usize) < body.len());
@@ -657,9 +977,9 @@ fn linearize_compare(
         .into_iter()
         .map(|expr| {
             let start_index = func.function.code.len();
-            func.function.code.push(OpCode::ScopeUp);
+            func.scope_up();
             linearize_exp_ref(expr, func, false);
-            func.function.code.push(OpCode::ScopeDown);
+            func.scope_down();
             let jump_out_setter = func.function.code.place_jump(false);
             (start_index, jump_out_setter)
         })
@@ -682,10 +1002,12 @@ fn linearize_try(
     filter_expr: ExpRef,
     error_variable: String,
     yoink_body: ExpRef,
-    func: &mut BasicFunction,
+    func: &mut FunctionScope,
 ) {
     let try_begin_index = func.function.code.len();
+    func.try_depth += 1;
     linearize_exp_ref(try_body, func, false);
+    func.try_depth -= 1;
     let skip_filter_setter = func.function.code.place_jump(false);
     let try_filter_index = func.function.code.len();
     // Runtime places error on stack
@@ -703,10 +1025,7 @@ fn linearize_try(
     func.function.code.push(OpCode::Yeet); // Re-throw error
     filter_match_jump_setter(&mut func.function.code, None);
     // Stack is now error and instance_of = true
-    let error_var_idx = func.function.add_reference(error_variable);
-    func.function
-        .code
-        .push(OpCode::AssignReference(error_var_idx, true));
+    func.assign_variable(error_variable, true);
 
     // Stack is now empty
     linearize_exp_ref(yoink_body, func, false);
@@ -719,7 +1038,7 @@ fn linearize_try(
     });
 }
 
-fn linearize_for(variable: String, iterable: ExpRef, body: ExpRef, func: &mut BasicFunction) {
+fn linearize_for(variable: String, iterable: ExpRef, body: ExpRef, func: &mut FunctionScope) {
     linearize_exp_ref(iterable, func, true);
     let condition_idx = func.function.code.len();
     func.function.code.push(OpCode::Duplicate);
@@ -730,19 +1049,15 @@ fn linearize_for(variable: String, iterable: ExpRef, body: ExpRef, func: &mut Ba
     func.function.code.push(OpCode::FunctionCall(0));
     func.function.code.push(OpCode::Negate);
     let loop_end_setter = func.function.code.place_jump(true);
-    func.function.code.push(OpCode::ScopeUp);
-    // AssignReference, // 1 Stack Value (value) and 2 opcode (reference, type)
+    func.scope_up();
     func.function.code.push(OpCode::Duplicate);
     let next_reference = func.function.add_reference("next".to_string());
     func.function.code.push(OpCode::FieldAccess(next_reference));
     func.function.code.push(OpCode::FunctionCall(0));
 
-    let target_idx = func.function.add_reference(variable);
-    func.function
-        .code
-        .push(OpCode::AssignReference(target_idx, true));
+    func.assign_variable(variable, true);
     linearize_exp_ref(body, func, false);
-    func.function.code.push(OpCode::ScopeDown);
+    func.scope_down();
     func.function.code.push(OpCode::jump(false, condition_idx));
 
     loop_end_setter(&mut func.function.code, None);
@@ -752,7 +1067,7 @@ fn linearize_for(variable: String, iterable: ExpRef, body: ExpRef, func: &mut Ba
 fn linearize_if_else(
     conditions: Vec<ConditionBody>,
     last: Option<ExpRef>,
-    func: &mut BasicFunction,
+    func: &mut FunctionScope,
 ) {
     let place_conditions = conditions
         .into_iter()
@@ -763,18 +1078,18 @@ fn linearize_if_else(
         })
         .collect::<Vec<_>>();
     if let Some(else_expr) = last {
-        func.function.code.push(OpCode::ScopeUp);
+        func.scope_up();
         linearize_exp_ref(else_expr, func, false);
-        func.function.code.push(OpCode::ScopeDown);
+        func.scope_down();
     }
     let jump_to_end_setter = func.function.code.place_jump(false);
     let place_bodies = place_conditions
         .into_iter()
         .map(|(jump_setter, body)| {
             jump_setter(&mut func.function.code, None);
-            func.function.code.push(OpCode::ScopeUp);
+            func.scope_up();
             linearize_exp_ref(body, func, false);
-            func.function.code.push(OpCode::ScopeDown);
+            func.scope_down();
             let jump_to_end_setter = func.function.code.place_jump(false);
             jump_to_end_setter
         })
@@ -786,14 +1101,14 @@ fn linearize_if_else(
     jump_to_end_setter(&mut func.function.code, Some(jump_to));
 }
 
-fn linearize_while(condition: ExpRef, body: ExpRef, func: &mut BasicFunction) {
+fn linearize_while(condition: ExpRef, body: ExpRef, func: &mut FunctionScope) {
     let begin_index = func.function.code.len();
     linearize_exp_ref(condition, func, true);
     func.function.code.push(OpCode::Negate);
     let condition_jump_setter = func.function.code.place_jump(true);
-    func.function.code.push(OpCode::ScopeUp);
+    func.scope_up();
     linearize_exp_ref(body, func, false);
-    func.function.code.push(OpCode::ScopeDown);
+    func.scope_down();
     func.function.code.push(OpCode::jump(false, begin_index));
     condition_jump_setter(&mut func.function.code, None);
 }
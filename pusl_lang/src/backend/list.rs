@@ -1,16 +1,16 @@
 use crate::backend::argparse;
 use crate::backend::object::{NativeFnHandle, Object, ObjectPtr, Value};
 use anymap::AnyMap;
-use garbage::MarkTrace;
+use garbage::{ManagedPool, MarkTrace};
 use std::any::Any;
 use std::fmt::Debug;
 use std::{cell::RefCell, collections::HashMap, fmt};
 
-use super::{object::NativeFn, ExecStateRef};
+use super::{object::Registry, ExecStateRef};
 
-struct List {
-    vec: Vec<Value>,
-    fn_table: ListBuiltin,
+pub(super) struct List {
+    pub(super) vec: Vec<Value>,
+    pub(super) fn_table: ListBuiltin,
 }
 
 impl Debug for List {
@@ -27,6 +27,10 @@ impl MarkTrace for List {
             value.mark_trace()
         }
     }
+
+    fn heap_size(&self) -> usize {
+        self.vec.heap_size()
+    }
 }
 
 impl Object for List {
@@ -49,7 +53,7 @@ impl Object for List {
 }
 
 #[derive(Copy, Clone)]
-struct ListBuiltin {
+pub(super) struct ListBuiltin {
     push_index: NativeFnHandle,
     list_index_get: NativeFnHandle,
     list_index_set: NativeFnHandle,
@@ -58,18 +62,26 @@ struct ListBuiltin {
 
 pub fn register(
     builtins: &mut HashMap<&str, Value>,
-    registry: &mut Vec<NativeFn>,
+    registry: &mut Registry,
     data_map: &mut AnyMap,
 ) {
-    builtins.insert("List", Value::native_fn(new_list, registry));
+    builtins.insert("List", Value::native_fn("List", new_list, registry));
     data_map.insert::<ListBuiltin>(ListBuiltin {
-        push_index: Value::native_fn_handle(list_push, registry),
-        list_index_get: Value::native_fn_handle(list_index_get, registry),
-        list_index_set: Value::native_fn_handle(list_index_set, registry),
-        list_len: Value::native_fn_handle(list_len, registry),
+        push_index: Value::native_fn_handle("List.push", list_push, registry),
+        list_index_get: Value::native_fn_handle("List.@index_get", list_index_get, registry),
+        list_index_set: Value::native_fn_handle("List.@index_set", list_index_set, registry),
+        list_len: Value::native_fn_handle("List.len", list_len, registry),
     });
 }
 
+/// A list holding `vec`, for lists made by the runtime rather than by pusl code
+pub(super) fn make_list(vec: Vec<Value>, builtin_data: &AnyMap, gc: &mut ManagedPool) -> ObjectPtr {
+    let fn_table = *builtin_data
+        .get::<ListBuiltin>()
+        .expect("List Builtins are not loaded");
+    gc.place_in_heap(RefCell::new(List { vec, fn_table })) as ObjectPtr
+}
+
 fn new_list(args: Vec<Value>, _: Option<Value>, st: ExecStateRef) -> Value {
     let list_builtins = *st
         .borrow()
@@ -100,10 +112,13 @@ fn get_list_vec<R, T: FnOnce(&mut Vec<Value>) -> R>(object: &Option<Value>, acti
     }
 }
 
-fn list_push(mut args: Vec<Value>, this: Option<Value>, _: ExecStateRef) -> Value {
+fn list_push(mut args: Vec<Value>, this: Option<Value>, st: ExecStateRef) -> Value {
     let value = args.pop().expect("must call push with 1 argument");
     assert!(args.is_empty());
     get_list_vec(&this, |vec| vec.push(value));
+    if let Some(Value::Object(gc_ptr)) = &this {
+        st.borrow_mut().gc.resize(gc_ptr);
+    }
     Value::Null
 }
 
@@ -1,13 +1,16 @@
-use std::{cell::RefCell, collections::HashMap, env, ffi::OsStr, io};
+use std::{cell::RefCell, collections::HashMap, ffi::OsStr, io};
 
 use anymap::AnyMap;
 use garbage::{Gc, ManagedPool, MarkTrace};
 
 use crate::backend::linearize::ByteCodeFile;
-use crate::backend::object::{FnPtr, Object, ObjectPtr, PuslObject, Value};
+use crate::backend::object::{FnPtr, InternTable, Object, ObjectPtr, PuslObject, Value};
 use crate::parser::expression::Compare;
 use std::cmp::Ordering;
 use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::time::Instant;
 
 use std::fmt::{self, Debug};
 
@@ -16,21 +19,33 @@ pub type ExecStateRef<'a> = &'a RefCell<ExecutionState<'a>>;
 #[macro_use]
 pub mod object;
 pub mod argparse;
+pub mod assembly;
 pub mod builtins;
+pub mod check;
+pub mod coverage;
 pub mod debug;
 pub mod generator;
 pub mod linearize;
 pub mod list;
+pub mod observe;
 pub mod opcode;
+pub mod optimize;
+pub mod profile;
+pub mod repl;
+pub mod shape;
+pub mod snapshot;
 
 use fmt::Formatter;
+use std::mem;
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
 
 use crate::backend::ExecuteReturn::{Return, Yield};
 use linearize::ResolvedFunction;
 
 use self::{
-    object::{FunctionTarget, NativeFn},
+    object::{FunctionTarget, NativeFn, Registry},
+    observe::Observer,
     opcode::OpCode,
 };
 
@@ -44,7 +59,12 @@ impl MarkTrace for BoundFunction {
     fn mark_trace(&self) {
         self.bound_values
             .iter()
-            .for_each(|value| value.mark_trace())
+            .for_each(|value| value.mark_trace());
+        self.target.mark_trace();
+    }
+
+    fn heap_size(&self) -> usize {
+        self.bound_values.heap_size()
     }
 }
 
@@ -69,34 +89,33 @@ impl Debug for BoundFunction {
     }
 }
 
-// TODO: implement scopes better
-#[derive(Debug)]
-enum VariableStack {
-    Variable(Variable),
-    ScopeBoundary,
-}
-
-#[derive(Debug)]
-struct Variable {
-    value: Value,
-    name: String,
-}
-
 #[derive(Debug)]
 pub struct StackFrame {
     this_obj: Option<ObjectPtr>,
     bfunc: FnPtr,
-    variables: Vec<VariableStack>,
+    locals: Vec<Value>,
     op_stack: Vec<Value>,
     index: usize,
 }
 
+impl MarkTrace for StackFrame {
+    fn mark_trace(&self) {
+        if let Some(this_obj) = &self.this_obj {
+            this_obj.mark_trace();
+        }
+        self.bfunc.mark_trace();
+        self.locals.iter().for_each(|value| value.mark_trace());
+        self.op_stack.iter().for_each(|value| value.mark_trace());
+    }
+}
+
 impl StackFrame {
     fn from_function(bfunc: FnPtr, this_obj: Option<ObjectPtr>) -> Self {
+        let locals = vec![Value::Null; bfunc.target.as_ref().locals.len()];
         StackFrame {
             this_obj,
             bfunc,
-            variables: vec![],
+            locals,
             op_stack: vec![],
             index: 0,
         }
@@ -106,28 +125,46 @@ impl StackFrame {
         let to_insert = PuslObject::new();
         let new_object = gc.place_in_heap(to_insert) as Gc<RefCell<dyn Object>>;
 
-        let frame = StackFrame {
-            this_obj: Some(new_object.clone()),
-            bfunc,
-            variables: vec![],
-            op_stack: vec![],
-            index: 0,
-        };
+        let frame = StackFrame::from_function(bfunc, Some(new_object.clone()));
         (frame, new_object)
     }
 
     pub fn get_code(&mut self) -> Option<OpCode> {
-        let code = self.bfunc.target.as_ref().code.get(self.index);
-        code.as_ref()
-            .map(|(_, new_offset)| self.index = *new_offset);
-        code.map(|(code, _)| code)
+        let code = self.bfunc.target.code.get(self.index).copied();
+        if code.is_some() {
+            self.index += 1;
+        }
+        code
     }
 }
 
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Checking the clock is slow, so the deadline is only checked this often
+const DEADLINE_CHECK_INTERVAL: u32 = 1024;
+
+/// Each native function that calls back into pusl code (like a generator resuming) runs a new
+/// `execute` on the native stack, so how deep that nests has to be limited much further
+pub const DEFAULT_MAX_REENTRANT_DEPTH: usize = 128;
+
 pub struct ExecContext<'a> {
     pub resolve: fn(Vec<String>) -> Option<ByteCodeFile>,
     pub stream: Option<&'a mut dyn io::Write>,
-    pub interrupt: Option<&'a mut dyn FnMut(&mut ExecutionState<'a>)>,
+    /// Calls deeper than this raise a StackOverflowError
+    pub max_call_depth: usize,
+    /// Native functions calling into pusl code nested deeper than this raise a StackOverflowError
+    pub max_reentrant_depth: usize,
+    /// How many instructions can be executed in total
+    pub fuel: Option<u64>,
+    /// How many bytes the garbage collected heap can grow to. Unreachable values are collected
+    /// before giving up, so only what the script still holds on to counts
+    pub max_heap: Option<usize>,
+    /// When execution has to stop by
+    pub deadline: Option<Instant>,
+    /// Told about everything the script does as it runs, in order
+    pub observers: Vec<&'a mut dyn Observer<'a>>,
+    /// Given to the script as the `args` list
+    pub args: Vec<String>,
 }
 
 impl<'a> Default for ExecContext<'a> {
@@ -135,7 +172,13 @@ impl<'a> Default for ExecContext<'a> {
         ExecContext {
             resolve: |_| None,
             stream: None,
-            interrupt: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_reentrant_depth: DEFAULT_MAX_REENTRANT_DEPTH,
+            fuel: None,
+            max_heap: None,
+            deadline: None,
+            observers: Vec::new(),
+            args: Vec::new(),
         }
     }
 }
@@ -143,6 +186,8 @@ impl<'a> Default for ExecContext<'a> {
 enum WriteOption<'a> {
     DYN(&'a mut dyn io::Write),
     DEFAULT(io::Stdout),
+    /// Swapped in while code whose output was already written runs again
+    Sink(io::Sink),
 }
 
 impl<'a> io::Write for WriteOption<'a> {
@@ -150,6 +195,7 @@ impl<'a> io::Write for WriteOption<'a> {
         match self {
             WriteOption::DYN(write) => write.write(buf),
             WriteOption::DEFAULT(stdout) => stdout.write(buf),
+            WriteOption::Sink(sink) => sink.write(buf),
         }
     }
 
@@ -157,6 +203,7 @@ impl<'a> io::Write for WriteOption<'a> {
         match self {
             WriteOption::DYN(write) => write.flush(),
             WriteOption::DEFAULT(stdout) => stdout.flush(),
+            WriteOption::Sink(sink) => sink.flush(),
         }
     }
 }
@@ -174,9 +221,10 @@ fn process_bcf(
     path: Vec<String>,
     resolved_imports: &Vec<(Vec<String>, ObjectPtr)>,
     gc: &mut ManagedPool,
+    strings: &mut InternTable,
 ) -> (StackFrame, (Vec<String>, ObjectPtr)) {
     let ByteCodeFile { base_func, imports } = bcf;
-    let rfunc = base_func.resolve(resolved_imports, imports, gc);
+    let rfunc = base_func.resolve(resolved_imports, imports, gc, strings);
     let bfunc = rfunc.bind(Vec::new(), gc);
     let (current_frame, import_object) = StackFrame::from_file(bfunc, gc);
     (current_frame, (path, import_object))
@@ -184,15 +232,87 @@ fn process_bcf(
 
 pub struct ExecutionState<'a> {
     imports: Vec<(Vec<String>, ObjectPtr)>,
+    /// The base function of each module in `imports`
+    module_functions: Vec<&'static ResolvedFunction>,
     execution_stack: Vec<StackFrame>,
     current_frame: StackFrame,
     resolve_stack: Vec<(Vec<String>, ByteCodeFile)>,
+    /// Kept so modules imported after startup, like by input to a repl, can be found
+    resolve: fn(Vec<String>) -> Option<ByteCodeFile>,
     gc: ManagedPool,
+    /// Every string literal which has been loaded, so each is only allocated once
+    strings: InternTable,
     builtins: HashMap<&'static str, Value>,
     builtin_data: AnyMap,
-    registry: Vec<NativeFn<'a>>,
+    registry: Registry<'a>,
     stream: WriteOption<'a>,
-    interrupt: Option<&'a mut dyn FnMut(&mut ExecutionState<'a>)>,
+    max_call_depth: usize,
+    max_reentrant_depth: usize,
+    /// How many `run_frame` calls are currently executing
+    reentrant_depth: usize,
+    /// Frames belonging to the `execute` calls that `run_frame` was called from
+    call_depth_base: usize,
+    /// An error raised by a native function, thrown once it returns
+    pending_error: Option<Value>,
+    fuel: Option<u64>,
+    max_heap: Option<usize>,
+    deadline: Option<Instant>,
+    until_deadline_check: u32,
+    /// Once a limit is hit execution can't continue, even after returning from a native function
+    exceeded: Option<Limit>,
+    /// Set by the exit builtin, execution stops like it does for an exceeded limit
+    exit_code: Option<i64>,
+    /// Instructions left in the slice given to `execute_for`
+    slice: Option<u64>,
+    /// Set from other threads to raise an InterruptedError
+    interrupted: Arc<AtomicBool>,
+    inputs: InputLog,
+    observers: Vec<&'a mut dyn Observer<'a>>,
+    /// The heap's size when observers were last told how it changed
+    observed_heap: usize,
+    /// Kept so the `args` builtin can be made again when the state is rewound
+    args: Vec<String>,
+}
+
+// Everything a state can reach from outside the heap. Interned strings are roots as well as
+// being held by the functions using them, so a string isn't freed while the table hands it out.
+impl<'a> MarkTrace for ExecutionState<'a> {
+    fn mark_trace(&self) {
+        self.imports
+            .iter()
+            .for_each(|(_, object)| object.mark_trace());
+        self.module_functions
+            .iter()
+            .for_each(|function| function.mark_trace());
+        self.execution_stack
+            .iter()
+            .for_each(|frame| frame.mark_trace());
+        self.current_frame.mark_trace();
+        self.strings.values().for_each(|string| string.mark_trace());
+        self.builtins.values().for_each(|value| value.mark_trace());
+        if let Some(error) = &self.pending_error {
+            error.mark_trace();
+        }
+    }
+}
+
+/// The results of native functions which can differ between runs, recorded so that a state
+/// rewound to a snapshot sees the same results when it runs again
+#[derive(Default)]
+struct InputLog {
+    is_recording: bool,
+    inputs: Vec<Input>,
+    /// The next input to give back, inputs from here on are replayed instead of made again
+    position: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Input {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
 }
 
 impl<'a> Debug for ExecutionState<'a> {
@@ -201,11 +321,10 @@ impl<'a> Debug for ExecutionState<'a> {
             .current_frame
             .bfunc
             .target
-            .as_ref()
             .code
             .get(self.current_frame.index);
         if let Some(current_op) = current_op {
-            current_op.0.format_opcode(
+            current_op.format_opcode(
                 self.current_frame.index,
                 f,
                 &self.current_frame.bfunc.target.as_ref(),
@@ -216,19 +335,202 @@ impl<'a> Debug for ExecutionState<'a> {
     }
 }
 
-pub fn startup(
-    main: ByteCodeFile,
-    main_path: PathBuf,
-    ctx: ExecContext<'_>,
-) -> RefCell<ExecutionState> {
+impl<'a> ExecutionState<'a> {
+    /// How many frames deep the current frame is, including the current frame
+    pub fn call_depth(&self) -> usize {
+        self.call_depth_base + self.execution_stack.len() + 1
+    }
+
+    /// Makes native code raise an error in the calling pusl code once it returns
+    pub fn raise(&mut self, error: Value) {
+        self.pending_error = Some(error);
+    }
+
+    /// Setting this to true from any thread raises an InterruptedError in the running script
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    /// Instructions left before the fuel limit is reached
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// The code the script called exit with, if it has
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+
+    /// Frees everything on the heap which the state can't reach, returning how many bytes were
+    /// freed. Values held outside the state, like by a native function partway through
+    /// running, aren't roots, so this does nothing while native functions are running pusl code.
+    pub fn collect_garbage(&mut self) -> usize {
+        if self.reentrant_depth > 0 {
+            return 0;
+        }
+        self.notify_allocated();
+        let mut gc = mem::take(&mut self.gc);
+        let freed = gc.collect_from(&*self);
+        self.gc = gc;
+        self.observed_heap = self.gc.size();
+        self.notify(|observer, state| observer.collected(state, freed));
+        freed
+    }
+
+    /// Tells observers how much the heap grew since they were last told
+    fn notify_allocated(&mut self) {
+        let (size, observed) = (self.gc.size(), self.observed_heap);
+        if size > observed {
+            self.notify(|observer, state| observer.allocated(state, size - observed));
+        }
+        self.observed_heap = size;
+    }
+
+    /// Uses up one instruction's worth of the limits, None if execution can continue
+    fn check_limits(&mut self) -> Option<Limit> {
+        if self.exceeded.is_some() {
+            return self.exceeded;
+        }
+        let exceeded = if self.fuel == Some(0) {
+            Some(Limit::Fuel)
+        } else if self.max_heap.is_some_and(|max| self.over_heap(max)) {
+            Some(Limit::Heap)
+        } else if self.deadline.is_some() && self.until_deadline_check == 0 {
+            self.until_deadline_check = DEADLINE_CHECK_INTERVAL;
+            self.deadline
+                .filter(|&deadline| Instant::now() >= deadline)
+                .map(|_| Limit::Deadline)
+        } else {
+            None
+        };
+        if exceeded.is_none() {
+            if let Some(fuel) = &mut self.fuel {
+                *fuel -= 1;
+            }
+            self.until_deadline_check = self.until_deadline_check.saturating_sub(1);
+        }
+        self.exceeded = exceeded;
+        exceeded
+    }
+
+    /// Whether the heap is bigger than `max` even after collecting what can't be reached. While
+    /// native functions are running pusl code nothing can be collected, so everything allocated
+    /// counts until they return
+    fn over_heap(&mut self, max: usize) -> bool {
+        if self.gc.size() <= max {
+            return false;
+        }
+        self.collect_garbage();
+        self.gc.size() > max
+    }
+
+    fn stack_overflow_error(&mut self, message: String) -> Value {
+        builtins::new_error(self, "StackOverflowError", message)
+    }
+
+    /// Calls `event` on each observer in turn
+    fn notify(&mut self, mut event: impl FnMut(&mut dyn Observer<'a>, &ExecutionState<'a>)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in observers.iter_mut() {
+            event(&mut **observer, self);
+        }
+        self.observers = observers;
+    }
+}
+
+/// The builtins with the native functions they use, which every state starts with
+fn new_builtins<'a>(
+    gc: &mut ManagedPool,
+    args: &[String],
+) -> (HashMap<&'static str, Value>, AnyMap, Registry<'a>) {
     let mut registry = Vec::new();
-    let (builtins, builtin_data) = builtins::get_builtins(&mut registry);
+    let (mut builtins, builtin_data) = builtins::get_builtins(&mut registry);
+    builtins::register_errors(&mut builtins, gc);
+    builtins::register_args(&mut builtins, &builtin_data, gc, args);
+    (builtins, builtin_data, registry)
+}
+
+/// Everything in a state besides what comes from its `ExecContext`
+struct StateParts<'a> {
+    imports: Vec<(Vec<String>, ObjectPtr)>,
+    module_functions: Vec<&'static ResolvedFunction>,
+    execution_stack: Vec<StackFrame>,
+    current_frame: StackFrame,
+    resolve_stack: Vec<(Vec<String>, ByteCodeFile)>,
+    gc: ManagedPool,
+    strings: InternTable,
+    builtins: HashMap<&'static str, Value>,
+    builtin_data: AnyMap,
+    registry: Registry<'a>,
+}
 
+fn assemble_state<'a>(parts: StateParts<'a>, ctx: ExecContext<'a>) -> ExecutionState<'a> {
+    let StateParts {
+        imports,
+        module_functions,
+        execution_stack,
+        current_frame,
+        resolve_stack,
+        gc,
+        strings,
+        builtins,
+        builtin_data,
+        registry,
+    } = parts;
     let ExecContext {
         resolve,
         stream,
-        interrupt,
+        max_call_depth,
+        max_reentrant_depth,
+        fuel,
+        max_heap,
+        deadline,
+        observers,
+        args,
     } = ctx;
+    let observed_heap = gc.size();
+    ExecutionState {
+        imports,
+        module_functions,
+        execution_stack,
+        current_frame,
+        resolve_stack,
+        resolve,
+        gc,
+        strings,
+        builtins,
+        builtin_data,
+        registry,
+        stream: stream.into(),
+        max_call_depth,
+        max_reentrant_depth,
+        reentrant_depth: 0,
+        call_depth_base: 0,
+        pending_error: None,
+        fuel,
+        max_heap,
+        deadline,
+        until_deadline_check: 0,
+        exceeded: None,
+        exit_code: None,
+        slice: None,
+        interrupted: Arc::new(AtomicBool::new(false)),
+        inputs: InputLog::default(),
+        observers,
+        observed_heap,
+        args,
+    }
+}
+
+pub fn startup(
+    main: ByteCodeFile,
+    main_path: PathBuf,
+    ctx: ExecContext<'_>,
+) -> RefCell<ExecutionState> {
+    let resolve = ctx.resolve;
     let mut resolved_imports = Vec::<(Vec<String>, ObjectPtr)>::new();
     // TODO: This shouldn't use a path buf at this point
     let mut resolve_stack = vec![(
@@ -260,27 +562,29 @@ pub fn startup(
 
     //TODO: Can we remove this refcell now?
     let mut gc = ManagedPool::new();
+    let mut strings = InternTable::new();
+    let (builtins, builtin_data, registry) = new_builtins(&mut gc, &ctx.args);
 
     let (main_path, top) = resolve_stack.pop().unwrap();
-    let (current_frame, resolution) = process_bcf(top, main_path, &resolved_imports, &mut gc);
+    let (current_frame, resolution) =
+        process_bcf(top, main_path, &resolved_imports, &mut gc, &mut strings);
     resolved_imports.push(resolution);
+    let module_functions = vec![current_frame.bfunc.target];
 
-    let state = ExecutionState {
+    let parts = StateParts {
         imports: resolved_imports,
+        module_functions,
         execution_stack: Vec::new(),
         current_frame,
         resolve_stack,
         gc,
+        strings,
         builtins,
         builtin_data,
         registry,
-        stream: stream.into(),
-        interrupt,
     };
 
-    let rstate = RefCell::new(state);
-
-    rstate
+    RefCell::new(assemble_state(parts, ctx))
 }
 
 #[derive(Debug)]
@@ -288,50 +592,90 @@ pub enum ExecuteReturn {
     Return(Value),
     Yield(Value),
     Error(Value),
+    /// Execution stopped before the next instruction, it can't be caught by the script
+    LimitExceeded(Limit),
+    /// The script called exit with this code
+    Exit(i64),
+    /// The slice given to `execute_for` ran out, executing again picks up where this left off
+    Suspended,
 }
 
-pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
-    let pusl_trace = env::var("PUSL_TRACE").is_ok();
-    let pusl_trace_var = env::var("PUSL_TRACE_VAR").is_ok();
+/// The resource limits from `ExecContext`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Fuel,
+    Heap,
+    Deadline,
+}
 
+pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
     loop {
-        let native_fn_call: (NativeFn, Vec<Value>, Option<Value>);
+        let native_fn_call: (&'static str, NativeFn, Vec<Value>, Option<Value>);
         loop {
             let mut state = st.borrow_mut();
-            if let Some(interrupt_fn) = state.interrupt.take() {
-                interrupt_fn(&mut state);
-                state.interrupt = Some(interrupt_fn);
+            if let Some(code) = state.exit_code {
+                return ExecuteReturn::Exit(code);
+            }
+            // Native functions calling back into pusl code can't be suspended part way through,
+            // so the slice can only end in the outermost execute
+            if state.slice == Some(0) && state.reentrant_depth == 0 {
+                return ExecuteReturn::Suspended;
+            }
+            if let Some(slice) = &mut state.slice {
+                *slice = slice.saturating_sub(1);
+            }
+            if let Some(limit) = state.check_limits() {
+                return ExecuteReturn::LimitExceeded(limit);
             }
             {
                 let current_idx = state.current_frame.index;
 
-                if pusl_trace {
-                    println!("{:?}", state);
-                }
-                if pusl_trace_var {
-                    println!("{:?}", &state.current_frame.op_stack);
-                }
-
                 let current_op = if let Some(op) = state.current_frame.get_code() {
                     op
                 } else {
+                    state.notify(|observer, state| observer.exit(state, &Value::Null));
                     if let Some(mut parent_frame) = state.execution_stack.pop() {
                         parent_frame.op_stack.push(Value::Null);
                         state.current_frame = parent_frame;
                         continue;
                     } else if let Some((path, parent_frame)) = state.resolve_stack.pop() {
                         let (frame, resolution) = {
-                            let ExecutionState { imports, gc, .. } = &mut *state;
-                            process_bcf(parent_frame, path, imports, gc)
+                            let ExecutionState {
+                                imports,
+                                gc,
+                                strings,
+                                ..
+                            } = &mut *state;
+                            process_bcf(parent_frame, path, imports, gc, strings)
                         };
+                        state.module_functions.push(frame.bfunc.target);
                         state.current_frame = frame;
                         state.imports.push(resolution);
+                        state.notify(|observer, state| observer.enter(state));
                         continue;
                     } else {
                         return Return(Value::Null);
                     }
                 };
 
+                if !state.observers.is_empty() {
+                    state.notify_allocated();
+                    state.notify(|observer, state| observer.instruction(state, current_idx));
+                }
+
+                if state.interrupted.load(atomic::Ordering::Relaxed) {
+                    state.interrupted.store(false, atomic::Ordering::Relaxed);
+                    let error = builtins::new_error(
+                        &mut state,
+                        "InterruptedError",
+                        "Interrupted".to_string(),
+                    );
+                    if let Err(error) = unwind_stack(&mut state, current_idx, error) {
+                        return ExecuteReturn::Error(error);
+                    }
+                    continue;
+                }
+
                 match current_op {
                     OpCode::Modulus => {
                         let rhs = state.current_frame.op_stack.pop().unwrap();
@@ -339,13 +683,7 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         state.current_frame.op_stack.push(modulus(lhs, rhs));
                     }
                     OpCode::Literal(pool_index) => {
-                        let literal = state
-                            .current_frame
-                            .bfunc
-                            .target
-                            .as_ref()
-                            .get_literal(pool_index);
-                        let value = literal.into_value(&mut state.gc);
+                        let value = state.current_frame.bfunc.target.literals[pool_index].clone();
                         state.current_frame.op_stack.push(value);
                     }
                     OpCode::PushThis => {
@@ -373,77 +711,37 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                             .get_reference(pool_index);
                         let value = state
                             .current_frame
-                            .variables
-                            .iter_mut()
-                            .rev()
-                            .filter_map(|var_stack| {
-                                if let VariableStack::Variable(var) = var_stack {
-                                    Some(var)
-                                } else {
-                                    None
-                                }
-                            })
-                            .find(|var| var.name == reference_name)
-                            .map(|var| var.value.clone())
-                            .or_else(|| {
-                                state
-                                    .current_frame
-                                    .bfunc
-                                    .target
-                                    .as_ref()
-                                    .binds
-                                    .iter()
-                                    .position(|name| name == &reference_name)
-                                    .map(|index| {
-                                        state.current_frame.bfunc.bound_values[index].clone()
-                                    })
-                            })
-                            .or_else(|| {
-                                state
-                                    .current_frame
-                                    .bfunc
-                                    .target
-                                    .imports
-                                    .iter()
-                                    .find(|&(name, _)| name.as_str() == reference_name)
-                                    .map(|(_, obj)| Value::Object(obj.clone()))
-                            })
+                            .bfunc
+                            .target
+                            .imports
+                            .iter()
+                            .find(|&(name, _)| name.as_str() == reference_name)
+                            .map(|(_, obj)| Value::Object(obj.clone()))
                             .or_else(|| state.builtins.get(reference_name.as_str()).cloned())
                             .unwrap_or_else(|| {
                                 panic!("Undeclared Variable \"{}\"", reference_name.as_str())
                             });
                         state.current_frame.op_stack.push(value);
                     }
+                    OpCode::PushLocal(slot) => {
+                        let value = state.current_frame.locals[slot].clone();
+                        state.current_frame.op_stack.push(value);
+                    }
+                    OpCode::PushBound(bind_index) => {
+                        let value = state.current_frame.bfunc.bound_values[bind_index].clone();
+                        state.current_frame.op_stack.push(value);
+                    }
                     OpCode::PushFunction(pool_index) => {
                         let rfunc = state.current_frame.bfunc.target.get_function(pool_index);
-                        let bound_values = rfunc
-                            .as_ref()
-                            .binds
-                            .iter()
-                            .map(|name| {
-                                state
-                                    .current_frame
-                                    .variables
-                                    .iter()
-                                    .rev()
-                                    .filter_map(|var_stack| {
-                                        if let VariableStack::Variable(var) = var_stack {
-                                            Some(var)
-                                        } else {
-                                            None
-                                        }
-                                    })
-                                    .find(|var| &var.name == name)
-                                    .map(|var| var.value.clone())
-                                    .unwrap_or_else(|| panic!("Undeclared Variable \"{}\"", name))
-                            })
-                            .collect();
+                        let num_binds = rfunc.as_ref().binds.len();
+                        let split_off_index = state.current_frame.op_stack.len() - num_binds;
+                        let bound_values = state.current_frame.op_stack.split_off(split_off_index);
 
                         let bfunc = rfunc.bind(bound_values, &mut state.gc);
 
                         state.current_frame.op_stack.push(Value::pusl_fn(bfunc));
                     }
-                    OpCode::FunctionCall(num_args) => {
+                    OpCode::FunctionCall(num_args) | OpCode::TailCall(num_args) => {
                         assert!(state.current_frame.op_stack.len() >= num_args);
                         let split_off_index = state.current_frame.op_stack.len() - num_args;
                         let args = state.current_frame.op_stack.split_off(split_off_index);
@@ -451,37 +749,44 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         match function {
                             Value::Function((FunctionTarget::Pusl(reference), this)) => {
                                 assert_eq!(reference.target.as_ref().args.len(), args.len());
-                                let arg_value_iter = args.into_iter();
                                 let mut new_frame = StackFrame::from_function(reference, this);
-                                for (name, value) in new_frame
-                                    .bfunc
-                                    .target
-                                    .as_ref()
-                                    .args
-                                    .iter()
-                                    .cloned()
-                                    .zip(arg_value_iter)
-                                {
-                                    new_frame
-                                        .variables
-                                        .push(VariableStack::Variable(Variable { value, name }));
+                                for (slot, value) in args.into_iter().enumerate() {
+                                    new_frame.locals[slot] = value;
                                 }
                                 if new_frame.bfunc.target.as_ref().is_generator {
                                     let result = generator::new_generator(new_frame, &mut state);
                                     state.current_frame.op_stack.push(result);
+                                } else if matches!(current_op, OpCode::TailCall(_))
+                                    && !state.current_frame.bfunc.target.as_ref().is_generator
+                                {
+                                    // The Return after this would only pass the value along,
+                                    // so the callee can return straight to our caller
+                                    state.current_frame = new_frame;
+                                    state.notify(|observer, state| observer.enter(state));
+                                } else if state.call_depth() >= state.max_call_depth {
+                                    let message = format!(
+                                        "Maximum call depth of {} exceeded",
+                                        state.max_call_depth
+                                    );
+                                    let error = state.stack_overflow_error(message);
+                                    if let Err(error) = unwind_stack(&mut state, current_idx, error)
+                                    {
+                                        return ExecuteReturn::Error(error);
+                                    }
                                 } else {
                                     let old_frame =
                                         std::mem::replace(&mut state.current_frame, new_frame);
                                     state.execution_stack.push(old_frame);
+                                    state.notify(|observer, state| observer.enter(state));
                                 }
                             }
                             Value::Function((FunctionTarget::Native(handle), this)) => {
                                 let this = this.map(|obj| Value::Object(obj));
-                                let ptr = *state
+                                let (name, ptr) = *state
                                     .registry
                                     .get(handle)
                                     .expect("Out of bounds function handle");
-                                native_fn_call = (ptr, args, this);
+                                native_fn_call = (name, ptr, args, this);
                                 break;
                             }
                             _ => panic!("Value must be a function to call"),
@@ -489,15 +794,22 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                     }
                     OpCode::FieldAccess(name_index) => {
                         let value = state.current_frame.op_stack.pop().unwrap();
-                        let name = state
-                            .current_frame
-                            .bfunc
-                            .target
-                            .as_ref()
-                            .get_reference(name_index);
+                        let target = state.current_frame.bfunc.target;
+                        let name = target.as_ref().references[name_index].as_str();
                         let value = match value {
                             Value::Object(object) => {
-                                let value = object.deref().borrow().get_field(name.as_str());
+                                let value = {
+                                    let borrow = object.deref().borrow();
+                                    match borrow.get_native_data().downcast_ref::<PuslObject>() {
+                                        Some(pusl_obj) => {
+                                            // The instruction index has already been advanced
+                                            let cache =
+                                                &target.field_caches[state.current_frame.index - 1];
+                                            pusl_obj.get_field_cached(name, cache)
+                                        }
+                                        None => borrow.get_field(name),
+                                    }
+                                };
                                 match value {
                                     Value::Function((target, None)) => {
                                         Value::Function((target, Some(object)))
@@ -562,30 +874,27 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         let lhs = state.current_frame.op_stack.pop().unwrap();
                         state.current_frame.op_stack.push(logic(lhs, rhs, false));
                     }
-                    OpCode::ScopeUp => {
-                        state
-                            .current_frame
-                            .variables
-                            .push(VariableStack::ScopeBoundary);
-                    }
-                    OpCode::ScopeDown => {
-                        while let Some(VariableStack::Variable(_)) =
-                            state.current_frame.variables.pop()
-                        {}
-                    }
                     OpCode::Return => {
                         let return_value = state.current_frame.op_stack.pop().unwrap();
+                        state.notify(|observer, state| observer.exit(state, &return_value));
                         if let Some(mut parent_frame) = state.execution_stack.pop() {
                             parent_frame.op_stack.push(return_value);
                             state.current_frame = parent_frame;
                             continue;
                         } else if let Some((path, parent_frame)) = state.resolve_stack.pop() {
                             let (frame, resolution) = {
-                                let ExecutionState { imports, gc, .. } = &mut *state;
-                                process_bcf(parent_frame, path, imports, gc)
+                                let ExecutionState {
+                                    imports,
+                                    gc,
+                                    strings,
+                                    ..
+                                } = &mut *state;
+                                process_bcf(parent_frame, path, imports, gc, strings)
                             };
+                            state.module_functions.push(frame.bfunc.target);
                             state.current_frame = frame;
                             state.imports.push(resolution);
+                            state.notify(|observer, state| observer.enter(state));
                             continue;
                         } else {
                             return Return(return_value);
@@ -602,17 +911,26 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         if condition {
                             state.current_frame.index = jump_index;
                         }
+                        let function = state.current_frame.bfunc.target;
+                        let way = if condition { 0 } else { 1 };
+                        state.notify(|observer, state| {
+                            observer.branch(state, function, current_idx, way)
+                        });
                     }
                     OpCode::ComparisonJump(greater_index, less_index, equal_index) => {
                         let rhs = state.current_frame.op_stack.pop().unwrap();
                         let lhs = state.current_frame.op_stack.pop().unwrap();
                         let ordering = compare_numerical(lhs, rhs);
-                        let index = match ordering {
-                            Ordering::Less => less_index,
-                            Ordering::Equal => equal_index,
-                            Ordering::Greater => greater_index,
+                        let (index, way) = match ordering {
+                            Ordering::Greater => (greater_index, 0),
+                            Ordering::Less => (less_index, 1),
+                            Ordering::Equal => (equal_index, 2),
                         };
                         state.current_frame.index = index;
+                        let function = state.current_frame.bfunc.target;
+                        state.notify(|observer, state| {
+                            observer.branch(state, function, current_idx, way)
+                        });
                     }
                     OpCode::Jump(jump_index) => {
                         state.current_frame.index = jump_index;
@@ -629,45 +947,22 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         let value = (*state.current_frame.op_stack.last().unwrap()).clone();
                         state.current_frame.op_stack.push(value);
                     }
-                    OpCode::AssignReference(pool_index, is_let) => {
+                    OpCode::AssignReference(pool_index, _) => {
+                        // Locals are assigned with AssignLocal, anything else can't be assigned to
                         let reference_name = state
                             .current_frame
                             .bfunc
                             .target
                             .as_ref()
                             .get_reference(pool_index);
+                        panic!(
+                            "Cannot assign to non-existing variable {} without let",
+                            reference_name
+                        );
+                    }
+                    OpCode::AssignLocal(slot) => {
                         let value = state.current_frame.op_stack.pop().unwrap();
-                        if is_let {
-                            state
-                                .current_frame
-                                .variables
-                                .push(VariableStack::Variable(Variable {
-                                    value,
-                                    name: reference_name,
-                                }))
-                        } else {
-                            let variable_opt = state
-                                .current_frame
-                                .variables
-                                .iter_mut()
-                                .rev()
-                                .filter_map(|var_stack| {
-                                    if let VariableStack::Variable(var) = var_stack {
-                                        Some(var)
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .find(|var| var.name == reference_name);
-                            let variable = match variable_opt {
-                                Some(variable) => variable,
-                                None => panic!(
-                                    "Cannot assign to non-existing variable {} without let",
-                                    reference_name
-                                ),
-                            };
-                            variable.value = value;
-                        }
+                        state.current_frame.locals[slot] = value;
                     }
                     OpCode::AssignField(pool_index, is_let) => {
                         let reference_name = state
@@ -685,6 +980,7 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                         (*object)
                             .borrow_mut()
                             .assign_field(reference_name.as_str(), value, is_let);
+                        state.gc.resize(&object);
                     }
                     OpCode::DuplicateMany(n) => {
                         let len = state.current_frame.op_stack.len();
@@ -719,6 +1015,7 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                     OpCode::Yield => {
                         assert!(state.current_frame.bfunc.target.as_ref().is_generator);
                         let result = state.current_frame.op_stack.pop().unwrap();
+                        state.notify(|observer, state| observer.yielded(state, &result));
                         return Yield(result);
                     }
                     OpCode::Yeet => {
@@ -731,32 +1028,193 @@ pub fn execute<'a>(st: ExecStateRef<'a>) -> ExecuteReturn {
                 }
             }
         }
-        let (ptr, args, this) = native_fn_call;
+        let (name, ptr, args, this) = native_fn_call;
+        st.borrow_mut()
+            .notify(|observer, state| observer.native_enter(state, name));
         let result = ptr(args, this, st);
-        st.borrow_mut().current_frame.op_stack.push(result);
+        let mut state = st.borrow_mut();
+        state.notify(|observer, state| observer.native_exit(state, name));
+        if let Some(error) = state.pending_error.take() {
+            // The instruction index has already been advanced past the call
+            let current_idx = state.current_frame.index - 1;
+            if let Err(error) = unwind_stack(&mut state, current_idx, error) {
+                return ExecuteReturn::Error(error);
+            }
+        } else {
+            state.current_frame.op_stack.push(result);
+        }
     }
 }
 
-fn unwind_stack(
-    state: &mut ExecutionState,
-    mut current_idx: usize,
-    error: Value,
-) -> Result<(), Value> {
-    loop {
-        for catch in &state.current_frame.bfunc.target.as_ref().catches {
-            if catch.begin <= current_idx && catch.filter > current_idx {
-                state.current_frame.index = catch.filter;
-                state.current_frame.op_stack.push(error);
-                return Ok(());
-            }
+/// Executes at most about `max_instructions` instructions before returning `Suspended`, so many
+/// scripts can take turns on one thread
+pub fn execute_for<'a>(st: ExecStateRef<'a>, max_instructions: u64) -> ExecuteReturn {
+    st.borrow_mut().slice = Some(max_instructions);
+    let result = execute(st);
+    st.borrow_mut().slice = None;
+    result
+}
+
+/// What `run_frame` swapped out of the state, put back when it's dropped so a panic while
+/// executing leaves the state as it was before the frame ran
+struct Reentry<'f, 'a> {
+    st: ExecStateRef<'a>,
+    frame: &'f mut StackFrame,
+    old_stack: Vec<StackFrame>,
+    old_base: usize,
+}
+
+impl<'f, 'a> Drop for Reentry<'f, 'a> {
+    fn drop(&mut self) {
+        // Borrows taken while executing are released before this runs, even when unwinding
+        let mut stb = self.st.borrow_mut();
+        std::mem::swap(self.frame, &mut stb.current_frame);
+        std::mem::swap(&mut self.old_stack, &mut stb.execution_stack);
+        stb.call_depth_base = self.old_base;
+        stb.reentrant_depth -= 1;
+    }
+}
+
+/// Runs a frame to completion on top of whatever is currently executing, for native functions
+/// which need to call back into pusl code
+pub fn run_frame<'a>(frame: &mut StackFrame, st: ExecStateRef<'a>) -> ExecuteReturn {
+    let reentry = {
+        let mut stb = st.borrow_mut();
+        let message = if stb.call_depth() >= stb.max_call_depth {
+            Some(format!(
+                "Maximum call depth of {} exceeded",
+                stb.max_call_depth
+            ))
+        } else if stb.reentrant_depth >= stb.max_reentrant_depth {
+            Some(format!(
+                "Maximum reentrant call depth of {} exceeded",
+                stb.max_reentrant_depth
+            ))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return ExecuteReturn::Error(stb.stack_overflow_error(message));
+        }
+        stb.reentrant_depth += 1;
+        let old_base = stb.call_depth_base;
+        stb.call_depth_base = stb.call_depth();
+        std::mem::swap(frame, &mut stb.current_frame);
+        let old_stack = std::mem::take(&mut stb.execution_stack);
+        // Only generators are run again after having started
+        if stb.current_frame.index == 0 {
+            stb.notify(|observer, state| observer.enter(state));
+        } else {
+            stb.notify(|observer, state| observer.resumed(state));
+        }
+        Reentry {
+            st,
+            frame,
+            old_stack,
+            old_base,
         }
-        if let Some(new_frame) = state.execution_stack.pop() {
-            state.current_frame = new_frame;
-            current_idx = state.current_frame.index;
+    };
+    let ret_val = execute(st);
+    drop(reentry);
+    ret_val
+}
+
+/// Like `run_frame`, for running code typed in by someone. Some mistakes, like calling null,
+/// panic in the VM rather than raising an error, so their message is given back instead.
+pub fn run_frame_caught<'a>(
+    frame: &mut StackFrame,
+    st: ExecStateRef<'a>,
+) -> Result<ExecuteReturn, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| run_frame(frame, st))).map_err(|payload| {
+        let message = check::panic_message(&*payload);
+        if message.is_empty() {
+            "Execution stopped on an internal error".to_string()
         } else {
-            return Err(error);
+            message
+        }
+    })
+}
+
+/// Makes a result which can differ between runs, like the current time. While inputs are
+/// recorded for a debugger, a state which was rewound gets back the result made the first time.
+/// `make` has to give null, a boolean, a number or a string.
+pub fn nondeterministic<'a>(
+    st: ExecStateRef<'a>,
+    make: impl FnOnce(ExecStateRef<'a>) -> Value,
+) -> Value {
+    let replayed = {
+        let mut state = st.borrow_mut();
+        let log = &mut state.inputs;
+        let input = log
+            .inputs
+            .get(log.position)
+            .filter(|_| log.is_recording)
+            .cloned();
+        if input.is_some() {
+            log.position += 1;
         }
+        input
+    };
+    if let Some(input) = replayed {
+        return match input {
+            Input::Null => Value::Null,
+            Input::Boolean(boolean) => Value::Boolean(boolean),
+            Input::Integer(integer) => Value::Integer(integer),
+            Input::Float(float) => Value::Float(float),
+            Input::String(string) => Value::String(st.borrow_mut().gc.place_in_heap(string)),
+        };
+    }
+    let value = make(st);
+    let mut state = st.borrow_mut();
+    if state.inputs.is_recording {
+        let input = match &value {
+            Value::Null => Input::Null,
+            Value::Boolean(boolean) => Input::Boolean(*boolean),
+            Value::Integer(integer) => Input::Integer(*integer),
+            Value::Float(float) => Input::Float(*float),
+            Value::String(string) => Input::String(String::clone(string)),
+            _ => panic!("Only null, booleans, numbers and strings can be recorded"),
+        };
+        state.inputs.inputs.push(input);
+        state.inputs.position += 1;
+    }
+    value
+}
+
+fn find_catch(frame: &StackFrame, current_idx: usize) -> Option<usize> {
+    frame
+        .bfunc
+        .target
+        .catches
+        .iter()
+        .find(|catch| catch.begin <= current_idx && catch.filter > current_idx)
+        .map(|catch| catch.filter)
+}
+
+/// Jumps to the innermost catch for the error. If nothing catches it the stack is left as it
+/// was so it can be inspected.
+fn unwind_stack(state: &mut ExecutionState, current_idx: usize, error: Value) -> Result<(), Value> {
+    state.notify(|observer, state| observer.yeet(state, &error));
+    let mut filter = find_catch(&state.current_frame, current_idx);
+    let mut depth = 0;
+    for frame in state.execution_stack.iter().rev() {
+        if filter.is_some() {
+            break;
+        }
+        filter = find_catch(frame, frame.index);
+        depth += 1;
+    }
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Err(error),
+    };
+    for _ in 0..depth {
+        state.current_frame = state.execution_stack.pop().unwrap();
     }
+    state.current_frame.index = filter;
+    state.current_frame.op_stack.push(error);
+    state.notify(|observer, state| observer.unwind(state, depth));
+    Ok(())
 }
 
 fn logic(lhs: Value, rhs: Value, is_and: bool) -> Value {
@@ -856,7 +1314,8 @@ fn compare(lhs: Value, rhs: Value, compare: Compare) -> Value {
             (Value::Float(lhs), Value::Integer(rhs)) => lhs == rhs as f64,
             (Value::Float(lhs), Value::Float(rhs)) => lhs == rhs,
             // Strings
-            (Value::String(lhs), Value::String(rhs)) => *lhs == *rhs,
+            // Interned strings are usually the same allocation
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs || *lhs == *rhs,
             // Functions
             (Value::Function((lhs_target, lhs_self)), Value::Function((rhs_target, rhs_self))) => {
                 lhs_self == rhs_self
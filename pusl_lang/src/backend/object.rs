@@ -1,3 +1,4 @@
+use super::shape::{bump_prototype_epoch, FieldLocation, InlineCache, Shape};
 use super::{BoundFunction, ExecStateRef, StackFrame};
 use bitflags::_core::cell::RefCell;
 use bitflags::_core::fmt::Formatter;
@@ -5,14 +6,20 @@ use fmt::Display;
 use std::any::Any;
 
 use garbage::{Gc, MarkTrace};
+use std::cell::{Cell, OnceCell};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use std::fmt::Debug;
+use std::rc::Rc;
 
 pub type ObjectPtr = Gc<RefCell<dyn Object>>;
 pub type StringPtr = Gc<String>;
+/// Strings placed in the heap once, keyed by their contents
+pub type InternTable = HashMap<String, StringPtr>;
 pub type NativeFn<'a> = fn(Vec<Value>, Option<Value>, ExecStateRef<'a>) -> Value;
+/// Every native function with a name which stays the same between runs, indexed by handle
+pub type Registry<'a> = Vec<(&'static str, NativeFn<'a>)>;
 pub type FnPtr = Gc<BoundFunction>;
 pub type GeneratorFn = Gc<StackFrame>;
 pub type MethodPtr = (FunctionTarget, Option<ObjectPtr>);
@@ -151,18 +158,23 @@ impl Value {
         }
     }
 
-    pub fn native_fn<'a>(function: NativeFn<'a>, registry: &mut Vec<NativeFn<'a>>) -> Self {
+    pub fn native_fn<'a>(
+        name: &'static str,
+        function: NativeFn<'a>,
+        registry: &mut Registry<'a>,
+    ) -> Self {
         let index = registry.len();
-        registry.push(function);
+        registry.push((name, function));
         Value::Function((FunctionTarget::Native(index), None))
     }
 
     pub fn native_fn_handle<'a>(
+        name: &'static str,
         function: NativeFn<'a>,
-        registry: &mut Vec<NativeFn<'a>>,
+        registry: &mut Registry<'a>,
     ) -> NativeFnHandle {
         let index = registry.len();
-        registry.push(function);
+        registry.push((name, function));
         index
     }
 
@@ -177,8 +189,18 @@ impl Value {
 
 impl MarkTrace for Value {
     fn mark_trace(&self) {
-        if let Value::Object(object) = self {
-            object.mark_trace()
+        match self {
+            Value::String(string) => string.mark_trace(),
+            Value::Function((target, this)) => {
+                if let FunctionTarget::Pusl(function) = target {
+                    function.mark_trace();
+                }
+                if let Some(this) = this {
+                    this.mark_trace();
+                }
+            }
+            Value::Object(object) => object.mark_trace(),
+            Value::Null | Value::Boolean(_) | Value::Integer(_) | Value::Float(_) => {}
         }
     }
 }
@@ -188,11 +210,11 @@ impl MarkTrace for PuslObject {
         if let Some(super_ptr) = &self.super_ptr {
             super_ptr.mark_trace();
         }
-        self.fields.iter().for_each(|(_, v)| {
-            if let Value::Object(ptr) = v {
-                ptr.mark_trace();
-            }
-        })
+        self.values.iter().for_each(|v| v.mark_trace())
+    }
+
+    fn heap_size(&self) -> usize {
+        self.values.heap_size()
     }
 }
 
@@ -218,14 +240,23 @@ pub fn is_instance_of(obj: ObjectPtr, parent: &ObjectPtr) -> bool {
 //Todo: The debug impl really should be custom
 pub struct PuslObject {
     super_ptr: Option<ObjectPtr>,
-    fields: HashMap<String, Value>,
+    shape: Rc<Shape>,
+    /// Field values, indexed by the slots in `shape`
+    values: Vec<Value>,
+    /// The root shape of objects which have this object as their super
+    child_root: OnceCell<Rc<Shape>>,
 }
 
 impl fmt::Debug for PuslObject {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = self
+            .shape
+            .names()
+            .map(|(name, slot)| (name, &self.values[slot]))
+            .collect::<HashMap<_, _>>();
         f.debug_struct("Object")
             .field("super_ptr", &self.super_ptr)
-            .field("fields", &self.fields)
+            .field("fields", &fields)
             .finish()
     }
 }
@@ -252,39 +283,155 @@ impl PuslObject {
     pub fn new() -> RefCell<Self> {
         let object = PuslObject {
             super_ptr: None,
-            fields: HashMap::new(),
+            shape: Shape::new_root(),
+            values: Vec::new(),
+            child_root: OnceCell::new(),
         };
         RefCell::new(object)
     }
 
     pub fn new_with_parent(parent: ObjectPtr) -> RefCell<Self> {
         let object = PuslObject {
+            shape: root_shape_under(&parent),
             super_ptr: Some(parent),
-            fields: HashMap::new(),
+            values: Vec::new(),
+            child_root: OnceCell::new(),
         };
         RefCell::new(object)
     }
+
+    pub fn super_ptr(&self) -> Option<&ObjectPtr> {
+        self.super_ptr.as_ref()
+    }
+
+    /// This object's own fields in the order they were added
+    pub fn own_fields(&self) -> Vec<(&str, &Value)> {
+        let mut names = self.shape.names().collect::<Vec<_>>();
+        names.sort_by_key(|(_, slot)| *slot);
+        names
+            .into_iter()
+            .map(|(name, slot)| (name, &self.values[slot]))
+            .collect()
+    }
+
+    fn is_prototype(&self) -> bool {
+        self.child_root.get().is_some()
+    }
+
+    /// Find a field on this object or up its super chain, along with where it was found if
+    /// the location can be cached
+    fn locate_field(&self, name: &str) -> (Value, Option<Option<FieldLocation>>) {
+        if let Some(slot) = self.shape.slot(name) {
+            let location = FieldLocation { depth: 0, slot };
+            return (self.values[slot].clone(), Some(Some(location)));
+        }
+        let mut current = self.super_ptr.clone();
+        let mut depth = 1;
+        while let Some(object_ptr) = current {
+            let object = object_ptr.borrow();
+            let pusl_obj = match object.get_native_data().downcast_ref::<PuslObject>() {
+                Some(pusl_obj) => pusl_obj,
+                // Native objects don't have shapes, so there is nothing to cache
+                None => return (object.get_field(name), None),
+            };
+            if let Some(slot) = pusl_obj.shape.slot(name) {
+                let location = FieldLocation { depth, slot };
+                return (pusl_obj.values[slot].clone(), Some(Some(location)));
+            }
+            current = pusl_obj.super_ptr.clone();
+            depth += 1;
+        }
+        (Value::Null, Some(None))
+    }
+
+    fn field_at(&self, location: FieldLocation) -> Value {
+        if location.depth == 0 {
+            return self.values[location.slot].clone();
+        }
+        let mut current = self.super_ptr.clone().expect("Inline cache is stale");
+        for _ in 1..location.depth {
+            let next = current
+                .borrow()
+                .get_native_data()
+                .downcast_ref::<PuslObject>()
+                .and_then(|pusl_obj| pusl_obj.super_ptr.clone())
+                .expect("Inline cache is stale");
+            current = next;
+        }
+        let object = current.borrow();
+        let pusl_obj = object
+            .get_native_data()
+            .downcast_ref::<PuslObject>()
+            .expect("Inline cache is stale");
+        pusl_obj.values[location.slot].clone()
+    }
+
+    /// `get_field` which skips the lookup when `cache` knows where the field is for this
+    /// object's shape, and updates `cache` when it doesn't
+    pub fn get_field_cached(&self, name: &str, cache: &Cell<InlineCache>) -> Value {
+        if name == "super" {
+            return self.get_field(name);
+        }
+        match cache.get().lookup(&self.shape) {
+            Some(Some(location)) => self.field_at(location),
+            Some(None) => Value::Null,
+            None => {
+                let (value, location) = self.locate_field(name);
+                if let Some(location) = location {
+                    cache.set(InlineCache::new(&self.shape, location));
+                }
+                value
+            }
+        }
+    }
+
+    fn set_super(&mut self, super_ptr: ObjectPtr) {
+        // Moving to a new super changes the shape, replay the fields so their slots don't move
+        let mut names = self.shape.names().collect::<Vec<_>>();
+        names.sort_by_key(|(_, slot)| *slot);
+        let mut shape = root_shape_under(&super_ptr);
+        for (name, _) in names {
+            shape = shape.with_field(name);
+        }
+        self.shape = shape;
+        self.super_ptr = Some(super_ptr);
+        if self.is_prototype() {
+            bump_prototype_epoch();
+        }
+    }
+}
+
+/// The shape objects start with when `parent` is their super
+fn root_shape_under(parent: &ObjectPtr) -> Rc<Shape> {
+    let parent = match parent.try_borrow() {
+        Ok(parent) => parent,
+        // Only happens for an object being made its own super, there's nothing to share
+        Err(_) => return Shape::new_root(),
+    };
+    match parent.get_native_data().downcast_ref::<PuslObject>() {
+        Some(pusl_obj) => pusl_obj.child_root.get_or_init(Shape::new_root).clone(),
+        None => Shape::new_root(),
+    }
 }
 
 impl Object for PuslObject {
     fn assign_field(&mut self, name: &str, value: Value, is_let: bool) {
         if name == "super" {
             match value {
-                Value::Object(object_ptr) => self.super_ptr = Some(object_ptr),
+                Value::Object(object_ptr) => self.set_super(object_ptr),
                 Value::Null => panic!("Cannot Remove Super Object"),
                 _ => panic!("Super Object must be an Object"),
             }
-        } else {
-            if is_let {
-                self.fields.insert(name.to_string(), value);
-            } else {
-                let entry = self.fields.get_mut(name);
-                if let Some(old_value) = entry {
-                    *old_value = value;
-                } else {
-                    panic!("Cannot assign to non-existent field without let")
-                }
+        } else if let Some(slot) = self.shape.slot(name) {
+            self.values[slot] = value;
+        } else if is_let {
+            self.shape = self.shape.with_field(name);
+            self.values.push(value);
+            if self.is_prototype() {
+                bump_prototype_epoch();
             }
+        } else {
+            panic!("Cannot assign to non-existent field without let")
         }
     }
 
@@ -295,14 +442,7 @@ impl Object for PuslObject {
                 None => Value::Null,
             }
         } else {
-            //TODO: Bad Recursion
-            if let Some(value) = self.fields.get(name).map(|val| (*val).clone()) {
-                value
-            } else if let Some(super_ptr) = &self.super_ptr {
-                super_ptr.borrow().get_field(name)
-            } else {
-                Value::Null
-            }
+            self.locate_field(name).0
         }
     }
 
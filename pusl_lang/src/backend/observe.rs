@@ -0,0 +1,144 @@
+//! Callbacks `execute` makes while it runs, so tools watching a script like profilers, tracers
+//! and debuggers can be put on `ExecContext.observers` together without changing the VM loop.
+
+use std::io::Write;
+
+use super::debug::{describe_value, location};
+use super::linearize::ResolvedFunction;
+use super::object::Value;
+use super::ExecutionState;
+
+/// Every callback does nothing unless it's overridden. The state is as it is at the time of the
+/// event, so `state.current_frame` is the frame that was just entered, returned to or resumed.
+pub trait Observer<'a> {
+    /// Before the instruction at `index` of the current frame runs
+    fn instruction(&mut self, _state: &ExecutionState<'a>, _index: usize) {}
+
+    /// A pusl function or module was called, and its frame is now the current frame. The main
+    /// module is already running when the first instruction runs, so it isn't entered. A tail
+    /// call replaces the caller's frame, so there the caller also ends without an `exit`.
+    fn enter(&mut self, _state: &ExecutionState<'a>) {}
+
+    /// The current frame returned `value`, before the frame it returns to takes over
+    fn exit(&mut self, _state: &ExecutionState<'a>, _value: &Value) {}
+
+    /// Before a native function runs
+    fn native_enter(&mut self, _state: &ExecutionState<'a>, _name: &'static str) {}
+
+    /// After a native function has returned, including any pusl code it called back into
+    fn native_exit(&mut self, _state: &ExecutionState<'a>, _name: &'static str) {}
+
+    /// The branch instruction at `index` of `function` went the way numbered `way`. A
+    /// ConditionalJump jumps (0) or falls through (1), a ComparisonJump goes greater (0), less (1)
+    /// or equal (2).
+    fn branch(
+        &mut self,
+        _state: &ExecutionState<'a>,
+        _function: &'static ResolvedFunction,
+        _index: usize,
+        _way: usize,
+    ) {
+    }
+
+    /// An error was thrown by `yeet`, a native function or the VM, before looking for a catch
+    fn yeet(&mut self, _state: &ExecutionState<'a>, _error: &Value) {}
+
+    /// A thrown error was caught, after leaving `frames` frames which didn't catch it. The
+    /// current frame is about to run its filter.
+    fn unwind(&mut self, _state: &ExecutionState<'a>, _frames: usize) {}
+
+    /// The current frame, a generator, yielded `value`
+    fn yielded(&mut self, _state: &ExecutionState<'a>, _value: &Value) {}
+
+    /// A generator which had yielded is running again
+    fn resumed(&mut self, _state: &ExecutionState<'a>) {}
+
+    /// The heap grew by `bytes` since the last instruction, counting memory owned by lists,
+    /// strings and objects as they grow
+    fn allocated(&mut self, _state: &ExecutionState<'a>, _bytes: usize) {}
+
+    /// `ExecutionState::collect_garbage` freed `bytes`
+    fn collected(&mut self, _state: &ExecutionState<'a>, _bytes: usize) {}
+}
+
+/// Writes every instruction as it runs, along with calls, returns and errors, indented by the
+/// call depth. A caller replaced by a tail call is written as exiting before the callee enters.
+pub struct Tracer<W: Write> {
+    out: W,
+    /// Whether to write the operand stack before each instruction
+    show_stack: bool,
+    /// The call depth of the frame the last instruction or return left running
+    depth: usize,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, show_stack: bool) -> Self {
+        Tracer {
+            out,
+            show_stack,
+            depth: 0,
+        }
+    }
+
+    fn line(&mut self, state: &ExecutionState, text: std::fmt::Arguments) {
+        let indent = "  ".repeat(state.call_depth().saturating_sub(1));
+        // A trace which can't be written shouldn't stop the script
+        let _result = writeln!(self.out, "{}{}", indent, text);
+    }
+}
+
+impl<'a, W: Write> Observer<'a> for Tracer<W> {
+    fn instruction(&mut self, state: &ExecutionState<'a>, index: usize) {
+        let function = state.current_frame.bfunc.target;
+        self.depth = state.call_depth();
+        if self.show_stack {
+            let stack = format!("{:?}", state.current_frame.op_stack);
+            self.line(state, format_args!("    stack {}", stack));
+        }
+        let mut op = String::new();
+        let _result = function.code[index].format_opcode(index, &mut op, function.as_ref());
+        self.line(state, format_args!("{}", op));
+    }
+
+    fn enter(&mut self, state: &ExecutionState<'a>) {
+        // A call which doesn't go any deeper is a tail call
+        if state.call_depth() <= self.depth {
+            self.line(state, format_args!("exit by tail call"));
+        }
+        self.depth = state.call_depth();
+        if let Some(location) = location(state, 0) {
+            self.line(state, format_args!("enter {}", location));
+        }
+    }
+
+    fn exit(&mut self, state: &ExecutionState<'a>, value: &Value) {
+        let value = describe_value(state, value);
+        self.line(state, format_args!("exit with {}", value));
+        self.depth = state.call_depth() - 1;
+    }
+
+    fn native_enter(&mut self, state: &ExecutionState<'a>, name: &'static str) {
+        self.line(state, format_args!("native {}", name));
+    }
+
+    fn yeet(&mut self, state: &ExecutionState<'a>, error: &Value) {
+        let error = describe_value(state, error);
+        self.line(state, format_args!("yeet {}", error));
+    }
+
+    fn unwind(&mut self, state: &ExecutionState<'a>, frames: usize) {
+        self.line(
+            state,
+            format_args!("caught after leaving {} frames", frames),
+        );
+    }
+
+    fn yielded(&mut self, state: &ExecutionState<'a>, value: &Value) {
+        let value = describe_value(state, value);
+        self.line(state, format_args!("yield {}", value));
+    }
+
+    fn resumed(&mut self, state: &ExecutionState<'a>) {
+        self.line(state, format_args!("resume"));
+    }
+}
@@ -1,18 +1,13 @@
-use std::fmt;
+use std::{convert::TryFrom, fmt};
 
-use serde::{
-    de::Error,
-    de::Visitor,
-    ser::{self, SerializeSeq},
-    Deserialize, Deserializer, Serialize, Serializer,
-};
+use serde::{de::Error, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::parser::expression::Compare;
 
 use super::linearize::Function;
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-#[repr(u64)]
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
 // Top is Rhs (Bottom is lhs and calculated first)
 enum OpCodeTag {
     Modulus,       // 2 Stack Values
@@ -34,8 +29,6 @@ enum OpCodeTag {
     Compare,      // 2 Stack Values (top is lhs), 1 OpCode (as Compare)
     And,          // 2 Stack Values
     Or,           // 2 Stack Values
-    ScopeUp,      // Go into a new block
-    ScopeDown,    // Leave a block
     Return,       // Return top of Stack
     ConditionalJump, // 1 Stack Value and 1 OpCode Value
     ComparisonJump, // 2 Stack Values and 3 OpCodes (Greater Than -> First Jump, Less Than -> Second Jump, Equal -> Third Jump)
@@ -47,8 +40,12 @@ enum OpCodeTag {
     PushBuiltin,    // 1 ByteCode Value (index of reference pool)
     DuplicateDeep,  // 1 ByteCode Value (index of stack to duplicate (0 is top of stack))
     PushSelf,
-    Yield, // Yield top of Stack
-    Yeet,  // Yeet top of Stack
+    Yield,       // Yield top of Stack
+    Yeet,        // Yeet top of Stack
+    PushLocal,   // 1 ByteCode Value (local slot)
+    AssignLocal, // 1 Stack Value (value) and 1 ByteCode Value (local slot)
+    PushBound,   // 1 ByteCode Value (index of bound values)
+    TailCall,    // Same as FunctionCall, always followed by Return
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -72,8 +69,6 @@ pub enum OpCode {
     Compare(Compare),         // 2 Stack Values (top is lhs), 1 OpCode (as Compare)
     And,                      // 2 Stack Values
     Or,                       // 2 Stack Values
-    ScopeUp,                  // Go into a new block
-    ScopeDown,                // Leave a block
     Return,                   // Return top of Stack
     ConditionalJump(usize),   // 1 Stack Value and 1 OpCode Value
     ComparisonJump(usize, usize, usize), // 2 Stack Values and 3 OpCodes (Greater Than -> First Jump, Less Than -> Second Jump, Equal -> Third Jump)
@@ -85,11 +80,30 @@ pub enum OpCode {
     PushBuiltin(usize),                  // 1 ByteCode Value (index of reference pool)
     DuplicateDeep(usize), // 1 ByteCode Value (index of stack to duplicate (0 is top of stack))
     PushSelf,
-    Yield, // Yield top of Stack
-    Yeet,  // Yeet top of Stack
+    Yield,              // Yield top of Stack
+    Yeet,               // Yeet top of Stack
+    PushLocal(usize),   // 1 ByteCode Value (local slot)
+    AssignLocal(usize), // 1 Stack Value (value) and 1 ByteCode Value (local slot)
+    PushBound(usize),   // 1 ByteCode Value (index of bound values)
+    TailCall(usize),    // Same as FunctionCall, always followed by Return
 }
 
 impl OpCode {
+    /// Apply `f` to every jump target of this opcode
+    pub fn map_targets<F>(self, mut f: F) -> OpCode
+    where
+        F: FnMut(usize) -> usize,
+    {
+        match self {
+            OpCode::ConditionalJump(target) => OpCode::ConditionalJump(f(target)),
+            OpCode::Jump(target) => OpCode::Jump(f(target)),
+            OpCode::ComparisonJump(greater, less, equal) => {
+                OpCode::ComparisonJump(f(greater), f(less), f(equal))
+            }
+            other => other,
+        }
+    }
+
     pub fn jump(is_conditional: bool, target: usize) -> OpCode {
         match is_conditional {
             true => OpCode::ConditionalJump(target),
@@ -135,8 +149,6 @@ impl OpCode {
             }
             OpCode::And => write!(f, "And")?,
             OpCode::Or => write!(f, "Or")?,
-            OpCode::ScopeUp => write!(f, "ScopeUp")?,
-            OpCode::ScopeDown => write!(f, "ScopeDown")?,
             OpCode::Return => write!(f, "Return")?,
             OpCode::ConditionalJump(jump_index) => {
                 write!(f, "CndJmp {}", jump_index)?;
@@ -182,12 +194,77 @@ impl OpCode {
             }
             OpCode::Yield => write!(f, "Yield")?,
             OpCode::Yeet => write!(f, "Yeet")?,
+            OpCode::PushLocal(slot) => {
+                write!(f, "PushLocal \"{}\"[{}]", func.locals[slot], slot)?;
+            }
+            OpCode::AssignLocal(slot) => {
+                write!(f, "AssignLocal \"{}\"[{}]", func.locals[slot], slot)?;
+            }
+            OpCode::PushBound(bind_index) => {
+                write!(
+                    f,
+                    "PushBound \"{}\"[{}]",
+                    func.binds[bind_index], bind_index
+                )?;
+            }
+            OpCode::TailCall(num_args) => {
+                write!(f, "TailCall {}", num_args)?;
+            }
         }
 
         Ok(())
     }
 }
 
+/// Every tag in the order of its byte value
+const OP_CODE_TAGS: [OpCodeTag; 36] = [
+    OpCodeTag::Modulus,
+    OpCodeTag::Literal,
+    OpCodeTag::PushReference,
+    OpCodeTag::PushFunction,
+    OpCodeTag::PushThis,
+    OpCodeTag::FunctionCall,
+    OpCodeTag::FieldAccess,
+    OpCodeTag::Addition,
+    OpCodeTag::Subtraction,
+    OpCodeTag::Negate,
+    OpCodeTag::Multiply,
+    OpCodeTag::Divide,
+    OpCodeTag::AssignReference,
+    OpCodeTag::AssignField,
+    OpCodeTag::DivideTruncate,
+    OpCodeTag::Exponent,
+    OpCodeTag::Compare,
+    OpCodeTag::And,
+    OpCodeTag::Or,
+    OpCodeTag::Return,
+    OpCodeTag::ConditionalJump,
+    OpCodeTag::ComparisonJump,
+    OpCodeTag::Jump,
+    OpCodeTag::Pop,
+    OpCodeTag::IsNull,
+    OpCodeTag::Duplicate,
+    OpCodeTag::DuplicateMany,
+    OpCodeTag::PushBuiltin,
+    OpCodeTag::DuplicateDeep,
+    OpCodeTag::PushSelf,
+    OpCodeTag::Yield,
+    OpCodeTag::Yeet,
+    OpCodeTag::PushLocal,
+    OpCodeTag::AssignLocal,
+    OpCodeTag::PushBound,
+    OpCodeTag::TailCall,
+];
+
+const COMPARES: [Compare; 6] = [
+    Compare::Less,
+    Compare::LessEqual,
+    Compare::Greater,
+    Compare::GreaterEqual,
+    Compare::Equal,
+    Compare::NotEqual,
+];
+
 fn bytecode_decode(op_code: OpCodeTag) -> &'static [ByteCodeTag] {
     use ByteCodeTag::*;
     match op_code {
@@ -210,12 +287,10 @@ fn bytecode_decode(op_code: OpCodeTag) -> &'static [ByteCodeTag] {
         OpCodeTag::Compare => &[Compare],
         OpCodeTag::And => &[],
         OpCodeTag::Or => &[],
-        OpCodeTag::ScopeUp => &[],
-        OpCodeTag::ScopeDown => &[],
         OpCodeTag::Return => &[],
-        OpCodeTag::ConditionalJump => &[Value],
-        OpCodeTag::ComparisonJump => &[Value, Value, Value],
-        OpCodeTag::Jump => &[Value],
+        OpCodeTag::ConditionalJump => &[Target],
+        OpCodeTag::ComparisonJump => &[Target, Target, Target],
+        OpCodeTag::Jump => &[Target],
         OpCodeTag::Pop => &[],
         OpCodeTag::IsNull => &[],
         OpCodeTag::Duplicate => &[],
@@ -225,205 +300,224 @@ fn bytecode_decode(op_code: OpCodeTag) -> &'static [ByteCodeTag] {
         OpCodeTag::PushSelf => &[],
         OpCodeTag::Yield => &[],
         OpCodeTag::Yeet => &[],
+        OpCodeTag::PushLocal => &[Value],
+        OpCodeTag::AssignLocal => &[Value],
+        OpCodeTag::PushBound => &[Value],
+        OpCodeTag::TailCall => &[Value],
     }
 }
 
+/// How each operand is laid out after the one byte opcode tag
 enum ByteCodeTag {
+    /// LEB128 encoded unsigned integer (pool indexes and counts)
     Value,
+    /// Fixed width little endian u32 so jumps can be placed before their target is known
+    Target,
+    /// One byte
     Compare,
+    /// One byte
     LetAssign,
 }
 
-// TODO: I'm pretty sure this is bad
-#[derive(Copy, Clone)]
-#[repr(C)]
-union ByteCode {
-    op_code: OpCodeTag,
-    value: u64,
-    compare: Compare,
-    let_assign: bool,
-}
+const TARGET_WIDTH: usize = 4;
 
-#[derive(Clone)]
-pub struct ByteCodeArray(Vec<ByteCode>);
+#[derive(Clone, Default)]
+pub struct ByteCodeArray(Vec<u8>);
 
 impl ByteCodeArray {
     // TODO: For safety, only accept a wrapper around usize as an offset
     pub fn new() -> Self {
         ByteCodeArray(Vec::new())
     }
+
     pub fn get(&self, offset: usize) -> Option<(OpCode, usize)> {
-        let op_code_tag = self.0.get(offset).cloned().map(ByteCode::as_op);
-        if let Some(op_code_tag) = op_code_tag {
-            let (op_code, delta) = match op_code_tag {
-                OpCodeTag::PushThis => (OpCode::PushThis, 1),
-                OpCodeTag::PushSelf => (OpCode::PushSelf, 1),
-                OpCodeTag::Modulus => (OpCode::Modulus, 1),
-                OpCodeTag::Literal => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    (OpCode::Literal(pool_index), 2)
-                }
-                OpCodeTag::PushReference => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    (OpCode::PushReference(pool_index), 2)
-                }
-                OpCodeTag::PushFunction => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    (OpCode::PushFunction(pool_index), 2)
-                }
-                OpCodeTag::FunctionCall => {
-                    let num_args = self.0[offset + 1].as_val();
-                    (OpCode::FunctionCall(num_args), 2)
-                }
-                OpCodeTag::FieldAccess => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    (OpCode::FieldAccess(pool_index), 2)
-                }
-                OpCodeTag::Addition => (OpCode::Addition, 1),
-                OpCodeTag::Subtraction => (OpCode::Subtraction, 1),
-                OpCodeTag::Negate => (OpCode::Negate, 1),
-                OpCodeTag::Multiply => (OpCode::Multiply, 1),
-                OpCodeTag::Divide => (OpCode::Divide, 1),
-                OpCodeTag::DivideTruncate => (OpCode::DivideTruncate, 1),
-                OpCodeTag::Exponent => (OpCode::Exponent, 1),
-                OpCodeTag::Compare => {
-                    let compare = self.0[offset + 1].as_cmp();
-                    (OpCode::Compare(compare), 2)
-                }
-                OpCodeTag::And => (OpCode::And, 1),
-                OpCodeTag::Or => (OpCode::Or, 1),
-                OpCodeTag::ScopeUp => (OpCode::ScopeUp, 1),
-                OpCodeTag::ScopeDown => (OpCode::ScopeDown, 1),
-                OpCodeTag::Return => (OpCode::Return, 1),
-                OpCodeTag::ConditionalJump => {
-                    let jump_index = self.0[offset + 1].as_val();
-                    (OpCode::ConditionalJump(jump_index), 2)
-                }
-                OpCodeTag::ComparisonJump => {
-                    let greater_jump_index = self.0[offset + 1].as_val();
-                    let less_jump_index = self.0[offset + 2].as_val();
-                    let equal_jump_index = self.0[offset + 3].as_val();
-                    (
-                        OpCode::ComparisonJump(
-                            greater_jump_index,
-                            less_jump_index,
-                            equal_jump_index,
-                        ),
-                        4,
-                    )
-                }
-                OpCodeTag::Jump => {
-                    let jump_index = self.0[offset + 1].as_val();
-                    (OpCode::Jump(jump_index), 2)
-                }
-                OpCodeTag::Pop => (OpCode::Pop, 1),
-                OpCodeTag::IsNull => (OpCode::IsNull, 1),
-                OpCodeTag::Duplicate => (OpCode::Duplicate, 1),
-                OpCodeTag::AssignReference => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    let is_let = self.0[offset + 2].as_bool();
-                    (OpCode::AssignReference(pool_index, is_let), 3)
-                }
-                OpCodeTag::AssignField => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    let is_let = self.0[offset + 2].as_bool();
-                    (OpCode::AssignField(pool_index, is_let), 3)
-                }
-                OpCodeTag::DuplicateMany => {
-                    let num = self.0[offset + 1].as_val();
-                    (OpCode::DuplicateMany(num), 2)
-                }
-                OpCodeTag::PushBuiltin => {
-                    let pool_index = self.0[offset + 1].as_val();
-                    (OpCode::PushBuiltin(pool_index), 2)
-                }
-                OpCodeTag::DuplicateDeep => {
-                    let dup_idx = self.0[offset + 1].as_val();
-                    (OpCode::DuplicateDeep(dup_idx), 2)
-                }
-                OpCodeTag::Yield => (OpCode::Yield, 1),
-                OpCodeTag::Yeet => (OpCode::Yeet, 1),
-            };
+        let op_code_tag = OP_CODE_TAGS[*self.0.get(offset)? as usize];
+        let mut cursor = offset + 1;
+        let op_code = match op_code_tag {
+            OpCodeTag::PushThis => OpCode::PushThis,
+            OpCodeTag::PushSelf => OpCode::PushSelf,
+            OpCodeTag::Modulus => OpCode::Modulus,
+            OpCodeTag::Literal => OpCode::Literal(self.read_value(&mut cursor)),
+            OpCodeTag::PushReference => OpCode::PushReference(self.read_value(&mut cursor)),
+            OpCodeTag::PushFunction => OpCode::PushFunction(self.read_value(&mut cursor)),
+            OpCodeTag::FunctionCall => OpCode::FunctionCall(self.read_value(&mut cursor)),
+            OpCodeTag::FieldAccess => OpCode::FieldAccess(self.read_value(&mut cursor)),
+            OpCodeTag::Addition => OpCode::Addition,
+            OpCodeTag::Subtraction => OpCode::Subtraction,
+            OpCodeTag::Negate => OpCode::Negate,
+            OpCodeTag::Multiply => OpCode::Multiply,
+            OpCodeTag::Divide => OpCode::Divide,
+            OpCodeTag::DivideTruncate => OpCode::DivideTruncate,
+            OpCodeTag::Exponent => OpCode::Exponent,
+            OpCodeTag::Compare => OpCode::Compare(self.read_compare(&mut cursor)),
+            OpCodeTag::And => OpCode::And,
+            OpCodeTag::Or => OpCode::Or,
+            OpCodeTag::Return => OpCode::Return,
+            OpCodeTag::ConditionalJump => OpCode::ConditionalJump(self.read_target(&mut cursor)),
+            OpCodeTag::ComparisonJump => {
+                let greater_jump_index = self.read_target(&mut cursor);
+                let less_jump_index = self.read_target(&mut cursor);
+                let equal_jump_index = self.read_target(&mut cursor);
+                OpCode::ComparisonJump(greater_jump_index, less_jump_index, equal_jump_index)
+            }
+            OpCodeTag::Jump => OpCode::Jump(self.read_target(&mut cursor)),
+            OpCodeTag::Pop => OpCode::Pop,
+            OpCodeTag::IsNull => OpCode::IsNull,
+            OpCodeTag::Duplicate => OpCode::Duplicate,
+            OpCodeTag::AssignReference => {
+                let pool_index = self.read_value(&mut cursor);
+                let is_let = self.read_bool(&mut cursor);
+                OpCode::AssignReference(pool_index, is_let)
+            }
+            OpCodeTag::AssignField => {
+                let pool_index = self.read_value(&mut cursor);
+                let is_let = self.read_bool(&mut cursor);
+                OpCode::AssignField(pool_index, is_let)
+            }
+            OpCodeTag::DuplicateMany => OpCode::DuplicateMany(self.read_value(&mut cursor)),
+            OpCodeTag::PushBuiltin => OpCode::PushBuiltin(self.read_value(&mut cursor)),
+            OpCodeTag::DuplicateDeep => OpCode::DuplicateDeep(self.read_value(&mut cursor)),
+            OpCodeTag::Yield => OpCode::Yield,
+            OpCodeTag::Yeet => OpCode::Yeet,
+            OpCodeTag::PushLocal => OpCode::PushLocal(self.read_value(&mut cursor)),
+            OpCodeTag::AssignLocal => OpCode::AssignLocal(self.read_value(&mut cursor)),
+            OpCodeTag::PushBound => OpCode::PushBound(self.read_value(&mut cursor)),
+            OpCodeTag::TailCall => OpCode::TailCall(self.read_value(&mut cursor)),
+        };
 
-            Some((op_code, offset + delta))
-        } else {
-            None
-        }
+        Some((op_code, cursor))
     }
 
     pub fn push(&mut self, op_code: OpCode) {
         match op_code {
-            OpCode::Modulus => self.0.extend([ByteCode::op(OpCodeTag::Modulus)]),
-            OpCode::Literal(idx) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::Literal), ByteCode::val(idx)]),
-            OpCode::PushReference(idx) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::PushReference), ByteCode::val(idx)]),
-            OpCode::PushFunction(idx) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::PushFunction), ByteCode::val(idx)]),
-            OpCode::PushThis => self.0.extend([ByteCode::op(OpCodeTag::PushThis)]),
-            OpCode::FunctionCall(idx) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::FunctionCall), ByteCode::val(idx)]),
-            OpCode::FieldAccess(idx) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::FieldAccess), ByteCode::val(idx)]),
-            OpCode::Addition => self.0.extend([ByteCode::op(OpCodeTag::Addition)]),
-            OpCode::Subtraction => self.0.extend([ByteCode::op(OpCodeTag::Subtraction)]),
-            OpCode::Negate => self.0.extend([ByteCode::op(OpCodeTag::Negate)]),
-            OpCode::Multiply => self.0.extend([ByteCode::op(OpCodeTag::Multiply)]),
-            OpCode::Divide => self.0.extend([ByteCode::op(OpCodeTag::Divide)]),
-            OpCode::AssignReference(idx, is_let) => self.0.extend([
-                ByteCode::op(OpCodeTag::AssignReference),
-                ByteCode::val(idx),
-                ByteCode::bool(is_let),
-            ]),
-            OpCode::AssignField(idx, is_let) => self.0.extend([
-                ByteCode::op(OpCodeTag::AssignField),
-                ByteCode::val(idx),
-                ByteCode::bool(is_let),
-            ]),
-            OpCode::DivideTruncate => self.0.extend([ByteCode::op(OpCodeTag::DivideTruncate)]),
-            OpCode::Exponent => self.0.extend([ByteCode::op(OpCodeTag::Exponent)]),
-            OpCode::Compare(compare) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::Compare), ByteCode::cmp(compare)]),
-            OpCode::And => self.0.extend([ByteCode::op(OpCodeTag::And)]),
-            OpCode::Or => self.0.extend([ByteCode::op(OpCodeTag::Or)]),
-            OpCode::ScopeUp => self.0.extend([ByteCode::op(OpCodeTag::ScopeUp)]),
-            OpCode::ScopeDown => self.0.extend([ByteCode::op(OpCodeTag::ScopeDown)]),
-            OpCode::Return => self.0.extend([ByteCode::op(OpCodeTag::Return)]),
-            OpCode::ConditionalJump(offset) => self.0.extend([
-                ByteCode::op(OpCodeTag::ConditionalJump),
-                ByteCode::val(offset),
-            ]),
-            OpCode::ComparisonJump(off1, off2, off3) => self.0.extend([
-                ByteCode::op(OpCodeTag::ComparisonJump),
-                ByteCode::val(off1),
-                ByteCode::val(off2),
-                ByteCode::val(off3),
-            ]),
-            OpCode::Jump(offset) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::Jump), ByteCode::val(offset)]),
-            OpCode::Pop => self.0.extend([ByteCode::op(OpCodeTag::Pop)]),
-            OpCode::IsNull => self.0.extend([ByteCode::op(OpCodeTag::IsNull)]),
-            OpCode::Duplicate => self.0.extend([ByteCode::op(OpCodeTag::Duplicate)]),
-            OpCode::DuplicateMany(n) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::DuplicateMany), ByteCode::val(n)]),
-            OpCode::PushBuiltin(n) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::PushBuiltin), ByteCode::val(n)]),
-            OpCode::DuplicateDeep(n) => self
-                .0
-                .extend([ByteCode::op(OpCodeTag::DuplicateDeep), ByteCode::val(n)]),
-            OpCode::PushSelf => self.0.extend([ByteCode::op(OpCodeTag::PushSelf)]),
-            OpCode::Yield => self.0.extend([ByteCode::op(OpCodeTag::Yield)]),
-            OpCode::Yeet => self.0.extend([ByteCode::op(OpCodeTag::Yeet)]),
+            OpCode::Modulus => self.push_tag(OpCodeTag::Modulus),
+            OpCode::Literal(idx) => {
+                self.push_tag(OpCodeTag::Literal);
+                self.push_value(idx);
+            }
+            OpCode::PushReference(idx) => {
+                self.push_tag(OpCodeTag::PushReference);
+                self.push_value(idx);
+            }
+            OpCode::PushFunction(idx) => {
+                self.push_tag(OpCodeTag::PushFunction);
+                self.push_value(idx);
+            }
+            OpCode::PushThis => self.push_tag(OpCodeTag::PushThis),
+            OpCode::FunctionCall(idx) => {
+                self.push_tag(OpCodeTag::FunctionCall);
+                self.push_value(idx);
+            }
+            OpCode::FieldAccess(idx) => {
+                self.push_tag(OpCodeTag::FieldAccess);
+                self.push_value(idx);
+            }
+            OpCode::Addition => self.push_tag(OpCodeTag::Addition),
+            OpCode::Subtraction => self.push_tag(OpCodeTag::Subtraction),
+            OpCode::Negate => self.push_tag(OpCodeTag::Negate),
+            OpCode::Multiply => self.push_tag(OpCodeTag::Multiply),
+            OpCode::Divide => self.push_tag(OpCodeTag::Divide),
+            OpCode::AssignReference(idx, is_let) => {
+                self.push_tag(OpCodeTag::AssignReference);
+                self.push_value(idx);
+                self.0.push(is_let as u8);
+            }
+            OpCode::AssignField(idx, is_let) => {
+                self.push_tag(OpCodeTag::AssignField);
+                self.push_value(idx);
+                self.0.push(is_let as u8);
+            }
+            OpCode::DivideTruncate => self.push_tag(OpCodeTag::DivideTruncate),
+            OpCode::Exponent => self.push_tag(OpCodeTag::Exponent),
+            OpCode::Compare(compare) => {
+                self.push_tag(OpCodeTag::Compare);
+                self.0.push(compare as u8);
+            }
+            OpCode::And => self.push_tag(OpCodeTag::And),
+            OpCode::Or => self.push_tag(OpCodeTag::Or),
+            OpCode::Return => self.push_tag(OpCodeTag::Return),
+            OpCode::ConditionalJump(offset) => {
+                self.push_tag(OpCodeTag::ConditionalJump);
+                self.push_target(offset);
+            }
+            OpCode::ComparisonJump(off1, off2, off3) => {
+                self.push_tag(OpCodeTag::ComparisonJump);
+                self.push_target(off1);
+                self.push_target(off2);
+                self.push_target(off3);
+            }
+            OpCode::Jump(offset) => {
+                self.push_tag(OpCodeTag::Jump);
+                self.push_target(offset);
+            }
+            OpCode::Pop => self.push_tag(OpCodeTag::Pop),
+            OpCode::IsNull => self.push_tag(OpCodeTag::IsNull),
+            OpCode::Duplicate => self.push_tag(OpCodeTag::Duplicate),
+            OpCode::DuplicateMany(n) => {
+                self.push_tag(OpCodeTag::DuplicateMany);
+                self.push_value(n);
+            }
+            OpCode::PushBuiltin(n) => {
+                self.push_tag(OpCodeTag::PushBuiltin);
+                self.push_value(n);
+            }
+            OpCode::DuplicateDeep(n) => {
+                self.push_tag(OpCodeTag::DuplicateDeep);
+                self.push_value(n);
+            }
+            OpCode::PushSelf => self.push_tag(OpCodeTag::PushSelf),
+            OpCode::Yield => self.push_tag(OpCodeTag::Yield),
+            OpCode::Yeet => self.push_tag(OpCodeTag::Yeet),
+            OpCode::PushLocal(slot) => {
+                self.push_tag(OpCodeTag::PushLocal);
+                self.push_value(slot);
+            }
+            OpCode::AssignLocal(slot) => {
+                self.push_tag(OpCodeTag::AssignLocal);
+                self.push_value(slot);
+            }
+            OpCode::PushBound(bind_index) => {
+                self.push_tag(OpCodeTag::PushBound);
+                self.push_value(bind_index);
+            }
+            OpCode::TailCall(num_args) => {
+                self.push_tag(OpCodeTag::TailCall);
+                self.push_value(num_args);
+            }
+        };
+    }
+
+    /// Decode every instruction up front, with jump targets changed from byte offsets to
+    /// instruction indexes. Also returns the byte offset of each instruction (and the end).
+    pub fn decode(&self) -> (Vec<OpCode>, Vec<usize>) {
+        let (mut offsets, ops): (Vec<_>, Vec<_>) = self.iter().unzip();
+        offsets.push(self.len());
+        let to_index = |offset| {
+            offsets
+                .binary_search(&offset)
+                .expect("Jump target is not an instruction")
         };
+        let ops = ops.into_iter().map(|op| op.map_targets(to_index)).collect();
+        (ops, offsets)
+    }
+
+    /// The inverse of `decode`, jump targets are changed from instruction indexes to byte
+    /// offsets. Also returns the byte offset of each instruction (and the end).
+    pub fn encode(ops: &[OpCode]) -> (ByteCodeArray, Vec<usize>) {
+        // Targets are fixed width, so laying out placeholder targets gives the real offsets
+        let mut layout = ByteCodeArray::new();
+        let mut offsets = Vec::with_capacity(ops.len() + 1);
+        for &op in ops {
+            offsets.push(layout.len());
+            layout.push(op.map_targets(|_| 0));
+        }
+        offsets.push(layout.len());
+
+        let mut code = ByteCodeArray::new();
+        for &op in ops {
+            code.push(op.map_targets(|target| offsets[target]));
+        }
+        (code, offsets)
     }
 
     pub fn iter<'a>(&'a self) -> OpCodeIter<'a> {
@@ -444,21 +538,145 @@ impl ByteCodeArray {
             OpCode::Jump(0)
         };
         self.push(op);
-        let jump_target_loc = self.0.len() - 1;
+        let jump_target_loc = self.0.len() - TARGET_WIDTH;
         return move |this, jump_target| {
-            this.0[jump_target_loc] = ByteCode::val(jump_target.unwrap_or(this.len()));
+            let jump_target = jump_target.unwrap_or(this.len());
+            this.write_target(jump_target_loc, jump_target);
         };
     }
 
     pub fn place_cmp_jump(&mut self) -> impl FnOnce(&mut Self, [usize; 3]) {
         self.push(OpCode::ComparisonJump(0, 0, 0));
-        let jump_target_arr_loc = self.0.len() - 3;
+        let jump_target_arr_loc = self.0.len() - 3 * TARGET_WIDTH;
         return move |this, jump_target| {
             for i in 0..3 {
-                this.0[jump_target_arr_loc + i] = ByteCode::val(jump_target[i]);
+                this.write_target(jump_target_arr_loc + i * TARGET_WIDTH, jump_target[i]);
             }
         };
     }
+
+    fn push_tag(&mut self, op_code: OpCodeTag) {
+        self.0.push(op_code as u8);
+    }
+
+    fn push_value(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn push_target(&mut self, target: usize) {
+        let target = u32::try_from(target).expect("Function is too long to jump within");
+        self.0.extend_from_slice(&target.to_le_bytes());
+    }
+
+    fn write_target(&mut self, location: usize, target: usize) {
+        let target = u32::try_from(target).expect("Function is too long to jump within");
+        self.0[location..location + TARGET_WIDTH].copy_from_slice(&target.to_le_bytes());
+    }
+
+    fn read_value(&self, cursor: &mut usize) -> usize {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.0[*cursor];
+            *cursor += 1;
+            value |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_target(&self, cursor: &mut usize) -> usize {
+        let mut bytes = [0u8; TARGET_WIDTH];
+        bytes.copy_from_slice(&self.0[*cursor..*cursor + TARGET_WIDTH]);
+        *cursor += TARGET_WIDTH;
+        u32::from_le_bytes(bytes) as usize
+    }
+
+    fn read_compare(&self, cursor: &mut usize) -> Compare {
+        let compare = COMPARES[self.0[*cursor] as usize];
+        *cursor += 1;
+        compare
+    }
+
+    fn read_bool(&self, cursor: &mut usize) -> bool {
+        let value = self.0[*cursor] != 0;
+        *cursor += 1;
+        value
+    }
+
+    /// Check that untrusted bytes decode to whole instructions with in-bounds jumps,
+    /// so that `get` never has to
+    fn validate(bytes: Vec<u8>) -> Result<Self, String> {
+        let mut boundaries = Vec::new();
+        let mut targets = Vec::new();
+        let mut cursor = 0;
+        let truncated = || "Bytecode is an invalid length".to_string();
+        while cursor < bytes.len() {
+            boundaries.push(cursor);
+            let tag = *OP_CODE_TAGS
+                .get(bytes[cursor] as usize)
+                .ok_or_else(|| format!("Invalid opcode {} at {}", bytes[cursor], cursor))?;
+            cursor += 1;
+            for operand in bytecode_decode(tag) {
+                match operand {
+                    ByteCodeTag::Value => {
+                        let mut length = 0;
+                        loop {
+                            let byte = *bytes.get(cursor + length).ok_or_else(truncated)?;
+                            length += 1;
+                            if byte & 0x80 == 0 {
+                                break;
+                            }
+                            if length * 7 >= usize::BITS as usize {
+                                return Err(format!("Operand at {} is too large", cursor));
+                            }
+                        }
+                        cursor += length;
+                    }
+                    ByteCodeTag::Target => {
+                        let target = bytes
+                            .get(cursor..cursor + TARGET_WIDTH)
+                            .ok_or_else(truncated)?;
+                        let mut target_bytes = [0u8; TARGET_WIDTH];
+                        target_bytes.copy_from_slice(target);
+                        targets.push(u32::from_le_bytes(target_bytes) as usize);
+                        cursor += TARGET_WIDTH;
+                    }
+                    ByteCodeTag::Compare => {
+                        let compare = *bytes.get(cursor).ok_or_else(truncated)?;
+                        if compare as usize >= COMPARES.len() {
+                            return Err(format!("Invalid comparison {} at {}", compare, cursor));
+                        }
+                        cursor += 1;
+                    }
+                    ByteCodeTag::LetAssign => {
+                        let flag = *bytes.get(cursor).ok_or_else(truncated)?;
+                        if flag > 1 {
+                            return Err(format!("Invalid flag {} at {}", flag, cursor));
+                        }
+                        cursor += 1;
+                    }
+                }
+            }
+        }
+        boundaries.push(bytes.len());
+        for target in targets {
+            if boundaries.binary_search(&target).is_err() {
+                return Err(format!("Jump target {} is not an instruction", target));
+            }
+        }
+        Ok(ByteCodeArray(bytes))
+    }
 }
 
 pub struct OpCodeIter<'a> {
@@ -483,28 +701,7 @@ impl Serialize for ByteCodeArray {
     where
         S: Serializer,
     {
-        let mut iter = self.0.iter();
-        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
-        while let Some(op_code) = iter.next() {
-            let op_code = op_code.as_op();
-            seq.serialize_element(&op_code)?;
-            let following = bytecode_decode(op_code);
-            let codes = iter.by_ref().take(following.len()).collect::<Vec<_>>();
-            if following.len() != codes.len() {
-                return Err(ser::Error::custom("Bytecode is an invalid length"));
-            }
-            for (tag, code) in following.into_iter().zip(codes) {
-                unsafe {
-                    match tag {
-                        ByteCodeTag::Value => seq.serialize_element(&code.value)?,
-                        ByteCodeTag::Compare => seq.serialize_element(&code.compare)?,
-                        ByteCodeTag::LetAssign => seq.serialize_element(&code.let_assign)?,
-                    }
-                }
-            }
-        }
-
-        seq.end()
+        serializer.serialize_bytes(&self.0)
     }
 }
 
@@ -513,7 +710,7 @@ impl<'de> Deserialize<'de> for ByteCodeArray {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_seq(ByteCodeArrayVisitor)
+        deserializer.deserialize_byte_buf(ByteCodeArrayVisitor)
     }
 }
 
@@ -523,71 +720,32 @@ impl<'de> Visitor<'de> for ByteCodeArrayVisitor {
     type Value = ByteCodeArray;
 
     fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "a pusl bytecode (8 bytes) representing an opcode or a u64"
-        )
+        write!(f, "pusl bytecode as a sequence of bytes")
     }
 
-    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E>
     where
-        A: serde::de::SeqAccess<'de>,
+        E: Error,
     {
-        let mut code = Vec::with_capacity(seq.size_hint().unwrap_or(0));
-        let err_fn = || A::Error::custom("Bytecode is an invalid length");
-        while let Some(op_code) = seq.next_element::<OpCodeTag>()? {
-            code.push(ByteCode::op(op_code));
-            for tag in bytecode_decode(op_code) {
-                match tag {
-                    ByteCodeTag::Value => code.push(ByteCode::val(
-                        seq.next_element::<u64>()?.ok_or_else(err_fn)? as usize,
-                    )),
-                    ByteCodeTag::Compare => code.push(ByteCode {
-                        compare: seq.next_element::<Compare>()?.ok_or_else(err_fn)?,
-                    }),
-                    ByteCodeTag::LetAssign => code.push(ByteCode {
-                        let_assign: seq.next_element::<bool>()?.ok_or_else(err_fn)?,
-                    }),
-                }
-            }
-        }
-
-        Ok(ByteCodeArray(code))
+        ByteCodeArray::validate(bytes.to_vec()).map_err(E::custom)
     }
-}
 
-impl ByteCode {
-    fn op(op_code: OpCodeTag) -> Self {
-        ByteCode { op_code }
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        ByteCodeArray::validate(bytes).map_err(E::custom)
     }
 
-    fn val(value: usize) -> Self {
-        ByteCode {
-            value: value as u64,
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
         }
-    }
-
-    fn bool(value: bool) -> Self {
-        ByteCode { let_assign: value }
-    }
-
-    fn cmp(value: Compare) -> Self {
-        ByteCode { compare: value }
-    }
-
-    fn as_op(self) -> OpCodeTag {
-        unsafe { self.op_code }
-    }
-
-    fn as_val(self) -> usize {
-        unsafe { self.value as usize }
-    }
-
-    fn as_bool(self) -> bool {
-        unsafe { self.let_assign }
-    }
 
-    fn as_cmp(self) -> Compare {
-        unsafe { self.compare }
+        ByteCodeArray::validate(bytes).map_err(A::Error::custom)
     }
 }
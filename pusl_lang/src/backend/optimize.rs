@@ -0,0 +1,520 @@
+//! Optimization passes over linearized functions. Each pass rewrites a function's decoded
+//! code (jump targets are instruction indexes) without changing what the code does,
+//! including which operations fail at runtime.
+
+use std::mem;
+
+use crate::lexer::token::Literal;
+use crate::parser::expression::Compare;
+
+use super::linearize::{BasicFunction, ByteCodeFile, ErrorCatch, Function};
+use super::opcode::{ByteCodeArray, OpCode};
+
+/// The highest level `optimize` does anything different for
+pub const MAX_LEVEL: u8 = 2;
+
+/// A rewrite of one function's code, returns true if anything changed
+type Pass = fn(&mut Body) -> bool;
+
+const BASIC_PASSES: &[Pass] = &[constant_fold, peephole];
+
+const FULL_PASSES: &[Pass] = &[constant_fold, peephole, thread_jumps, eliminate_dead_code];
+
+/// Passes are repeated until nothing changes, but never more than this many times
+const MAX_ROUNDS: usize = 16;
+
+/// Optimize every function in the file. Level 0 leaves the code as it is, level 1 folds
+/// constants and removes redundant instruction sequences, level 2 and above also threads
+/// jumps and removes unreachable code.
+pub fn optimize(bcf: &mut ByteCodeFile, level: u8) {
+    let passes = match level {
+        0 => return,
+        1 => BASIC_PASSES,
+        _ => FULL_PASSES,
+    };
+    optimize_function(&mut bcf.base_func, passes);
+}
+
+fn optimize_function(bfunc: &mut BasicFunction, passes: &[Pass]) {
+    for sub_function in &mut bfunc.sub_functions {
+        optimize_function(sub_function, passes);
+    }
+
+    let function = bfunc.as_mut();
+    let mut body = Body::new(function);
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+        for pass in passes {
+            changed |= pass(&mut body);
+        }
+        if !changed {
+            break;
+        }
+    }
+    body.finish(function);
+}
+
+/// A function's code being rewritten, along with the literal pool constants are folded into
+struct Body {
+    ops: Vec<OpCode>,
+    /// Catch ranges as instruction indexes
+    catches: Vec<ErrorCatch>,
+    /// The line table as instruction indexes
+    lines: Vec<(usize, usize)>,
+    literals: Vec<Literal>,
+}
+
+impl Body {
+    fn new(function: &mut Function) -> Self {
+        let (ops, offsets) = function.code.decode();
+        let to_index = |offset| {
+            offsets
+                .binary_search(&offset)
+                .expect("Catch is not on an instruction")
+        };
+        let catches = function
+            .catches
+            .iter()
+            .map(|catch| ErrorCatch {
+                begin: to_index(catch.begin),
+                filter: to_index(catch.filter),
+            })
+            .collect();
+        let lines = function
+            .lines
+            .iter()
+            .map(|&(offset, line)| (to_index(offset), line))
+            .collect();
+        Body {
+            ops,
+            catches,
+            lines,
+            literals: mem::take(&mut function.literals),
+        }
+    }
+
+    /// Write the code back to the function, dropping literals which are no longer used
+    fn finish(self, function: &mut Function) {
+        let Body {
+            mut ops,
+            catches,
+            lines,
+            literals,
+        } = self;
+
+        let mut used = vec![false; literals.len()];
+        for op in &ops {
+            if let OpCode::Literal(index) = *op {
+                used[index] = true;
+            }
+        }
+        let mut new_index = Vec::with_capacity(literals.len());
+        let mut kept = Vec::new();
+        for (literal, used) in literals.into_iter().zip(used) {
+            new_index.push(kept.len());
+            if used {
+                kept.push(literal);
+            }
+        }
+        for op in &mut ops {
+            if let OpCode::Literal(index) = op {
+                *index = new_index[*index];
+            }
+        }
+
+        let (code, offsets) = ByteCodeArray::encode(&ops);
+        function.code = code;
+        function.catches = catches
+            .into_iter()
+            .map(|catch| ErrorCatch {
+                begin: offsets[catch.begin],
+                filter: offsets[catch.filter],
+            })
+            .collect();
+        function.lines = lines
+            .into_iter()
+            .map(|(index, line)| (offsets[index], line))
+            .collect();
+        function.literals = kept;
+    }
+
+    /// Marks every instruction that control can reach other than by falling through (and the
+    /// end). A sequence can only be rewritten if none of its instructions after the first are
+    /// marked, otherwise something could jump into the middle of it.
+    fn leaders(&self) -> Vec<bool> {
+        let mut leaders = vec![false; self.ops.len() + 1];
+        for op in &self.ops {
+            op.map_targets(|target| {
+                leaders[target] = true;
+                target
+            });
+        }
+        for catch in &self.catches {
+            leaders[catch.begin] = true;
+            leaders[catch.filter] = true;
+        }
+        leaders
+    }
+
+    /// Is `ops[start..start + len]` in bounds with no jumps into the middle of it
+    fn is_sequence(&self, leaders: &[bool], start: usize, len: usize) -> bool {
+        start + len <= self.ops.len() && !leaders[start + 1..start + len].contains(&true)
+    }
+
+    /// Remove the marked instructions. Anything that targeted a removed instruction now
+    /// targets the next instruction which remains.
+    fn remove(&mut self, removed: &[bool]) -> bool {
+        if !removed.contains(&true) {
+            return false;
+        }
+        let mut new_index = Vec::with_capacity(removed.len() + 1);
+        let mut kept = 0;
+        for &is_removed in removed {
+            new_index.push(kept);
+            if !is_removed {
+                kept += 1;
+            }
+        }
+        new_index.push(kept);
+
+        self.ops = mem::take(&mut self.ops)
+            .into_iter()
+            .zip(removed)
+            .filter(|(_, &is_removed)| !is_removed)
+            .map(|(op, _)| op.map_targets(|target| new_index[target]))
+            .collect();
+        for catch in &mut self.catches {
+            catch.begin = new_index[catch.begin];
+            catch.filter = new_index[catch.filter];
+        }
+        // A line whose code was all removed is dropped
+        let mut lines: Vec<(usize, usize)> = Vec::with_capacity(self.lines.len());
+        for &(index, line) in &self.lines {
+            let index = new_index[index];
+            if lines.last().is_some_and(|&(start, _)| start == index) {
+                lines.pop();
+            }
+            if index < self.ops.len() && lines.last().map(|&(_, last)| last) != Some(line) {
+                lines.push((index, line));
+            }
+        }
+        self.lines = lines;
+        true
+    }
+
+    fn add_literal(&mut self, literal: Literal) -> usize {
+        // Compare floats by bits so 0.0 and -0.0 stay distinct
+        let is_same = |existing: &Literal| match (existing, &literal) {
+            (Literal::Float(lhs), Literal::Float(rhs)) => lhs.to_bits() == rhs.to_bits(),
+            (existing, literal) => existing == literal,
+        };
+        self.literals.iter().position(is_same).unwrap_or_else(|| {
+            self.literals.push(literal);
+            self.literals.len() - 1
+        })
+    }
+}
+
+/// Evaluate operations on literals at compile time. Anything which would fail at runtime
+/// (overflow, division by zero, mismatched types) is left for the runtime to report.
+fn constant_fold(body: &mut Body) -> bool {
+    let leaders = body.leaders();
+    let mut removed = vec![false; body.ops.len()];
+    let mut changed = false;
+    let mut index = 0;
+    while index < body.ops.len() {
+        let lhs = match body.ops[index] {
+            OpCode::Literal(pool_index) => body.literals[pool_index].clone(),
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+
+        if body.is_sequence(&leaders, index, 3) {
+            if let OpCode::Literal(pool_index) = body.ops[index + 1] {
+                let rhs = &body.literals[pool_index];
+                if let Some(result) = fold_binary(&lhs, rhs, body.ops[index + 2]) {
+                    let pool_index = body.add_literal(result);
+                    body.ops[index + 2] = OpCode::Literal(pool_index);
+                    removed[index] = true;
+                    removed[index + 1] = true;
+                    changed = true;
+                    // The result may fold with what follows it
+                    index += 2;
+                    continue;
+                }
+            }
+        }
+
+        if body.is_sequence(&leaders, index, 2) {
+            match (body.ops[index + 1], &lhs) {
+                // Copying a literal is the same as pushing it again, which may fold further
+                (OpCode::Duplicate, _) => {
+                    body.ops[index + 1] = body.ops[index];
+                    changed = true;
+                    index += 1;
+                    continue;
+                }
+                (OpCode::ConditionalJump(target), Literal::Boolean(condition)) => {
+                    removed[index] = true;
+                    if *condition {
+                        body.ops[index + 1] = OpCode::Jump(target);
+                    } else {
+                        removed[index + 1] = true;
+                    }
+                    changed = true;
+                    index += 2;
+                    continue;
+                }
+                (op, _) => {
+                    if let Some(result) = fold_unary(&lhs, op) {
+                        let pool_index = body.add_literal(result);
+                        body.ops[index + 1] = OpCode::Literal(pool_index);
+                        removed[index] = true;
+                        changed = true;
+                        index += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        index += 1;
+    }
+
+    body.remove(&removed) || changed
+}
+
+fn fold_unary(operand: &Literal, op: OpCode) -> Option<Literal> {
+    let result = match (op, operand) {
+        (OpCode::Negate, Literal::Boolean(val)) => Literal::Boolean(!val),
+        (OpCode::Negate, Literal::Integer(val)) => Literal::Integer(val.checked_neg()?),
+        (OpCode::Negate, Literal::Float(val)) => Literal::Float(-val),
+        (OpCode::IsNull, operand) => Literal::Boolean(matches!(operand, Literal::Null)),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn fold_binary(lhs: &Literal, rhs: &Literal, op: OpCode) -> Option<Literal> {
+    match op {
+        OpCode::Addition => fold_arithmetic(lhs, rhs, i64::checked_add, |lhs, rhs| lhs + rhs),
+        OpCode::Subtraction => fold_arithmetic(lhs, rhs, i64::checked_sub, |lhs, rhs| lhs - rhs),
+        OpCode::Multiply => fold_arithmetic(lhs, rhs, i64::checked_mul, |lhs, rhs| lhs * rhs),
+        OpCode::Divide => fold_arithmetic(lhs, rhs, i64::checked_div, |lhs, rhs| lhs / rhs),
+        OpCode::DivideTruncate => {
+            let (lhs, rhs) = match (lhs, rhs) {
+                (Literal::Integer(lhs), Literal::Integer(rhs)) => {
+                    return lhs.checked_div(*rhs).map(Literal::Integer)
+                }
+                (Literal::Float(lhs), Literal::Integer(rhs)) => (*lhs, *rhs as f64),
+                (Literal::Integer(lhs), Literal::Float(rhs)) => (*lhs as f64, *rhs),
+                (Literal::Float(lhs), Literal::Float(rhs)) => (*lhs, *rhs),
+                _ => return None,
+            };
+            Some(Literal::Integer((lhs / rhs) as i64))
+        }
+        OpCode::Exponent => {
+            let result = match (lhs, rhs) {
+                (Literal::Integer(lhs), Literal::Integer(rhs)) if *rhs >= 0 => {
+                    Literal::Integer(lhs.checked_pow(*rhs as u32)?)
+                }
+                (Literal::Integer(lhs), Literal::Integer(rhs)) => {
+                    Literal::Float((*lhs as f64).powi(*rhs as i32))
+                }
+                (Literal::Float(lhs), Literal::Integer(rhs)) => {
+                    Literal::Float(lhs.powi(*rhs as i32))
+                }
+                (Literal::Integer(lhs), Literal::Float(rhs)) => {
+                    Literal::Float((*lhs as f64).powf(*rhs))
+                }
+                (Literal::Float(lhs), Literal::Float(rhs)) => Literal::Float(lhs.powf(*rhs)),
+                _ => return None,
+            };
+            Some(result)
+        }
+        // The runtime implements modulus as a bitwise and
+        OpCode::Modulus => match (lhs, rhs) {
+            (Literal::Integer(lhs), Literal::Integer(rhs)) => Some(Literal::Integer(lhs & rhs)),
+            _ => None,
+        },
+        OpCode::And | OpCode::Or => {
+            let is_and = op == OpCode::And;
+            match (lhs, rhs) {
+                (Literal::Boolean(lhs), Literal::Boolean(rhs)) => {
+                    Some(Literal::Boolean(if is_and { lhs & rhs } else { lhs | rhs }))
+                }
+                (Literal::Integer(lhs), Literal::Integer(rhs)) => {
+                    Some(Literal::Integer(if is_and { lhs & rhs } else { lhs | rhs }))
+                }
+                _ => None,
+            }
+        }
+        OpCode::Compare(compare) => fold_compare(lhs, rhs, compare).map(Literal::Boolean),
+        _ => None,
+    }
+}
+
+fn fold_arithmetic(
+    lhs: &Literal,
+    rhs: &Literal,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Option<Literal> {
+    let result = match (lhs, rhs) {
+        (Literal::Integer(lhs), Literal::Integer(rhs)) => Literal::Integer(int_op(*lhs, *rhs)?),
+        (Literal::Float(lhs), Literal::Integer(rhs)) => Literal::Float(float_op(*lhs, *rhs as f64)),
+        (Literal::Integer(lhs), Literal::Float(rhs)) => Literal::Float(float_op(*lhs as f64, *rhs)),
+        (Literal::Float(lhs), Literal::Float(rhs)) => Literal::Float(float_op(*lhs, *rhs)),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn fold_compare(lhs: &Literal, rhs: &Literal, compare: Compare) -> Option<bool> {
+    let numbers = match (lhs, rhs) {
+        (Literal::Integer(lhs), Literal::Integer(rhs)) => Some(lhs.cmp(rhs)),
+        (Literal::Float(lhs), Literal::Integer(rhs)) => lhs.partial_cmp(&(*rhs as f64)),
+        (Literal::Integer(lhs), Literal::Float(rhs)) => (*lhs as f64).partial_cmp(rhs),
+        (Literal::Float(lhs), Literal::Float(rhs)) => lhs.partial_cmp(rhs),
+        _ => None,
+    };
+    let result = match compare {
+        Compare::Equal | Compare::NotEqual => {
+            let is_equal = match (lhs, rhs) {
+                (Literal::Null, Literal::Null) => true,
+                (Literal::Boolean(lhs), Literal::Boolean(rhs)) => lhs == rhs,
+                (Literal::String(lhs), Literal::String(rhs)) => lhs == rhs,
+                (Literal::Integer(lhs), Literal::Integer(rhs)) => lhs == rhs,
+                (Literal::Integer(lhs), Literal::Float(rhs)) => *lhs as f64 == *rhs,
+                (Literal::Float(lhs), Literal::Integer(rhs)) => *lhs == *rhs as f64,
+                (Literal::Float(lhs), Literal::Float(rhs)) => lhs == rhs,
+                _ => false,
+            };
+            is_equal ^ (compare == Compare::NotEqual)
+        }
+        // Ordering anything other than two comparable numbers fails at runtime
+        Compare::Less => numbers?.is_lt(),
+        Compare::LessEqual => numbers?.is_le(),
+        Compare::Greater => numbers?.is_gt(),
+        Compare::GreaterEqual => numbers?.is_ge(),
+    };
+    Some(result)
+}
+
+/// Remove or simplify short instruction sequences
+fn peephole(body: &mut Body) -> bool {
+    let leaders = body.leaders();
+    let mut removed = vec![false; body.ops.len()];
+    let mut changed = false;
+    let mut index = 0;
+    while index < body.ops.len() {
+        if body.is_sequence(&leaders, index, 2) {
+            match (body.ops[index], body.ops[index + 1]) {
+                // Pushing a value which can't fail and then discarding it
+                (
+                    OpCode::Duplicate
+                    | OpCode::Literal(_)
+                    | OpCode::PushLocal(_)
+                    | OpCode::PushBound(_),
+                    OpCode::Pop,
+                ) => {
+                    removed[index] = true;
+                    removed[index + 1] = true;
+                    changed = true;
+                    index += 2;
+                    continue;
+                }
+                // Comparisons which succeed are totally ordered, so negating flips them
+                (OpCode::Compare(compare), OpCode::Negate) => {
+                    body.ops[index + 1] = OpCode::Compare(inverse(compare));
+                    removed[index] = true;
+                    changed = true;
+                    index += 2;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        index += 1;
+    }
+
+    body.remove(&removed) || changed
+}
+
+fn inverse(compare: Compare) -> Compare {
+    match compare {
+        Compare::Less => Compare::GreaterEqual,
+        Compare::LessEqual => Compare::Greater,
+        Compare::Greater => Compare::LessEqual,
+        Compare::GreaterEqual => Compare::Less,
+        Compare::Equal => Compare::NotEqual,
+        Compare::NotEqual => Compare::Equal,
+    }
+}
+
+/// Send jumps which land on another jump straight to its target, turn jumps to a return into
+/// a return, and remove jumps to the next instruction
+fn thread_jumps(body: &mut Body) -> bool {
+    let ops = &body.ops;
+    let final_target = |mut target: usize| {
+        // Bounded so a cycle of jumps can't hang the compiler
+        for _ in 0..ops.len() {
+            match ops.get(target) {
+                Some(&OpCode::Jump(next)) if next != target => target = next,
+                _ => break,
+            }
+        }
+        target
+    };
+    let threaded = ops
+        .iter()
+        .map(|op| match op.map_targets(final_target) {
+            OpCode::Jump(target) if ops.get(target) == Some(&OpCode::Return) => OpCode::Return,
+            op => op,
+        })
+        .collect::<Vec<_>>();
+    let changed = threaded != body.ops;
+    body.ops = threaded;
+
+    let removed = body
+        .ops
+        .iter()
+        .enumerate()
+        .map(|(index, op)| *op == OpCode::Jump(index + 1))
+        .collect::<Vec<_>>();
+    body.remove(&removed) || changed
+}
+
+/// Remove instructions that can't be reached from the start of the function or a catch
+fn eliminate_dead_code(body: &mut Body) -> bool {
+    let len = body.ops.len();
+    let mut reachable = vec![false; len];
+    let mut to_visit = vec![0];
+    to_visit.extend(body.catches.iter().map(|catch| catch.filter));
+    while let Some(index) = to_visit.pop() {
+        if index >= len || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        let op = body.ops[index];
+        op.map_targets(|target| {
+            to_visit.push(target);
+            target
+        });
+        let falls_through = !matches!(
+            op,
+            OpCode::Jump(_) | OpCode::ComparisonJump(..) | OpCode::Return | OpCode::Yeet
+        );
+        if falls_through {
+            to_visit.push(index + 1);
+        }
+    }
+
+    let removed = reachable
+        .iter()
+        .map(|reachable| !reachable)
+        .collect::<Vec<_>>();
+    body.remove(&removed)
+}
@@ -0,0 +1,443 @@
+//! An instrumenting profiler, which observes every instruction and native call.
+//!
+//! Time between two instructions is counted against the first of them, so the time of a native
+//! function is counted separately, without the pusl code it calls back into. Calls are counted
+//! as frames are entered, so tail calls count as calls even though they reuse the frame.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::debug::module_of;
+use super::linearize::ResolvedFunction;
+use super::object::Value;
+use super::observe::Observer;
+use super::ExecutionState;
+
+/// Lines shown in the summary, the rest are left out
+const MAX_SUMMARY_LINES: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct FunctionProfile {
+    /// The function's arguments and where it starts, or the module's path for a module's code
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    /// Time from being called to returning, counting recursive calls once
+    pub inclusive: Duration,
+    /// Time spent running the function's own instructions
+    pub exclusive: Duration,
+    /// Time spent in native functions it called
+    pub native: Duration,
+    /// Instructions and time by source line
+    pub lines: BTreeMap<usize, LineProfile>,
+    /// Calls which haven't returned yet
+    active: usize,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LineProfile {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NativeProfile {
+    pub name: &'static str,
+    pub calls: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    Pusl(usize),
+    Native(usize),
+}
+
+/// A distinct call stack, made of its parent's stack and one more frame
+struct Node {
+    frame: Option<Frame>,
+    parent: usize,
+    children: HashMap<Frame, usize>,
+    time: Duration,
+}
+
+/// A call which hasn't returned
+struct Active {
+    function: usize,
+    depth: usize,
+    node: usize,
+    start: Instant,
+}
+
+/// The instruction time is being counted against
+struct Running {
+    function: usize,
+    line: Option<usize>,
+    node: usize,
+}
+
+/// Where a native call started, so its own time can be told apart from code it called
+struct NativeCall {
+    start: Instant,
+    counted: Duration,
+}
+
+pub struct Profiler {
+    functions: Vec<FunctionProfile>,
+    function_ids: HashMap<*const ResolvedFunction, usize>,
+    natives: Vec<NativeProfile>,
+    native_ids: HashMap<&'static str, usize>,
+    /// Every call stack seen, the first is the empty stack
+    nodes: Vec<Node>,
+    stack: Vec<Active>,
+    running: Option<Running>,
+    /// Native calls which haven't returned, innermost last
+    native_calls: Vec<NativeCall>,
+    last_tick: Instant,
+    /// All the time counted against something so far
+    counted: Duration,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            natives: Vec::new(),
+            native_ids: HashMap::new(),
+            nodes: vec![Node {
+                frame: None,
+                parent: 0,
+                children: HashMap::new(),
+                time: Duration::default(),
+            }],
+            stack: Vec::new(),
+            running: None,
+            native_calls: Vec::new(),
+            last_tick: Instant::now(),
+            counted: Duration::default(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    pub fn natives(&self) -> &[NativeProfile] {
+        &self.natives
+    }
+
+    fn child(&mut self, parent: usize, frame: Frame) -> usize {
+        if let Some(&node) = self.nodes[parent].children.get(&frame) {
+            return node;
+        }
+        self.nodes.push(Node {
+            frame: Some(frame),
+            parent,
+            children: HashMap::new(),
+            time: Duration::default(),
+        });
+        let node = self.nodes.len() - 1;
+        self.nodes[parent].children.insert(frame, node);
+        node
+    }
+
+    /// Counts the time since the last tick against what was running
+    fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_tick);
+        self.last_tick = now;
+        if let Some(running) = &self.running {
+            let function = &mut self.functions[running.function];
+            function.exclusive += elapsed;
+            if let Some(line) = running.line {
+                function.lines.entry(line).or_default().time += elapsed;
+            }
+            self.nodes[running.node].time += elapsed;
+            self.counted += elapsed;
+        }
+    }
+
+    fn function_id(
+        &mut self,
+        state: &ExecutionState,
+        function: &'static ResolvedFunction,
+    ) -> usize {
+        let key = function as *const ResolvedFunction;
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+        // Joined as a path, so the root of an absolute path isn't doubled
+        let module = module_of(state, function).map(|module| {
+            let path = state.imports[module].0.iter().collect::<PathBuf>();
+            path.display().to_string()
+        });
+        let module = module.unwrap_or_else(|| "<unknown>".to_string());
+        let is_module_code = state
+            .module_functions
+            .iter()
+            .any(|module| std::ptr::eq(*module, function));
+        let name = if is_module_code {
+            module
+        } else {
+            match function.line_at(0) {
+                Some(line) => format!(
+                    "fn({}) {}:{}",
+                    function.as_ref().args.join(", "),
+                    module,
+                    line
+                ),
+                None => format!("fn({}) {}", function.as_ref().args.join(", "), module),
+            }
+        };
+        self.functions.push(FunctionProfile {
+            name,
+            ..FunctionProfile::default()
+        });
+        self.function_ids.insert(key, self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    /// Starts counting time against the current frame, as a new call unless it's resuming
+    fn push(&mut self, state: &ExecutionState, now: Instant, is_call: bool) {
+        let function = self.function_id(state, state.current_frame.bfunc.target);
+        let parent = self.stack.last().map_or(0, |top| top.node);
+        let node = self.child(parent, Frame::Pusl(function));
+        self.stack.push(Active {
+            function,
+            depth: state.call_depth(),
+            node,
+            start: now,
+        });
+        let profile = &mut self.functions[function];
+        if is_call {
+            profile.calls += 1;
+        }
+        profile.active += 1;
+    }
+
+    /// Ends the calls deeper than `depth`, like frames left by an error
+    fn pop_below(&mut self, depth: usize, now: Instant) {
+        while self.stack.last().is_some_and(|top| top.depth > depth) {
+            self.exit(now);
+        }
+    }
+
+    fn exit(&mut self, now: Instant) {
+        let active = self.stack.pop().unwrap();
+        let function = &mut self.functions[active.function];
+        function.active -= 1;
+        if function.active == 0 {
+            function.inclusive += now.saturating_duration_since(active.start);
+        }
+    }
+
+    /// Ends every call still running, once the script is done
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        self.tick(now);
+        self.running = None;
+        while !self.stack.is_empty() {
+            self.exit(now);
+        }
+    }
+
+    /// A table of functions by their own time, the lines they spent the most time on and the
+    /// native functions called
+    pub fn write_summary(&self, out: &mut dyn Write) -> io::Result<()> {
+        fn millis(duration: Duration) -> f64 {
+            duration.as_secs_f64() * 1000.0
+        }
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by_key(|function| std::cmp::Reverse(function.exclusive));
+        writeln!(
+            out,
+            "{:>10} {:>14} {:>14} {:>14} {:>12}  function",
+            "calls", "instructions", "inclusive ms", "exclusive ms", "native ms"
+        )?;
+        for function in &functions {
+            writeln!(
+                out,
+                "{:>10} {:>14} {:>14.3} {:>14.3} {:>12.3}  {}",
+                function.calls,
+                function.instructions,
+                millis(function.inclusive),
+                millis(function.exclusive),
+                millis(function.native),
+                function.name
+            )?;
+        }
+
+        let mut lines = functions
+            .iter()
+            .flat_map(|function| {
+                function
+                    .lines
+                    .iter()
+                    .map(move |(line, profile)| (function, line, profile))
+            })
+            .collect::<Vec<_>>();
+        lines.sort_by_key(|(_, _, profile)| std::cmp::Reverse(profile.time));
+        writeln!(out)?;
+        writeln!(out, "{:>14} {:>14}  line", "instructions", "time ms")?;
+        for (function, line, profile) in lines.iter().take(MAX_SUMMARY_LINES) {
+            writeln!(
+                out,
+                "{:>14} {:>14.3}  {} line {}",
+                profile.instructions,
+                millis(profile.time),
+                function.name,
+                line
+            )?;
+        }
+        if lines.len() > MAX_SUMMARY_LINES {
+            writeln!(out, "...and {} more", lines.len() - MAX_SUMMARY_LINES)?;
+        }
+
+        if !self.natives.is_empty() {
+            let mut natives = self.natives.iter().collect::<Vec<_>>();
+            natives.sort_by_key(|native| std::cmp::Reverse(native.time));
+            writeln!(out)?;
+            writeln!(out, "{:>10} {:>14}  native", "calls", "time ms")?;
+            for native in natives {
+                writeln!(
+                    out,
+                    "{:>10} {:>14.3}  {}",
+                    native.calls,
+                    millis(native.time),
+                    native.name
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// One line per call stack, its frames joined by ';' then the nanoseconds spent there, as
+    /// flamegraph tools read
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            let nanos = node.time.as_nanos();
+            if nanos == 0 {
+                continue;
+            }
+            let mut frames = Vec::new();
+            let mut current = index;
+            while let Some(frame) = self.nodes[current].frame {
+                frames.push(match frame {
+                    Frame::Pusl(function) => self.functions[function].name.clone(),
+                    Frame::Native(native) => format!("native {}", self.natives[native].name),
+                });
+                current = self.nodes[current].parent;
+            }
+            frames.reverse();
+            writeln!(out, "{} {}", frames.join(";"), nanos)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Observer<'a> for Profiler {
+    fn instruction(&mut self, state: &ExecutionState<'a>, index: usize) {
+        let now = Instant::now();
+        self.tick(now);
+        let target = state.current_frame.bfunc.target;
+        let depth = state.call_depth();
+        // Frames dropped by an error which left a native function's call aren't exited
+        self.pop_below(depth, now);
+        // The first module starts running without being entered
+        if self.stack.last().is_none_or(|top| top.depth < depth) {
+            self.push(state, now, true);
+        }
+        let function = self.stack.last().unwrap().function;
+
+        let line = target.line_at(index);
+        let profile = &mut self.functions[function];
+        profile.instructions += 1;
+        if let Some(line) = line {
+            profile.lines.entry(line).or_default().instructions += 1;
+        }
+        self.running = Some(Running {
+            function,
+            line,
+            node: self.stack.last().unwrap().node,
+        });
+    }
+
+    fn enter(&mut self, state: &ExecutionState<'a>) {
+        let now = Instant::now();
+        self.tick(now);
+        // A tail call replaces the caller's frame, so the caller returns as the callee starts
+        self.pop_below(state.call_depth() - 1, now);
+        self.push(state, now, true);
+    }
+
+    fn exit(&mut self, state: &ExecutionState<'a>, _value: &Value) {
+        let now = Instant::now();
+        self.tick(now);
+        self.pop_below(state.call_depth() - 1, now);
+    }
+
+    fn unwind(&mut self, state: &ExecutionState<'a>, _frames: usize) {
+        let now = Instant::now();
+        self.tick(now);
+        self.pop_below(state.call_depth(), now);
+    }
+
+    fn yielded(&mut self, state: &ExecutionState<'a>, _value: &Value) {
+        let now = Instant::now();
+        self.tick(now);
+        self.pop_below(state.call_depth() - 1, now);
+    }
+
+    fn resumed(&mut self, state: &ExecutionState<'a>) {
+        let now = Instant::now();
+        self.tick(now);
+        self.push(state, now, false);
+    }
+
+    fn native_enter(&mut self, _state: &ExecutionState<'a>, _name: &'static str) {
+        let start = Instant::now();
+        self.tick(start);
+        self.native_calls.push(NativeCall {
+            start,
+            counted: self.counted,
+        });
+    }
+
+    /// Counts a native call's time, less whatever was counted while it ran
+    fn native_exit(&mut self, _state: &ExecutionState<'a>, name: &'static str) {
+        let call = self.native_calls.pop().unwrap();
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(call.start);
+        let own = elapsed.saturating_sub(self.counted - call.counted);
+        self.last_tick = now;
+        self.counted += own;
+
+        let id = match self.native_ids.get(name) {
+            Some(&id) => id,
+            None => {
+                self.natives.push(NativeProfile {
+                    name,
+                    ..NativeProfile::default()
+                });
+                self.native_ids.insert(name, self.natives.len() - 1);
+                self.natives.len() - 1
+            }
+        };
+        self.natives[id].calls += 1;
+        self.natives[id].time += own;
+        if let Some(active) = self.stack.last() {
+            let (function, parent) = (active.function, active.node);
+            self.functions[function].native += own;
+            let node = self.child(parent, Frame::Native(id));
+            self.nodes[node].time += own;
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Running source a piece at a time in one state, for an interactive prompt. Each piece is
+//! compiled as a function taking the variables earlier pieces made with `let`, which returns
+//! them again along with the value of its last statement. Modules a piece imports are found
+//! with the state's `resolve` and run first, like they are at startup.
+
+use std::ffi::OsStr;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use super::debug::describe_error;
+use super::linearize::{linearize_file, ResolvedFunction};
+use super::list::List;
+use super::object::{ObjectPtr, PuslObject, Value};
+use super::opcode::OpCode;
+use super::{
+    process_bcf, run_frame_caught, ExecStateRef, ExecuteReturn, ExecutionState, StackFrame,
+};
+use crate::lexer::token::Literal;
+use crate::lexer::{lex, opens_block};
+use crate::parser::expression::{AssignAccess, AssignmentFlags, Expression};
+use crate::parser::{parse, Eval, ExpRef, Import, ParsedFile};
+
+/// Whether more lines are needed to finish the input in `lines`. Input which starts a block
+/// goes on until an empty line, so blocks can hold `else` and `yoink` parts at any depth.
+pub fn is_incomplete<S: AsRef<str>>(lines: &[S]) -> bool {
+    match (lines.first(), lines.last()) {
+        (Some(first), Some(last)) => {
+            opens_block(first.as_ref()) && (lines.len() == 1 || !last.as_ref().trim().is_empty())
+        }
+        _ => false,
+    }
+}
+
+/// The name of the variable a top level statement makes with `let`
+fn let_name(statement: &Eval) -> Option<&str> {
+    match statement {
+        Eval::Expression(Expression::Line { expression, .. }) => let_name(expression),
+        Eval::Expression(Expression::Assigment {
+            target: AssignAccess::Reference { name },
+            flags,
+            ..
+        }) if flags.contains(AssignmentFlags::LET) => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether a top level statement has a value worth showing
+fn has_value(statement: &Eval) -> bool {
+    match statement {
+        Eval::Expression(Expression::Line { expression, .. }) => has_value(expression),
+        Eval::Expression(Expression::Assigment { .. })
+        | Eval::Expression(Expression::Return { .. })
+        | Eval::Expression(Expression::Yeet { .. })
+        | Eval::Expression(Expression::Yield { .. })
+        | Eval::Branch(_) => false,
+        Eval::Expression(_) => true,
+    }
+}
+
+fn expression(expression: Expression) -> ExpRef {
+    Box::new(Eval::Expression(expression))
+}
+
+/// Finds the first name a function or the functions in it use which isn't a builtin
+fn unknown_reference(
+    function: &ResolvedFunction,
+    is_known: &dyn Fn(&str) -> bool,
+) -> Option<String> {
+    for op in &function.code {
+        let reference = match *op {
+            OpCode::PushReference(index) | OpCode::AssignReference(index, _) => {
+                &function.as_ref().references[index]
+            }
+            _ => continue,
+        };
+        if !is_known(reference) {
+            return Some(reference.clone());
+        }
+    }
+    function
+        .sub_functions
+        .iter()
+        .find_map(|sub_function| unknown_reference(sub_function, is_known))
+}
+
+/// The value a frame gave back, or what went wrong running it
+fn returned_value(st: ExecStateRef, result: ExecuteReturn) -> Result<Value, String> {
+    match result {
+        ExecuteReturn::Return(value) => Ok(value),
+        ExecuteReturn::Error(error) => Err(describe_error(&st.borrow(), &error)),
+        ExecuteReturn::LimitExceeded(limit) => Err(format!("{:?} limit exceeded", limit)),
+        ExecuteReturn::Exit(code) => Err(format!("Exited with code {}", code)),
+        other => Err(format!("Unexpected result {:?}", other)),
+    }
+}
+
+/// Runs the module at `path` unless it already has, after the modules it imports. `loading`
+/// holds the modules waiting for their imports to run.
+fn load_module(
+    st: ExecStateRef,
+    path: &[String],
+    loading: &mut Vec<Vec<String>>,
+) -> Result<(), String> {
+    if st.borrow().imports.iter().any(|(loaded, _)| loaded == path) {
+        return Ok(());
+    }
+    if loading.iter().any(|waiting| waiting == path) {
+        return Err(format!("Module {} imports itself", path.join("/")));
+    }
+    let resolve = st.borrow().resolve;
+    // Source is compiled as it's found, and the compiler reports mistakes by panicking
+    let code = panic::catch_unwind(|| resolve(path.to_vec()))
+        .map_err(|_| format!("Unable to compile module {}", path.join("/")))?
+        .ok_or_else(|| format!("Unable to resolve import {}", path.join("/")))?;
+    loading.push(path.to_vec());
+    for import in &code.imports {
+        load_module(st, &import.path, loading)?;
+    }
+    loading.pop();
+
+    let mut frame = {
+        let mut state = st.borrow_mut();
+        let (frame, resolution) = {
+            let ExecutionState {
+                imports,
+                gc,
+                strings,
+                ..
+            } = &mut *state;
+            process_bcf(code, path.to_vec(), imports, gc, strings)
+        };
+        state.module_functions.push(frame.bfunc.target);
+        state.imports.push(resolution);
+        frame
+    };
+    returned_value(st, run_frame_caught(&mut frame, st)?).map(|_| ())
+}
+
+#[derive(Default)]
+pub struct Repl {
+    /// The variables made with `let` so far, in the order they were first made
+    bindings: Vec<(String, Value)>,
+    /// The object for each module imported so far, by alias
+    imports: Vec<(String, ObjectPtr)>,
+    /// What `this` is for every input
+    this: Option<ObjectPtr>,
+    /// The index in the state's modules of the last input, if it can be replaced by the next.
+    /// Input which made functions stays, so the functions can still be found by their module.
+    module: Option<usize>,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl::default()
+    }
+
+    pub fn bindings(&self) -> &[(String, Value)] {
+        &self.bindings
+    }
+
+    /// Runs the modules `imports` name which haven't run yet, and makes their aliases usable
+    /// by this input and the ones after it
+    fn import(&mut self, st: ExecStateRef, imports: Vec<Import>) -> Result<(), String> {
+        for Import { path, alias } in imports {
+            load_module(st, &path, &mut Vec::new())?;
+            let mut state = st.borrow_mut();
+            let module = state
+                .imports
+                .iter()
+                .find(|(loaded, _)| loaded == &path)
+                .map(|(_, object)| object.clone())
+                .expect("The module was just loaded");
+            let object = state.gc.place_in_heap(PuslObject::new_with_parent(module)) as ObjectPtr;
+            self.imports.retain(|(existing, _)| existing != &alias);
+            self.imports.push((alias, object));
+        }
+        Ok(())
+    }
+
+    /// Runs `source` as if it came after everything run before, as a module at `path`. Returns
+    /// the value of the last statement if it's an expression, and null otherwise. An error
+    /// leaves the variables as they were before.
+    pub fn run<'a>(
+        &mut self,
+        st: ExecStateRef<'a>,
+        source: &str,
+        path: &Path,
+    ) -> Result<Value, String> {
+        // The lexer and parser report mistakes by panicking
+        let parsed = panic::catch_unwind(|| parse(lex(source.lines())))
+            .map_err(|_| "Unable to parse input".to_string())?;
+        let ParsedFile { expr, imports } = parsed;
+        let mut statements = match *expr {
+            Eval::Expression(Expression::Joiner { expressions }) => expressions,
+            _ => unreachable!("A parsed file is a list of statements"),
+        };
+        let mut names = self
+            .bindings
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for statement in &statements {
+            if let Some(name) = let_name(statement) {
+                if !names.iter().any(|existing| existing == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        // At the end of the function every name is the outermost variable made last
+        let returned = names
+            .iter()
+            .map(|name| {
+                expression(Expression::Reference {
+                    target: name.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let last = match statements.pop() {
+            Some(last) if has_value(&last) => match *last {
+                Eval::Expression(Expression::Line { number, expression }) => {
+                    Some((number, expression))
+                }
+                _ => unreachable!("Statements are each on a line"),
+            },
+            Some(last) => {
+                statements.push(last);
+                None
+            }
+            None => None,
+        };
+        let give_back = |value: ExpRef| {
+            let values = std::iter::once(value).chain(returned).collect();
+            expression(Expression::Return {
+                value: expression(Expression::ListDeclaration { values }),
+            })
+        };
+        match last {
+            Some((number, value)) => {
+                statements.push(expression(Expression::Line {
+                    number,
+                    expression: give_back(value),
+                }));
+            }
+            None => {
+                let null = expression(Expression::Literal {
+                    value: Literal::Null,
+                });
+                statements.push(give_back(null));
+            }
+        }
+        let declaration = Expression::FunctionDeclaration {
+            binds: Vec::new(),
+            params: self.bindings.iter().map(|(name, _)| name.clone()).collect(),
+            body: expression(Expression::Joiner {
+                expressions: statements,
+            }),
+        };
+        let file = ParsedFile {
+            expr: expression(Expression::Joiner {
+                expressions: vec![expression(declaration)],
+            }),
+            imports: Vec::new(),
+        };
+        let code = panic::catch_unwind(AssertUnwindSafe(|| linearize_file(file)))
+            .map_err(|_| "Unable to compile input".to_string())?;
+        self.import(st, imports)?;
+
+        let (mut frame, rfunc, module) = {
+            let mut state = st.borrow_mut();
+            let state = &mut *state;
+            let rfunc = code.base_func.resolve_with_objects(
+                self.imports.clone(),
+                &mut state.gc,
+                &mut state.strings,
+            );
+            let function = rfunc.get_function(0);
+            let returns = function
+                .code
+                .iter()
+                .filter(|op| matches!(op, OpCode::Return))
+                .count();
+            // Unknown names panic when they are reached, so find them first
+            let builtins = &state.builtins;
+            let imports = &self.imports;
+            let is_known = |name: &str| {
+                builtins.contains_key(name) || imports.iter().any(|(alias, _)| alias == name)
+            };
+            let mistake = if returns != 1 || function.as_ref().is_generator {
+                Some("Only functions can return or yield".to_string())
+            } else {
+                unknown_reference(function, &is_known)
+                    .map(|name| format!("Unknown variable \"{}\"", name))
+            };
+            if let Some(mistake) = mistake {
+                // Nothing has been made from it yet
+                unsafe { ResolvedFunction::free(rfunc) };
+                return Err(mistake);
+            }
+
+            let gc = &mut state.gc;
+            let object = self
+                .this
+                .get_or_insert_with(|| gc.place_in_heap(PuslObject::new()) as ObjectPtr)
+                .clone();
+            let path = path
+                .iter()
+                .map(OsStr::to_string_lossy)
+                .map(String::from)
+                .collect();
+            let module = match self.module {
+                Some(module) => {
+                    let last = mem::replace(&mut state.module_functions[module], rfunc);
+                    state.imports[module] = (path, object.clone());
+                    // Nothing made by the last input refers to it
+                    unsafe { ResolvedFunction::free(last) };
+                    module
+                }
+                None => {
+                    state.module_functions.push(rfunc);
+                    state.imports.push((path, object.clone()));
+                    state.imports.len() - 1
+                }
+            };
+            let mut frame =
+                StackFrame::from_function(function.bind(Vec::new(), &mut state.gc), Some(object));
+            for (slot, (_, value)) in self.bindings.iter().enumerate() {
+                frame.locals[slot] = value.clone();
+            }
+            (frame, rfunc, module)
+        };
+
+        let result = run_frame_caught(&mut frame, st);
+        drop(frame);
+        // Observers can keep pointers to the functions they saw run
+        let is_done_with = rfunc.is_done_with() && st.borrow().observers.is_empty();
+        self.module = is_done_with.then_some(module);
+        let mut values = match returned_value(st, result?)? {
+            Value::Object(list) => {
+                let list = list.borrow();
+                let list = list
+                    .get_native_data()
+                    .downcast_ref::<List>()
+                    .expect("Input returns a list");
+                list.vec.clone()
+            }
+            other => return Err(format!("Unexpected result {:?}", other)),
+        };
+        let value = values.remove(0);
+        self.bindings = names.into_iter().zip(values).collect();
+        Ok(value)
+    }
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// 0 is never given out so an empty `InlineCache` can't match a shape
+static NEXT_SHAPE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Bumped whenever an object that is the super of another object changes its fields or its
+/// own super. Cached lookups that go through the super chain are only valid for one epoch.
+static PROTOTYPE_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+pub fn prototype_epoch() -> u64 {
+    PROTOTYPE_EPOCH.load(Ordering::Relaxed)
+}
+
+pub fn bump_prototype_epoch() {
+    PROTOTYPE_EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The layout of an object's fields (hidden class). Objects with the same super object which
+/// had the same fields added in the same order share a shape, so a shape identifies both
+/// which slot each field is in and which object is next in the super chain.
+pub struct Shape {
+    id: usize,
+    slots: HashMap<String, usize>,
+    transitions: RefCell<HashMap<String, Rc<Shape>>>,
+}
+
+impl Shape {
+    /// A shape with no fields, each super object has its own root
+    pub fn new_root() -> Rc<Shape> {
+        Rc::new(Shape {
+            id: NEXT_SHAPE_ID.fetch_add(1, Ordering::Relaxed),
+            slots: HashMap::new(),
+            transitions: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.slots.iter().map(|(name, slot)| (name.as_str(), *slot))
+    }
+
+    /// The shape after adding a field, which goes in the next slot
+    pub fn with_field(&self, name: &str) -> Rc<Shape> {
+        let mut transitions = self.transitions.borrow_mut();
+        if let Some(shape) = transitions.get(name) {
+            return shape.clone();
+        }
+        let mut slots = self.slots.clone();
+        slots.insert(name.to_string(), self.slots.len());
+        let shape = Rc::new(Shape {
+            id: NEXT_SHAPE_ID.fetch_add(1, Ordering::Relaxed),
+            slots,
+            transitions: RefCell::new(HashMap::new()),
+        });
+        transitions.insert(name.to_string(), shape.clone());
+        shape
+    }
+}
+
+/// Where a field was found: how many super objects up the chain and which slot
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FieldLocation {
+    pub depth: usize,
+    pub slot: usize,
+}
+
+/// Remembers where a `FieldAccess` instruction found its field the last time it ran
+#[derive(Copy, Clone, Debug, Default)]
+pub struct InlineCache {
+    shape: usize,
+    epoch: u64,
+    /// None if the field wasn't found anywhere in the chain
+    location: Option<FieldLocation>,
+}
+
+impl InlineCache {
+    pub fn new(shape: &Shape, location: Option<FieldLocation>) -> Self {
+        InlineCache {
+            shape: shape.id,
+            epoch: prototype_epoch(),
+            location,
+        }
+    }
+
+    /// Where to find the field on an object of this shape, or None if the cache can't say
+    pub fn lookup(&self, shape: &Shape) -> Option<Option<FieldLocation>> {
+        if self.shape != shape.id {
+            return None;
+        }
+        match self.location {
+            // Own fields only depend on the shape
+            Some(location) if location.depth == 0 => Some(Some(location)),
+            location if self.epoch == prototype_epoch() => Some(location),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,821 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use anymap::AnyMap;
+use garbage::ManagedPool;
+use serde::{Deserialize, Serialize};
+
+use super::generator::{Generator, GeneratorBuiltin, IterationEnd};
+use super::linearize::{BasicFunction, ByteCodeFile, ResolvedFunction};
+use super::list::{List, ListBuiltin};
+use super::object::{
+    FnPtr, FunctionTarget, InternTable, ObjectPtr, PuslObject, Registry, StringPtr, Value,
+};
+use super::{assemble_state, new_builtins, ExecContext, ExecutionState, StackFrame, StateParts};
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// A native function is running, and its state is on the native stack
+    InNativeCall,
+    /// A native object which can't be saved
+    UnknownObject(String),
+    /// A native function which isn't registered under this name
+    UnknownNative(String),
+    /// An object builtin which doesn't exist under this name
+    UnknownBuiltin(String),
+    Corrupt(String),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::InNativeCall => write!(f, "Cannot snapshot inside a native function"),
+            SnapshotError::UnknownObject(object) => write!(f, "Cannot snapshot {}", object),
+            SnapshotError::UnknownNative(name) => write!(f, "Unknown native function {}", name),
+            SnapshotError::UnknownBuiltin(name) => write!(f, "Unknown builtin {}", name),
+            SnapshotError::Corrupt(reason) => write!(f, "Snapshot is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Everything needed to carry on executing a paused state. Heap objects are referred to by
+/// their index in `heap`, so shared objects and cycles are kept.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    modules: Vec<ModuleSnapshot>,
+    heap: Vec<HeapEntry>,
+    execution_stack: Vec<FrameSnapshot>,
+    current_frame: FrameSnapshot,
+    /// The modules waiting to run, without their code in a checkpoint
+    resolve_stack: Vec<(Vec<String>, Option<ByteCodeFile>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModuleSnapshot {
+    path: Vec<String>,
+    object: ObjectRef,
+    /// Left out of a checkpoint, the state rewound to it still has the module
+    function: Option<BasicFunction>,
+    /// The object made for each of the module's imports, by alias
+    imports: Vec<(String, ObjectRef)>,
+}
+
+/// Builtin objects are made fresh by every state, so they're saved by name
+#[derive(Serialize, Deserialize)]
+enum ObjectRef {
+    Heap(usize),
+    Builtin(String),
+}
+
+#[derive(Serialize, Deserialize)]
+enum ValueSnapshot {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(usize),
+    Function(usize, Option<ObjectRef>),
+    Native(String, Option<ObjectRef>),
+    Object(ObjectRef),
+}
+
+/// The module a function is in and the sub function indexes leading to it from the module's
+/// base function
+#[derive(Serialize, Deserialize)]
+struct FunctionId {
+    module: usize,
+    path: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FrameSnapshot {
+    this_obj: Option<ObjectRef>,
+    function: usize,
+    locals: Vec<ValueSnapshot>,
+    op_stack: Vec<ValueSnapshot>,
+    index: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+enum HeapEntry {
+    String(String),
+    Function {
+        function: FunctionId,
+        bound_values: Vec<ValueSnapshot>,
+    },
+    Object {
+        super_ptr: Option<ObjectRef>,
+        fields: Vec<(String, ValueSnapshot)>,
+    },
+    List(Vec<ValueSnapshot>),
+    Generator {
+        frame: Option<FrameSnapshot>,
+        next_val: Option<ValueSnapshot>,
+    },
+    IterationEnd,
+}
+
+fn address<T: ?Sized>(value: &T) -> *const () {
+    value as *const T as *const ()
+}
+
+/// Saves a state which isn't running so it can be restored later, possibly in another process
+pub fn snapshot(state: &ExecutionState) -> Result<Vec<u8>, SnapshotError> {
+    write(state, true)
+}
+
+/// Saves a state like `snapshot` but without any code, for a recording to rewind the same state
+/// to with `rewind_checkpoint`. A state only gains modules, and the ones it gains were waiting
+/// to run when recording started, so the recording keeps their code once instead.
+pub(super) fn checkpoint(state: &ExecutionState) -> Result<Vec<u8>, SnapshotError> {
+    write(state, false)
+}
+
+fn write(state: &ExecutionState, with_code: bool) -> Result<Vec<u8>, SnapshotError> {
+    if state.reentrant_depth > 0 {
+        return Err(SnapshotError::InNativeCall);
+    }
+    let mut writer = Writer::new(state);
+
+    let mut modules = Vec::new();
+    for ((path, object), rfunc) in state.imports.iter().zip(&state.module_functions) {
+        let imports = rfunc
+            .imports
+            .iter()
+            .map(|(alias, object)| (alias.clone(), writer.object(object)))
+            .collect();
+        modules.push(ModuleSnapshot {
+            path: path.clone(),
+            object: writer.object(object),
+            function: with_code.then(|| rfunc.to_basic()),
+            imports,
+        });
+    }
+    let execution_stack = state
+        .execution_stack
+        .iter()
+        .map(|frame| writer.frame(frame))
+        .collect::<Result<_, _>>()?;
+    let current_frame = writer.frame(&state.current_frame)?;
+    writer.write_pending()?;
+
+    let snapshot = Snapshot {
+        modules,
+        heap: writer.heap.into_iter().map(Option::unwrap).collect(),
+        execution_stack,
+        current_frame,
+        resolve_stack: state
+            .resolve_stack
+            .iter()
+            .map(|(path, bcf)| (path.clone(), with_code.then(|| bcf.clone())))
+            .collect(),
+    };
+    bincode::serialize(&snapshot).map_err(|err| SnapshotError::Corrupt(err.to_string()))
+}
+
+/// A state which carries on from where the snapshot was taken, with the settings from `ctx`
+pub fn restore<'a>(
+    bytes: &[u8],
+    ctx: ExecContext<'a>,
+) -> Result<RefCell<ExecutionState<'a>>, SnapshotError> {
+    let mut base = Base {
+        gc: ManagedPool::new(),
+        strings: InternTable::new(),
+        modules: Vec::new(),
+    };
+    let parts = read(deserialize(bytes)?, &ctx.args, &mut base, &[])?;
+    Ok(RefCell::new(assemble_state(parts, ctx)))
+}
+
+/// Puts a state back to where the snapshot was taken. Unlike `restore` it keeps the state's own
+/// settings, stream, limits used so far and recorded inputs.
+pub fn rewind(state: &mut ExecutionState, bytes: &[u8]) -> Result<(), SnapshotError> {
+    rewind_with(state, deserialize(bytes)?, &[])
+}
+
+/// Puts a state back to a checkpoint made by `checkpoint`, with `waiting` the modules which
+/// were waiting to run when recording started
+pub(super) fn rewind_checkpoint(
+    state: &mut ExecutionState,
+    bytes: &[u8],
+    waiting: &[(Vec<String>, ByteCodeFile)],
+) -> Result<(), SnapshotError> {
+    rewind_with(state, deserialize(bytes)?, waiting)
+}
+
+fn deserialize(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+    bincode::deserialize(bytes).map_err(|err| SnapshotError::Corrupt(err.to_string()))
+}
+
+/// The snapshot is read into the state's own heap, so the modules the state already has are
+/// used again rather than resolved again, along with the strings their code interned. What the
+/// state had that the snapshot doesn't is collected afterwards.
+fn rewind_with(
+    state: &mut ExecutionState,
+    snapshot: Snapshot,
+    waiting: &[(Vec<String>, ByteCodeFile)],
+) -> Result<(), SnapshotError> {
+    let mut base = Base {
+        gc: std::mem::take(&mut state.gc),
+        strings: std::mem::take(&mut state.strings),
+        modules: state
+            .imports
+            .iter()
+            .map(|(path, _)| path.as_slice())
+            .zip(state.module_functions.iter().copied())
+            .collect(),
+    };
+    let parts = read(snapshot, &state.args, &mut base, waiting);
+    let StateParts {
+        imports,
+        module_functions,
+        execution_stack,
+        current_frame,
+        resolve_stack,
+        mut gc,
+        strings,
+        builtins,
+        builtin_data,
+        registry,
+    } = match parts {
+        Ok(parts) => parts,
+        Err(err) => {
+            // Objects made before the mistake was found are freed by the next collection
+            state.gc = base.gc;
+            state.strings = base.strings;
+            return Err(err);
+        }
+    };
+    state.execution_stack = execution_stack;
+    state.current_frame = current_frame;
+    state.imports = imports;
+    let old_functions = std::mem::replace(&mut state.module_functions, module_functions);
+    state.resolve_stack = resolve_stack;
+    state.strings = strings;
+    state.builtins = builtins;
+    state.builtin_data = builtin_data;
+    state.registry = registry;
+    state.reentrant_depth = 0;
+    state.call_depth_base = 0;
+    state.pending_error = None;
+    state.exceeded = None;
+    state.exit_code = None;
+    gc.collect_from(&*state);
+    state.gc = gc;
+    state.observed_heap = state.gc.size();
+    // Modules loaded after the snapshot was taken can't be reached any more, but observers can
+    // keep pointers to the functions they saw run
+    if state.observers.is_empty() {
+        for function in old_functions {
+            let is_kept = state
+                .module_functions
+                .iter()
+                .any(|kept| std::ptr::eq(*kept, function));
+            if !is_kept {
+                unsafe { ResolvedFunction::free(function) };
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a snapshot is read on top of, a new heap or the heap of a state being rewound
+struct Base<'m> {
+    gc: ManagedPool,
+    strings: InternTable,
+    /// Modules by path, used again for each of the snapshot's first modules with the same path
+    /// and imports rather than resolved from the snapshot's code
+    modules: Vec<(&'m [String], &'static ResolvedFunction)>,
+}
+
+/// The parts of the state in the snapshot, with the `args` builtin made from `args`. Code left
+/// out of the snapshot comes from `base` or, for modules waiting to run, from `waiting`.
+fn read<'a>(
+    snapshot: Snapshot,
+    args: &[String],
+    base: &mut Base,
+    waiting: &[(Vec<String>, ByteCodeFile)],
+) -> Result<StateParts<'a>, SnapshotError> {
+    let Snapshot {
+        modules,
+        heap,
+        execution_stack,
+        current_frame,
+        resolve_stack,
+    } = snapshot;
+    let resolve_stack = resolve_stack
+        .into_iter()
+        .enumerate()
+        .map(|(index, (path, bcf))| {
+            let bcf = match bcf {
+                Some(bcf) => bcf,
+                None => waiting
+                    .get(index)
+                    .filter(|(waiting, _)| waiting == &path)
+                    .map(|(_, bcf)| bcf.clone())
+                    .ok_or_else(|| missing_code(&path))?,
+            };
+            Ok((path, bcf))
+        })
+        .collect::<Result<_, SnapshotError>>()?;
+
+    let gc = &mut base.gc;
+    let (builtins, builtin_data, registry) = new_builtins(gc, args);
+    let mut reader = Reader::new(heap, &builtins, &builtin_data, &registry, gc);
+
+    let mut imports = Vec::new();
+    let mut is_same = true;
+    for (index, module) in modules.into_iter().enumerate() {
+        let object = reader.object(&module.object)?;
+        let kept = base
+            .modules
+            .get(index)
+            .filter(|(path, kept)| is_same_module(path, kept, &module))
+            .map(|&(_, kept)| kept);
+        // Everything after a module that changed has to be resolved again too
+        is_same &= kept.is_some();
+        let rfunc = match kept {
+            Some(kept) if is_same => {
+                // The kept function points at the objects it was given, so they're emptied and
+                // filled in from the snapshot
+                for ((_, object), (_, id)) in kept.imports.iter().zip(&module.imports) {
+                    if let ObjectRef::Heap(id) = id {
+                        reader.reuse(*id, object)?;
+                    }
+                }
+                kept
+            }
+            _ => {
+                let import_objects = module
+                    .imports
+                    .iter()
+                    .map(|(alias, object)| Ok((alias.clone(), reader.object(object)?)))
+                    .collect::<Result<_, SnapshotError>>()?;
+                let path = &module.path;
+                let function = module.function.ok_or_else(|| missing_code(path))?;
+                function.resolve_with_objects(import_objects, reader.gc, &mut base.strings)
+            }
+        };
+        reader.modules.push(rfunc);
+        imports.push((module.path, object));
+    }
+    reader.read_heap()?;
+    let execution_stack = execution_stack
+        .iter()
+        .map(|frame| reader.frame(frame))
+        .collect::<Result<_, _>>()?;
+    let current_frame = reader.frame(&current_frame)?;
+    let module_functions = reader.modules;
+
+    Ok(StateParts {
+        imports,
+        module_functions,
+        execution_stack,
+        current_frame,
+        resolve_stack,
+        gc: std::mem::take(&mut base.gc),
+        strings: std::mem::take(&mut base.strings),
+        builtins,
+        builtin_data,
+        registry,
+    })
+}
+
+/// Whether a function the state has can be used for the module in a snapshot
+fn is_same_module(path: &[String], kept: &ResolvedFunction, module: &ModuleSnapshot) -> bool {
+    path == module.path.as_slice()
+        && kept.imports.len() == module.imports.len()
+        && kept
+            .imports
+            .iter()
+            .zip(&module.imports)
+            .all(|((kept, _), (alias, object))| {
+                kept == alias && matches!(object, ObjectRef::Heap(_))
+            })
+}
+
+fn missing_code(path: &[String]) -> SnapshotError {
+    SnapshotError::Corrupt(format!("the code of {} was left out", path.join("/")))
+}
+
+enum Pending {
+    Object(ObjectPtr, usize),
+    Function(FnPtr, usize),
+}
+
+struct Writer<'s, 'a> {
+    state: &'s ExecutionState<'a>,
+    /// None until the object has been written
+    heap: Vec<Option<HeapEntry>>,
+    ids: HashMap<*const (), usize>,
+    /// Objects which have an id but haven't been written yet
+    pending: Vec<Pending>,
+    functions: HashMap<*const (), FunctionId>,
+    builtins: HashMap<*const (), &'static str>,
+}
+
+impl<'s, 'a> Writer<'s, 'a> {
+    fn new(state: &'s ExecutionState<'a>) -> Self {
+        let mut functions = HashMap::new();
+        for (module, rfunc) in state.module_functions.iter().enumerate() {
+            let mut to_visit = vec![(*rfunc, Vec::new())];
+            while let Some((rfunc, path)) = to_visit.pop() {
+                for (index, sub_function) in rfunc.sub_functions.iter().enumerate() {
+                    let mut sub_path = path.clone();
+                    sub_path.push(index);
+                    to_visit.push((sub_function, sub_path));
+                }
+                functions.insert(address(rfunc), FunctionId { module, path });
+            }
+        }
+        let builtins = state
+            .builtins
+            .iter()
+            .filter_map(|(name, value)| match value {
+                Value::Object(object) => Some((address(&**object), *name)),
+                _ => None,
+            })
+            .collect();
+        Writer {
+            state,
+            heap: Vec::new(),
+            ids: HashMap::new(),
+            pending: Vec::new(),
+            functions,
+            builtins,
+        }
+    }
+
+    /// The id for a heap value, and whether it was just given one
+    fn id_of(&mut self, address: *const ()) -> (usize, bool) {
+        if let Some(id) = self.ids.get(&address) {
+            return (*id, false);
+        }
+        let id = self.heap.len();
+        self.heap.push(None);
+        self.ids.insert(address, id);
+        (id, true)
+    }
+
+    fn object(&mut self, object: &ObjectPtr) -> ObjectRef {
+        if let Some(name) = self.builtins.get(&address(&**object)) {
+            return ObjectRef::Builtin(name.to_string());
+        }
+        let (id, is_new) = self.id_of(address(&**object));
+        if is_new {
+            self.pending.push(Pending::Object(object.clone(), id));
+        }
+        ObjectRef::Heap(id)
+    }
+
+    fn string(&mut self, string: &StringPtr) -> usize {
+        let (id, is_new) = self.id_of(address(&**string));
+        if is_new {
+            self.heap[id] = Some(HeapEntry::String(string.to_string()));
+        }
+        id
+    }
+
+    fn function(&mut self, function: &FnPtr) -> usize {
+        let (id, is_new) = self.id_of(address(&**function));
+        if is_new {
+            self.pending.push(Pending::Function(function.clone(), id));
+        }
+        id
+    }
+
+    fn value(&mut self, value: &Value) -> Result<ValueSnapshot, SnapshotError> {
+        let snapshot = match value {
+            Value::Null => ValueSnapshot::Null,
+            Value::Boolean(value) => ValueSnapshot::Boolean(*value),
+            Value::Integer(value) => ValueSnapshot::Integer(*value),
+            Value::Float(value) => ValueSnapshot::Float(*value),
+            Value::String(string) => ValueSnapshot::String(self.string(string)),
+            Value::Function((target, this)) => {
+                let this = this.as_ref().map(|this| self.object(this));
+                match target {
+                    FunctionTarget::Pusl(function) => {
+                        ValueSnapshot::Function(self.function(function), this)
+                    }
+                    FunctionTarget::Native(handle) => {
+                        let (name, _) = self.state.registry.get(*handle).ok_or_else(|| {
+                            SnapshotError::UnknownNative(format!("handle {}", handle))
+                        })?;
+                        ValueSnapshot::Native(name.to_string(), this)
+                    }
+                }
+            }
+            Value::Object(object) => ValueSnapshot::Object(self.object(object)),
+        };
+        Ok(snapshot)
+    }
+
+    fn values(&mut self, values: &[Value]) -> Result<Vec<ValueSnapshot>, SnapshotError> {
+        values.iter().map(|value| self.value(value)).collect()
+    }
+
+    fn frame(&mut self, frame: &StackFrame) -> Result<FrameSnapshot, SnapshotError> {
+        Ok(FrameSnapshot {
+            this_obj: frame.this_obj.as_ref().map(|this| self.object(this)),
+            function: self.function(&frame.bfunc),
+            locals: self.values(&frame.locals)?,
+            op_stack: self.values(&frame.op_stack)?,
+            index: frame.index,
+        })
+    }
+
+    fn write_pending(&mut self) -> Result<(), SnapshotError> {
+        while let Some(pending) = self.pending.pop() {
+            let (entry, id) = match pending {
+                Pending::Object(object, id) => (self.object_entry(&object)?, id),
+                Pending::Function(function, id) => {
+                    let target =
+                        self.functions
+                            .get(&address(function.target))
+                            .ok_or_else(|| {
+                                SnapshotError::Corrupt("function is not in any module".to_string())
+                            })?;
+                    let function_id = FunctionId {
+                        module: target.module,
+                        path: target.path.clone(),
+                    };
+                    let entry = HeapEntry::Function {
+                        function: function_id,
+                        bound_values: self.values(&function.bound_values)?,
+                    };
+                    (entry, id)
+                }
+            };
+            self.heap[id] = Some(entry);
+        }
+        Ok(())
+    }
+
+    fn object_entry(&mut self, object: &ObjectPtr) -> Result<HeapEntry, SnapshotError> {
+        let borrow = object.borrow();
+        let data = borrow.get_native_data();
+        let entry = if let Some(pusl_obj) = data.downcast_ref::<PuslObject>() {
+            let super_ptr = pusl_obj.super_ptr().map(|super_ptr| self.object(super_ptr));
+            let fields = pusl_obj
+                .own_fields()
+                .into_iter()
+                .map(|(name, value)| Ok((name.to_string(), self.value(value)?)))
+                .collect::<Result<_, SnapshotError>>()?;
+            HeapEntry::Object { super_ptr, fields }
+        } else if let Some(list) = data.downcast_ref::<List>() {
+            HeapEntry::List(self.values(&list.vec)?)
+        } else if let Some(generator) = data.downcast_ref::<Generator>() {
+            let frame = match &generator.stack {
+                Some(frame) => Some(self.frame(frame)?),
+                None => None,
+            };
+            let next_val = match &generator.next_val {
+                Some(value) => Some(self.value(value)?),
+                None => None,
+            };
+            HeapEntry::Generator { frame, next_val }
+        } else if data.is::<IterationEnd>() {
+            HeapEntry::IterationEnd
+        } else {
+            return Err(SnapshotError::UnknownObject(format!("{:?}", borrow)));
+        };
+        Ok(entry)
+    }
+}
+
+enum Slot {
+    String(StringPtr),
+    Object(ObjectPtr),
+    /// None until it's been made
+    Function(Option<FnPtr>),
+}
+
+struct Reader<'r, 'a> {
+    /// Taken once each object has been filled in
+    entries: Vec<Option<HeapEntry>>,
+    slots: Vec<Slot>,
+    modules: Vec<&'static ResolvedFunction>,
+    builtins: &'r HashMap<&'static str, Value>,
+    registry: &'r Registry<'a>,
+    gc: &'r mut ManagedPool,
+}
+
+impl<'r, 'a> Reader<'r, 'a> {
+    /// Makes every object empty first so references between them, including cycles, can be
+    /// filled in afterwards
+    fn new(
+        heap: Vec<HeapEntry>,
+        builtins: &'r HashMap<&'static str, Value>,
+        builtin_data: &AnyMap,
+        registry: &'r Registry<'a>,
+        gc: &'r mut ManagedPool,
+    ) -> Self {
+        let list_table = *builtin_data
+            .get::<ListBuiltin>()
+            .expect("List Builtins are not loaded");
+        let generator_table = *builtin_data
+            .get::<GeneratorBuiltin>()
+            .expect("Generator Builtins are not loaded");
+        let slots = heap
+            .iter()
+            .map(|entry| match entry {
+                HeapEntry::String(string) => Slot::String(gc.place_in_heap(string.clone())),
+                HeapEntry::Function { .. } => Slot::Function(None),
+                HeapEntry::Object { .. } => Slot::Object(gc.place_in_heap(PuslObject::new())),
+                HeapEntry::List(_) => Slot::Object(gc.place_in_heap(RefCell::new(List {
+                    vec: Vec::new(),
+                    fn_table: list_table,
+                }))),
+                HeapEntry::Generator { .. } => {
+                    Slot::Object(gc.place_in_heap(RefCell::new(Generator {
+                        stack: None,
+                        next_val: None,
+                        fn_table: generator_table,
+                    })))
+                }
+                HeapEntry::IterationEnd => {
+                    Slot::Object(gc.place_in_heap(RefCell::new(IterationEnd)))
+                }
+            })
+            .collect();
+        Reader {
+            entries: heap.into_iter().map(Some).collect(),
+            slots,
+            modules: Vec::new(),
+            builtins,
+            registry,
+            gc,
+        }
+    }
+
+    /// Uses an object which already exists as the one at `id`, emptied so it can be filled in
+    fn reuse(&mut self, id: usize, object: &ObjectPtr) -> Result<(), SnapshotError> {
+        match self.entries.get(id) {
+            Some(Some(HeapEntry::Object { .. })) => {}
+            _ => return Err(SnapshotError::Corrupt(format!("{} is not an object", id))),
+        }
+        let mut borrow = object.borrow_mut();
+        let pusl_obj = borrow
+            .get_native_data_mut()
+            .downcast_mut::<PuslObject>()
+            .ok_or_else(|| SnapshotError::Corrupt("an import is not an object".to_string()))?;
+        *pusl_obj = PuslObject::new().into_inner();
+        drop(borrow);
+        self.slots[id] = Slot::Object(object.clone());
+        Ok(())
+    }
+
+    fn object(&self, object: &ObjectRef) -> Result<ObjectPtr, SnapshotError> {
+        match object {
+            ObjectRef::Heap(id) => match self.slots.get(*id) {
+                Some(Slot::Object(object)) => Ok(object.clone()),
+                _ => Err(SnapshotError::Corrupt(format!("{} is not an object", id))),
+            },
+            ObjectRef::Builtin(name) => match self.builtins.get(name.as_str()) {
+                Some(Value::Object(object)) => Ok(object.clone()),
+                _ => Err(SnapshotError::UnknownBuiltin(name.clone())),
+            },
+        }
+    }
+
+    fn function(&mut self, id: usize) -> Result<FnPtr, SnapshotError> {
+        match self.slots.get(id) {
+            Some(Slot::Function(Some(function))) => return Ok(function.clone()),
+            Some(Slot::Function(None)) => {}
+            _ => return Err(SnapshotError::Corrupt(format!("{} is not a function", id))),
+        }
+        let (function_id, bound_values) = match self.entries[id].take() {
+            Some(HeapEntry::Function {
+                function,
+                bound_values,
+            }) => (function, bound_values),
+            _ => {
+                return Err(SnapshotError::Corrupt(format!(
+                    "function {} is bound to itself",
+                    id
+                )))
+            }
+        };
+        let bound_values = self.values(&bound_values)?;
+        let mut target = *self
+            .modules
+            .get(function_id.module)
+            .ok_or_else(|| SnapshotError::Corrupt(format!("no module {}", function_id.module)))?;
+        for index in function_id.path {
+            target = target
+                .sub_functions
+                .get(index)
+                .ok_or_else(|| SnapshotError::Corrupt(format!("no sub function {}", index)))?;
+        }
+        let function = target.bind(bound_values, self.gc);
+        self.slots[id] = Slot::Function(Some(function.clone()));
+        Ok(function)
+    }
+
+    fn value(&mut self, value: &ValueSnapshot) -> Result<Value, SnapshotError> {
+        let value = match value {
+            ValueSnapshot::Null => Value::Null,
+            ValueSnapshot::Boolean(value) => Value::Boolean(*value),
+            ValueSnapshot::Integer(value) => Value::Integer(*value),
+            ValueSnapshot::Float(value) => Value::Float(*value),
+            ValueSnapshot::String(id) => match self.slots.get(*id) {
+                Some(Slot::String(string)) => Value::String(string.clone()),
+                _ => return Err(SnapshotError::Corrupt(format!("{} is not a string", id))),
+            },
+            ValueSnapshot::Function(id, this) => {
+                let this = this.as_ref().map(|this| self.object(this)).transpose()?;
+                Value::Function((FunctionTarget::Pusl(self.function(*id)?), this))
+            }
+            ValueSnapshot::Native(name, this) => {
+                let this = this.as_ref().map(|this| self.object(this)).transpose()?;
+                let handle = self
+                    .registry
+                    .iter()
+                    .position(|(registered, _)| registered == name)
+                    .ok_or_else(|| SnapshotError::UnknownNative(name.clone()))?;
+                Value::Function((FunctionTarget::Native(handle), this))
+            }
+            ValueSnapshot::Object(object) => Value::Object(self.object(object)?),
+        };
+        Ok(value)
+    }
+
+    fn values(&mut self, values: &[ValueSnapshot]) -> Result<Vec<Value>, SnapshotError> {
+        values.iter().map(|value| self.value(value)).collect()
+    }
+
+    fn frame(&mut self, frame: &FrameSnapshot) -> Result<StackFrame, SnapshotError> {
+        let bfunc = self.function(frame.function)?;
+        let this_obj = frame
+            .this_obj
+            .as_ref()
+            .map(|this| self.object(this))
+            .transpose()?;
+        let locals = self.values(&frame.locals)?;
+        if locals.len() != bfunc.target.as_ref().locals.len()
+            || frame.index > bfunc.target.code.len()
+        {
+            return Err(SnapshotError::Corrupt(
+                "frame doesn't match its function".to_string(),
+            ));
+        }
+        Ok(StackFrame {
+            this_obj,
+            bfunc,
+            locals,
+            op_stack: self.values(&frame.op_stack)?,
+            index: frame.index,
+        })
+    }
+
+    /// Fills in every object made by `new`
+    fn read_heap(&mut self) -> Result<(), SnapshotError> {
+        for id in 0..self.entries.len() {
+            if let Some(HeapEntry::Function { .. }) = self.entries[id] {
+                self.function(id)?;
+                continue;
+            }
+            let object = match &self.slots[id] {
+                Slot::Object(object) => object.clone(),
+                _ => continue,
+            };
+            match self.entries[id].take() {
+                Some(HeapEntry::Object { super_ptr, fields }) => {
+                    let mut borrow = object.borrow_mut();
+                    if let Some(super_ptr) = super_ptr {
+                        borrow.assign_field("super", Value::Object(self.object(&super_ptr)?), true);
+                    }
+                    for (name, value) in fields {
+                        borrow.assign_field(&name, self.value(&value)?, true);
+                    }
+                }
+                Some(HeapEntry::List(values)) => {
+                    let values = self.values(&values)?;
+                    let mut borrow = object.borrow_mut();
+                    let list = borrow.get_native_data_mut().downcast_mut::<List>().unwrap();
+                    list.vec = values;
+                }
+                Some(HeapEntry::Generator { frame, next_val }) => {
+                    let frame = frame.as_ref().map(|frame| self.frame(frame)).transpose()?;
+                    let next_val = next_val
+                        .as_ref()
+                        .map(|value| self.value(value))
+                        .transpose()?;
+                    let mut borrow = object.borrow_mut();
+                    let generator = borrow
+                        .get_native_data_mut()
+                        .downcast_mut::<Generator>()
+                        .unwrap();
+                    generator.stack = frame;
+                    generator.next_val = next_val;
+                }
+                _ => {}
+            }
+            self.gc.resize(&object);
+        }
+        Ok(())
+    }
+}
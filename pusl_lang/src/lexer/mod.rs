@@ -47,12 +47,15 @@ where
     I: IntoIterator<Item = &'a str>,
 {
     let iter = lines.into_iter();
-    let indent_iter = iter.map(lex_line).filter(|(line, _)| !line.is_empty());
+    let indent_iter = iter
+        .map(lex_line)
+        .zip(1..)
+        .filter(|((line, _), _)| !line.is_empty());
     let mut last_indent = Vec::new();
     let mut lines = Vec::new();
-    for (tokens, indent_tokens) in indent_iter {
+    for ((tokens, indent_tokens), line_number) in indent_iter {
         assert!(IndentChar::compare(&last_indent, &indent_tokens).is_some());
-        lines.push((tokens, indent_tokens.len()));
+        lines.push((tokens, indent_tokens.len(), line_number));
         last_indent = indent_tokens;
     }
 
@@ -66,22 +69,28 @@ where
     roots
 }
 
+/// Whether a line starts a block, which the more indented lines after it belong to
+pub fn opens_block(line: &str) -> bool {
+    let (tokens, _) = lex_line(line);
+    tokens.last() == Some(&Token::Symbol(Symbol::Colon))
+}
+
 fn lex_internal<I>(stream: &mut Peekable<I>) -> Option<LexUnit>
 where
-    I: Iterator<Item = (Vec<Token>, usize)>,
+    I: Iterator<Item = (Vec<Token>, usize, usize)>,
 {
-    if let Some((tokens, indentation)) = stream.next() {
+    if let Some((tokens, indentation, line_number)) = stream.next() {
         let mut children = Vec::new();
         let mut children_indent: Option<usize> = None;
         while stream
             .peek()
-            .map_or(false, |(_, child_indent)| indentation < *child_indent)
+            .map_or(false, |(_, child_indent, _)| indentation < *child_indent)
         {
             if let Some(children_indent) = children_indent {
-                let (_, this_indent) = stream.peek().unwrap();
+                let (_, this_indent, _) = stream.peek().unwrap();
                 assert_eq!(children_indent, *this_indent);
             } else {
-                let (_, this_indent) = stream.peek().unwrap();
+                let (_, this_indent, _) = stream.peek().unwrap();
                 children_indent = Some(*this_indent);
             }
             if let Some(child) = lex_internal(stream) {
@@ -105,19 +114,21 @@ where
                 Some(LexUnit::Block(Block {
                     kind: return_type,
                     line: tokens,
+                    line_number,
                     children,
                 }))
             } else if tokens.contains(&Token::Keyword(Keyword::Fn)) {
                 Some(LexUnit::Block(Block {
                     kind: BlockType::Function,
                     line: tokens,
+                    line_number,
                     children,
                 }))
             } else {
                 panic!("Unrecognized Block Type");
             }
         } else {
-            Some(LexUnit::Statement(tokens))
+            Some(LexUnit::Statement(tokens, line_number))
         }
     } else {
         None
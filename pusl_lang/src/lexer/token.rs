@@ -72,9 +72,10 @@ pub enum BlockType {
     Yoink,
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub enum LexUnit {
-    Statement(Vec<Token>),
+    /// The tokens on a line and its line number
+    Statement(Vec<Token>, usize),
     Block(Block),
 }
 
@@ -84,24 +85,33 @@ impl Debug for LexUnit {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Block {
     pub kind: BlockType,
     pub line: Vec<Token>,
+    /// The source line the block starts on, counting from 1
+    pub line_number: usize,
     pub children: Vec<LexUnit>,
 }
 
 impl LexUnit {
     pub fn get_tokens(&self) -> &Vec<Token> {
         match self {
-            LexUnit::Statement(tokens) => tokens,
+            LexUnit::Statement(tokens, _) => tokens,
             LexUnit::Block(block) => block.get_tokens(),
         }
     }
 
+    pub fn get_line_number(&self) -> usize {
+        match self {
+            LexUnit::Statement(_, line_number) => *line_number,
+            LexUnit::Block(block) => block.line_number,
+        }
+    }
+
     pub fn fmt_indent(&self, f: &mut Formatter<'_>, indent: usize) -> fmt::Result {
         match self {
-            LexUnit::Statement(tokens) => {
+            LexUnit::Statement(tokens, _) => {
                 for _ in 0..indent {
                     write!(f, "\t")?;
                 }
@@ -135,6 +135,12 @@ pub enum Expression {
     },
 
     SelfReference,
+
+    /// Code which starts on a source line
+    Line {
+        number: usize,
+        expression: ExpRef,
+    },
 }
 
 bitflags! {
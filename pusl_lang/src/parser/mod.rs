@@ -29,7 +29,7 @@ pub struct ParsedFile {
     pub imports: Vec<Import>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Import {
     pub path: Vec<String>,
     pub alias: String,
@@ -43,9 +43,9 @@ where
 {
     let mut iter = source.into_iter().peekable();
     let mut imports = Vec::new();
-    while let Some(LexUnit::Statement(tokens)) = iter.peek() {
+    while let Some(LexUnit::Statement(tokens, _)) = iter.peek() {
         if let Some(&Token::Keyword(Keyword::Import)) = tokens.first() {
-            if let Some(LexUnit::Statement(tokens)) = iter.next() {
+            if let Some(LexUnit::Statement(tokens, _)) = iter.next() {
                 let import = parse_import(tokens);
                 imports.push(import);
             } else {
@@ -105,10 +105,12 @@ fn parse_lex_unit<I>(unit: LexUnit, stream: &mut Peekable<I>) -> ExpRef
 where
     I: Iterator<Item = LexUnit>,
 {
-    match unit {
+    let number = unit.get_line_number();
+    let expression = match unit {
         LexUnit::Block(block) => parse_branch(block, stream),
-        LexUnit::Statement(tokens) => parse_statement(tokens),
-    }
+        LexUnit::Statement(tokens, _) => parse_statement(tokens),
+    };
+    Box::new(Eval::Expression(Expression::Line { number, expression }))
 }
 
 /// Parse a while loop
@@ -218,7 +220,13 @@ where
         }
     }) {
         if let Some(LexUnit::Block(elif_block)) = block_stream.next() {
+            let number = elif_block.line_number;
             let (elif_condition, elif_body) = parse_condition_body(elif_block, &mut elif_func);
+            // The condition is evaluated as part of the if statement, but is on its own line
+            let elif_condition = Box::new(Eval::Expression(Expression::Line {
+                number,
+                expression: elif_condition,
+            }));
             conditions.push(ConditionBody {
                 condition: elif_condition,
                 body: elif_body,
@@ -0,0 +1,57 @@
+mod test_util;
+
+use pusl_lang::backend::assembly::{assemble, disassemble};
+use pusl_lang::backend::linearize::linearize_file;
+use pusl_lang::backend::{execute, startup, ExecContext};
+use pusl_lang::lexer::lex;
+use pusl_lang::parser::parse;
+use std::path::PathBuf;
+use test_util::check_bcf_equal;
+
+fn round_trip(source: &str) {
+    let code = linearize_file(parse(lex(source.lines())));
+    let text = disassemble(&code);
+    let assembled = assemble(&text).expect("Disassembly should assemble");
+
+    check_bcf_equal(&code, &assembled);
+    assert_eq!(text, disassemble(&assembled));
+}
+
+#[test]
+fn round_trip_test() {
+    round_trip(include_str!("../../resources/simple_program.pusl"));
+    round_trip(include_str!("../../resources/secondary_source.pusl"));
+    round_trip(include_str!("../../resources/generator.pusl"));
+    round_trip(include_str!("../../resources/yoink_filter.pusl"));
+    round_trip(include_str!("../../resources/errors.pusl"));
+}
+
+#[test]
+fn run_assembled_test() {
+    let code = assemble(include_str!("../../resources/asm/countdown.pasm"))
+        .expect("Assembly should be valid");
+    let path = PathBuf::from("countdown.pasm");
+
+    let mut ctx = ExecContext::default();
+    let mut output = Vec::new();
+    ctx.stream = Some(&mut output);
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    assert_eq!(actual, "3\n2\n1\nliftoff\n");
+}
+
+#[test]
+fn assemble_error_test() {
+    let err = assemble(".function\n    Jump nowhere\n.end\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert_eq!(err.message, "undefined label 'nowhere'");
+
+    let err = assemble(".function\n    Literal 0\n.end\n").unwrap_err();
+    assert_eq!(err.line, 2);
+
+    let err = assemble(".function\n    Frobnicate\n.end\n").unwrap_err();
+    assert_eq!(err.message, "unknown instruction 'Frobnicate'");
+}
@@ -0,0 +1,96 @@
+use pusl_lang::backend::check::{check, Diagnostic, Severity};
+
+/// Checks `source` where only imports of `found` resolve, as (line, column, code) for each
+/// diagnostic
+fn check_source(source: &str) -> Vec<(usize, usize, &'static str)> {
+    let diagnostics = check(source, &|path| path.join(".") == "found");
+    diagnostics
+        .iter()
+        .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.code))
+        .collect()
+}
+
+#[test]
+fn check_lex_test() {
+    let source = "let x = 5 $ 3\nprintln(x)\n  let y = ?\n";
+    assert_eq!(
+        check_source(source),
+        [(1, 11, "lex-error"), (3, 11, "lex-error")]
+    );
+}
+
+#[test]
+fn check_block_test() {
+    let source = "if true:\n    1\n      2\nlet a = 1\n";
+    assert_eq!(check_source(source), [(3, 7, "block-error")]);
+}
+
+#[test]
+fn check_parse_test() {
+    let source = "\
+let f = fn(a):
+    if a:
+        let = 3
+    return a
+let y = (1 +
+println(1)
+else:
+    2
+";
+    assert_eq!(
+        check_source(source),
+        [
+            (3, 9, "parse-error"),
+            (5, 1, "parse-error"),
+            (7, 1, "parse-error")
+        ]
+    );
+}
+
+#[test]
+fn check_names_test() {
+    let source = "\
+import found as used
+import found as unused
+import lost as gone
+let f = fn(a):
+    return a + missing
+println(f(used.value), gone)
+";
+    let diagnostics = check(source, &|path| path.join(".") == "found");
+    let messages = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.message.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        [
+            "Import unused is never used",
+            "Unable to resolve import lost",
+            "Unknown variable \"missing\"",
+        ]
+    );
+    assert_eq!(
+        diagnostics[2],
+        Diagnostic {
+            line: 5,
+            column: 16,
+            severity: Severity::Warning,
+            code: "unknown-variable",
+            message: "Unknown variable \"missing\"".to_string(),
+        }
+    );
+    assert_eq!(diagnostics[1].severity, Severity::Error);
+}
+
+#[test]
+fn check_valid_test() {
+    let sources = [
+        include_str!("../../resources/fibb.pusl"),
+        include_str!("../../resources/generator.pusl"),
+        include_str!("../../resources/errors.pusl"),
+    ];
+    for source in sources {
+        assert_eq!(check_source(source), []);
+    }
+}
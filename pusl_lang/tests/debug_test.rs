@@ -0,0 +1,336 @@
+mod test_util;
+
+use pusl_lang::backend::debug::{
+    serve_commands, DebugCommand, DebugEvent, DebugResponse, Debugger, Resume, Reverse,
+    MAX_CHECKPOINTS,
+};
+use pusl_lang::backend::linearize::{linearize_file, ByteCodeFile};
+use pusl_lang::backend::object::Value;
+use pusl_lang::backend::{debug, startup, ExecContext, ExecuteReturn};
+use pusl_lang::lexer::lex;
+use pusl_lang::parser::parse;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use test_util::compare_test_eq;
+
+const DEBUG_SOURCE: &str = include_str!("../../resources/debug.pusl");
+const TIME_TRAVEL_SOURCE: &str = include_str!("../../resources/time_travel.pusl");
+const SECOND_SOURCE: &str = include_str!("../../resources/secondary_source.pusl");
+
+fn test_resolve(path: Vec<String>) -> Option<ByteCodeFile> {
+    assert_eq!(path.join("/"), "secondary_source");
+    Some(linearize_file(parse(lex(SECOND_SOURCE.lines()))))
+}
+
+/// Runs a debugger session with scripted commands, returning the debugger's and the script's
+/// output
+fn run_session(commands: &str) -> (String, String) {
+    let code = linearize_file(parse(lex(DEBUG_SOURCE.lines())));
+    let mut script_output = Vec::new();
+    let mut debugger_output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut script_output),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("debug.pusl"), ctx);
+        let result = debug::run_session(&state, &mut commands.as_bytes(), &mut debugger_output)
+            .expect("Unable to write debugger output");
+        assert!(matches!(result, Some(ExecuteReturn::Return(_))));
+    }
+    (
+        String::from_utf8(debugger_output).expect("Invalid UTF8 in debugger output"),
+        String::from_utf8(script_output).expect("Invalid UTF8 in script output"),
+    )
+}
+
+#[test]
+fn debug_session_test() {
+    let commands = "\
+break debug.pusl:11
+continue
+backtrace
+locals
+bound
+print a + b * 10
+frame 1
+print total
+next
+next
+locals
+delete 1
+break debug:4
+continue
+this
+print this.x
+finish
+stack
+step
+step
+continue
+";
+    let (transcript, script_output) = run_session(commands);
+    compare_test_eq(&transcript, "debug", "session");
+    assert_eq!(script_output, "33\n66\n");
+}
+
+#[test]
+fn debug_step_test() {
+    let commands = "step\nstep\nstep\nnext\nprint offset\nprint missing\nprint )\ncontinue\n";
+    let (transcript, _) = run_session(commands);
+    compare_test_eq(&transcript, "debug", "step");
+}
+
+#[test]
+fn debug_reverse_test() {
+    let commands = "\
+reverse-step
+record 4
+break debug.pusl:11
+continue
+continue
+continue
+locals
+reverse-continue
+locals
+reverse-step
+reverse-step
+locals
+delete 1
+continue
+reverse-step
+reverse-step
+backtrace
+reverse-continue
+continue
+";
+    let (transcript, script_output) = run_session(commands);
+    compare_test_eq(&transcript, "debug", "reverse");
+    // Output from code run again isn't written twice
+    assert_eq!(script_output, "33\n66\n");
+}
+
+#[test]
+fn debug_recorded_input_test() {
+    let code = linearize_file(parse(lex(TIME_TRAVEL_SOURCE.lines())));
+    let mut script_output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut script_output),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("time_travel.pusl"), ctx);
+        let mut debugger = Debugger::new();
+        assert!(debugger.reverse(&state, Reverse::Step).is_err());
+        debugger.start_recording(&state, 2).unwrap();
+        let breakpoint = debugger.add_breakpoint("time_travel".to_string(), 5);
+
+        let start = |debugger: &mut Debugger| {
+            let event = debugger.resume(&state, Resume::Continue);
+            assert!(matches!(event, DebugEvent::Breakpoint(id) if id == breakpoint));
+            match debug::evaluate(&state, 0, "start") {
+                Ok(Value::Float(start)) => start,
+                _ => panic!("start should be a float"),
+            }
+        };
+        let first = start(&mut debugger);
+        let event = debugger.reverse(&state, Reverse::Continue).unwrap();
+        assert!(matches!(event, DebugEvent::RecordingStart));
+        // time() gives back what it gave the first time through
+        assert_eq!(start(&mut debugger).to_bits(), first.to_bits());
+
+        debugger.remove_breakpoint(breakpoint);
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(
+            event,
+            DebugEvent::Finished(ExecuteReturn::Return(_))
+        ));
+        let event = debugger.reverse(&state, Reverse::Step).unwrap();
+        assert!(matches!(event, DebugEvent::Step));
+        let location = debug::location(&state.borrow(), 0).unwrap();
+        assert_eq!(location.line, Some(7));
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(
+            event,
+            DebugEvent::Finished(ExecuteReturn::Return(_))
+        ));
+    }
+    assert_eq!(String::from_utf8(script_output).unwrap(), "1\n2\n3\ntrue\n");
+}
+
+#[test]
+fn debug_evaluate_mistake_test() {
+    let code = linearize_file(parse(lex(DEBUG_SOURCE.lines())));
+    let mut script_output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut script_output),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("debug.pusl"), ctx);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint("debug.pusl".to_string(), 11);
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(event, DebugEvent::Breakpoint(_)));
+        let depth = state.borrow().call_depth();
+
+        // Mistakes the VM doesn't raise as errors are given back without stopping the script
+        for expression in &["a()", "1 < \"a\"", "offset.x", "add(null, 1)"] {
+            assert!(
+                debug::evaluate(&state, 0, expression).is_err(),
+                "{} should fail",
+                expression
+            );
+            assert_eq!(state.borrow().call_depth(), depth);
+        }
+        assert!(matches!(
+            debug::evaluate(&state, 0, "a + b + offset"),
+            Ok(Value::Integer(10))
+        ));
+
+        debugger.remove_breakpoint(1);
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(
+            event,
+            DebugEvent::Finished(ExecuteReturn::Return(_))
+        ));
+    }
+    assert_eq!(String::from_utf8(script_output).unwrap(), "33\n66\n");
+}
+
+#[test]
+fn debug_recording_limit_test() {
+    let source = "let i = 0\nwhile i < 1000:\n    i = i + 1\nprintln(i)\n";
+    let code = linearize_file(parse(lex(source.lines())));
+    let mut script_output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut script_output),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("limit.pusl"), ctx);
+        let mut debugger = Debugger::new();
+        debugger.start_recording(&state, 1).unwrap();
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(
+            event,
+            DebugEvent::Finished(ExecuteReturn::Return(_))
+        ));
+
+        // Only the last checkpoints are kept, so the start of the loop is out of reach
+        let event = debugger.reverse(&state, Reverse::Continue).unwrap();
+        assert!(matches!(event, DebugEvent::RecordingStart));
+        match debug::evaluate(&state, 0, "i") {
+            Ok(Value::Integer(i)) => {
+                assert!(i > 1000 - MAX_CHECKPOINTS as i64, "went back to {}", i)
+            }
+            other => panic!("i should be an integer, not {:?}", other),
+        }
+        let event = debugger.reverse(&state, Reverse::Step).unwrap();
+        assert!(matches!(event, DebugEvent::RecordingStart));
+
+        let event = debugger.resume(&state, Resume::Continue);
+        assert!(matches!(
+            event,
+            DebugEvent::Finished(ExecuteReturn::Return(_))
+        ));
+    }
+    assert_eq!(String::from_utf8(script_output).unwrap(), "1000\n");
+}
+
+#[test]
+fn debug_reverse_import_test() {
+    let source = "\
+import secondary_source as ss
+let before = 1
+let ss.extra = 5
+println(ss.extra + ss.func(1))
+";
+    let code = linearize_file(parse(lex(source.lines())));
+    let mut script_output = Vec::new();
+    {
+        let ctx = ExecContext {
+            resolve: test_resolve,
+            stream: Some(&mut script_output),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("main.pusl"), ctx);
+        let mut debugger = Debugger::new();
+        // Recording starts in the imported module, before the main module is loaded
+        debugger.start_recording(&state, 1).unwrap();
+        let finish = |debugger: &mut Debugger| {
+            let event = debugger.resume(&state, Resume::Continue);
+            assert!(matches!(
+                event,
+                DebugEvent::Finished(ExecuteReturn::Return(_))
+            ));
+        };
+        finish(&mut debugger);
+
+        let breakpoint = debugger.add_breakpoint("main".to_string(), 3);
+        let event = debugger.reverse(&state, Reverse::Continue).unwrap();
+        assert!(matches!(event, DebugEvent::Breakpoint(id) if id == breakpoint));
+        // The import's object is back to how it was, though its module's code was kept
+        assert!(matches!(
+            debug::evaluate(&state, 0, "ss.extra"),
+            Ok(Value::Null)
+        ));
+        assert!(matches!(
+            debug::evaluate(&state, 0, "ss.func(1)"),
+            Ok(Value::Integer(2))
+        ));
+
+        debugger.remove_breakpoint(breakpoint);
+        let event = debugger.reverse(&state, Reverse::Continue).unwrap();
+        assert!(matches!(event, DebugEvent::RecordingStart));
+        finish(&mut debugger);
+    }
+    assert_eq!(String::from_utf8(script_output).unwrap(), "7\n");
+}
+
+#[test]
+fn serve_commands_test() {
+    let (command_send, command_recv) = mpsc::channel();
+    let (response_send, response_recv) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        let source = "let a = 1\nlet b = 2\nprint(a + b)\n";
+        let code = linearize_file(parse(lex(source.lines())));
+        let mut output = Vec::new();
+        {
+            let ctx = ExecContext {
+                stream: Some(&mut output),
+                ..ExecContext::default()
+            };
+            let state = startup(code, PathBuf::from("serve"), ctx);
+            serve_commands(&state, &command_recv, &response_send);
+        }
+        String::from_utf8(output).expect("Invalid UTF8 in script output")
+    });
+    let send = |command| {
+        command_send.send(command).unwrap();
+        response_recv.recv().unwrap()
+    };
+
+    assert!(matches!(
+        response_recv.recv().unwrap(),
+        DebugResponse::Paused(0)
+    ));
+    // Stepping one instruction
+    assert!(matches!(
+        send(DebugCommand::RunToIndex(1)),
+        DebugResponse::Paused(1)
+    ));
+    // An index the module doesn't have is refused without moving
+    match send(DebugCommand::RunToIndex(1000)) {
+        DebugResponse::Error(message) => assert!(message.contains("1000"), "{}", message),
+        _ => panic!("Expected an error for an index past the end"),
+    }
+    // Running to a later instruction like a breakpoint
+    assert!(matches!(
+        send(DebugCommand::RunToIndex(4)),
+        DebugResponse::Paused(4)
+    ));
+    assert!(matches!(send(DebugCommand::Run), DebugResponse::Done));
+    assert_eq!(worker.join().unwrap(), "3");
+}
@@ -0,0 +1,172 @@
+use pusl_lang::backend::linearize::{linearize_file, ByteCodeFile};
+use pusl_lang::backend::repl::{is_incomplete, Repl};
+use pusl_lang::backend::{debug, execute, startup, ExecContext, ExecuteReturn};
+use pusl_lang::lexer::lex;
+use pusl_lang::parser::parse;
+use std::path::{Path, PathBuf};
+
+const FIBB_SOURCE: &str = include_str!("../../resources/fibb.pusl");
+const SECOND_SOURCE: &str = include_str!("../../resources/secondary_source.pusl");
+
+fn test_resolve(path: Vec<String>) -> Option<ByteCodeFile> {
+    match path.join("/").as_str() {
+        "secondary_source" => Some(linearize_file(parse(lex(SECOND_SOURCE.lines())))),
+        "uses_secondary" => {
+            let source = "import secondary_source as ss\nlet this.length = ss.func(10)";
+            Some(linearize_file(parse(lex(source.lines()))))
+        }
+        _ => None,
+    }
+}
+
+/// Runs each input in turn in one state, returning what each input's value is shown as or its
+/// error, and the script's output
+fn run_inputs(inputs: &[&str]) -> (Vec<Result<String, String>>, Vec<String>, String) {
+    let mut output = Vec::new();
+    let mut results = Vec::new();
+    let names;
+    {
+        let ctx = ExecContext {
+            resolve: test_resolve,
+            stream: Some(&mut output),
+            ..ExecContext::default()
+        };
+        let empty = linearize_file(parse(lex(std::iter::empty())));
+        let state = startup(empty, PathBuf::from("<repl>"), ctx);
+        assert!(matches!(execute(&state), ExecuteReturn::Return(_)));
+        let mut repl = Repl::new();
+        for input in inputs {
+            let result = repl
+                .run(&state, input, Path::new("<repl>"))
+                .map(|value| debug::describe_value(&state.borrow(), &value));
+            results.push(result);
+        }
+        names = repl
+            .bindings()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+    }
+    let output = String::from_utf8(output).expect("Invalid UTF8 in test output");
+    (results, names, output)
+}
+
+#[test]
+fn repl_bindings_test() {
+    let (results, names, output) = run_inputs(&[
+        "1 + 2",
+        "let x = 5",
+        "x * 2",
+        "let add = fn(a, b):\n    return a + b",
+        "add(x, 1)",
+        "x = x + 1",
+        "x",
+        "if x > 5:\n    println(\"big\")\nelse:\n    println(\"small\")\n",
+        "let i = 0\nwhile i < 3:\n    i = i + 1\ni",
+        "let x = \"shadowed\"",
+        "x",
+        "println(i)",
+    ]);
+    let shown = results
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        shown,
+        [
+            "3",
+            "null",
+            "10",
+            "null",
+            "6",
+            "null",
+            "6",
+            "null",
+            "3",
+            "null",
+            "\"shadowed\"",
+            "null",
+        ]
+    );
+    assert_eq!(names, ["x", "add", "i"]);
+    assert_eq!(output, "big\n3\n");
+}
+
+#[test]
+fn repl_error_test() {
+    let (results, names, _) = run_inputs(&[
+        "let kept = 1",
+        "missing + 1",
+        "let lost = 2\nyeet Object()",
+        "return 1",
+        "if true:\n  1\n    2",
+        "kept",
+    ]);
+    assert!(results[1].as_ref().unwrap_err().contains("missing"));
+    assert!(results[2].is_err());
+    assert!(results[3].is_err());
+    assert!(results[4].is_err());
+    // Variables from input which failed aren't kept
+    assert_eq!(results[5], Ok("1".to_string()));
+    assert_eq!(names, ["kept"]);
+}
+
+#[test]
+fn repl_load_test() {
+    let (results, names, output) = run_inputs(&[FIBB_SOURCE, "b", "c.f(7)"]);
+    assert_eq!(results[0], Ok("null".to_string()));
+    assert_eq!(results[1], Ok("34".to_string()));
+    assert_eq!(results[2], Ok("8".to_string()));
+    assert_eq!(names, ["fibbonacci", "c", "b"]);
+    assert_eq!(output, "34\n");
+}
+
+#[test]
+fn repl_panic_test() {
+    let (results, names, output) = run_inputs(&[
+        "let n = null",
+        "n()",
+        "1 < \"a\"",
+        "let f = fn():\n    return null()",
+        "1 + 1",
+        "f()",
+        "println(n)",
+        "let m = 2",
+        "m + 1",
+    ]);
+    // Mistakes the VM panics on are reported and the repl keeps going
+    assert!(results[1].is_err());
+    assert!(results[2].is_err());
+    assert!(results[5].is_err());
+    assert_eq!(results[4], Ok("2".to_string()));
+    assert_eq!(results[8], Ok("3".to_string()));
+    assert_eq!(names, ["n", "f", "m"]);
+    assert_eq!(output, "null\n");
+}
+
+#[test]
+fn repl_import_test() {
+    let (results, names, _) = run_inputs(&[
+        "import secondary_source as ss",
+        "ss.str",
+        "ss.func(1)",
+        "import uses_secondary as uses\nlet length = uses.length",
+        "length + ss.func(0)",
+        "import missing as m",
+    ]);
+    assert_eq!(results[1], Ok("\"Hello There\"".to_string()));
+    assert_eq!(results[2], Ok("2".to_string()));
+    assert_eq!(results[4], Ok("12".to_string()));
+    assert!(results[5].as_ref().unwrap_err().contains("missing"));
+    assert_eq!(names, ["length"]);
+}
+
+#[test]
+fn repl_incomplete_test() {
+    assert!(!is_incomplete::<&str>(&[]));
+    assert!(!is_incomplete(&["1 + 2"]));
+    assert!(is_incomplete(&["if x:"]));
+    assert!(is_incomplete(&["let f = fn(a): # adds one"]));
+    assert!(is_incomplete(&["if x:", "    1", "else:"]));
+    assert!(!is_incomplete(&["if x:", "    1", ""]));
+}
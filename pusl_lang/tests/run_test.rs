@@ -1,13 +1,23 @@
 mod test_util;
 
 use pusl_lang::backend::linearize::{linearize_file, ByteCodeFile};
-use pusl_lang::backend::{execute, startup, ExecContext};
+use pusl_lang::backend::observe::Tracer;
+use pusl_lang::backend::{
+    assembly::assemble, coverage::Coverage, debug, execute, execute_for, object::Value,
+    observe::Observer, optimize::optimize, profile::Profiler, snapshot, startup, ExecContext,
+    ExecuteReturn, ExecutionState, Limit,
+};
 use pusl_lang::lexer::lex;
 use pusl_lang::parser::parse;
+use std::cell::RefCell;
+use std::io;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use test_util::compare_test_eq;
 
-const SECOND_SOURCE: &'static str = include_str!("../../resources/secondary_source.pusl");
+const SECOND_SOURCE: &str = include_str!("../../resources/secondary_source.pusl");
 
 fn test_resolve(path: Vec<String>) -> Option<ByteCodeFile> {
     assert_eq!(path.join("/"), "secondary_source");
@@ -18,7 +28,7 @@ fn test_resolve(path: Vec<String>) -> Option<ByteCodeFile> {
     Some(code)
 }
 
-const GENERATOR_SOURCE: &'static str = include_str!("../../resources/generator.pusl");
+const GENERATOR_SOURCE: &str = include_str!("../../resources/generator.pusl");
 
 #[test]
 fn run_generator_test() {
@@ -32,14 +42,14 @@ fn run_generator_test() {
     let mut output = Vec::new();
     ctx.stream = Some(&mut output);
 
-    let mut state = startup(code, path, ctx);
-    let _result = execute(&mut state);
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
     let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
 
     compare_test_eq(&actual, "run", "generator")
 }
 
-const SIMPLE_SOURCE: &'static str = include_str!("../../resources/simple_program.pusl");
+const SIMPLE_SOURCE: &str = include_str!("../../resources/simple_program.pusl");
 
 #[test]
 fn run_simple_test() {
@@ -49,19 +59,21 @@ fn run_simple_test() {
     let code = linearize_file(ast);
     let path = PathBuf::from("simple_program.pusl");
 
-    let mut ctx = ExecContext::default();
-    ctx.resolve = test_resolve;
     let mut output = Vec::new();
-    ctx.stream = Some(&mut output);
+    let ctx = ExecContext {
+        resolve: test_resolve,
+        stream: Some(&mut output),
+        ..ExecContext::default()
+    };
 
-    let mut state = startup(code, path, ctx);
-    let _result = execute(&mut state);
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
     let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
 
     compare_test_eq(&actual, "run", "small")
 }
 
-const ERROR_SOURCE: &'static str = include_str!("../../resources/errors.pusl");
+const ERROR_SOURCE: &str = include_str!("../../resources/errors.pusl");
 #[test]
 fn run_error_test() {
     let lines = ERROR_SOURCE.lines();
@@ -74,14 +86,14 @@ fn run_error_test() {
     let mut output = Vec::new();
     ctx.stream = Some(&mut output);
 
-    let mut state = startup(code, path, ctx);
-    let _result = execute(&mut state);
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
     let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
 
     compare_test_eq(&actual, "run", "error")
 }
 
-const FIBB_SOURCE: &'static str = include_str!("../../resources/fibb.pusl");
+const FIBB_SOURCE: &str = include_str!("../../resources/fibb.pusl");
 
 #[test]
 fn run_fibb_test() {
@@ -95,14 +107,266 @@ fn run_fibb_test() {
     let mut output = Vec::new();
     ctx.stream = Some(&mut output);
 
-    let mut state = startup(code, path, ctx);
-    let _result = execute(&mut state);
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
     let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
 
     compare_test_eq(&actual, "run", "fibb")
 }
 
-const YOINK_SOURCE: &'static str = include_str!("../../resources/yoink_filter.pusl");
+#[test]
+fn run_profile_test() {
+    let code = linearize_file(parse(lex(FIBB_SOURCE.lines())));
+    let mut profiler = Profiler::new();
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            observers: vec![&mut profiler],
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("fibb.pusl"), ctx);
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::Return(_)));
+    }
+    profiler.finish();
+    compare_test_eq(&String::from_utf8(output).unwrap(), "run", "fibb");
+
+    let functions = profiler.functions();
+    let names = functions
+        .iter()
+        .map(|function| function.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["fibb.pusl", "fn(num) fibb.pusl:2"]);
+    let (module, fibbonacci) = (&functions[0], &functions[1]);
+    assert_eq!(module.calls, 1);
+    // fibbonacci(10) calls itself for every number down to 1, besides 10 and 9 calling 8 and 7
+    assert_eq!(fibbonacci.calls, 109);
+    assert!(module.inclusive >= fibbonacci.inclusive);
+    assert!(fibbonacci.inclusive >= fibbonacci.exclusive);
+    for function in functions {
+        let line_instructions: u64 = function.lines.values().map(|line| line.instructions).sum();
+        assert_eq!(line_instructions, function.instructions);
+    }
+    assert_eq!(
+        fibbonacci.lines.keys().copied().collect::<Vec<_>>(),
+        [2, 3, 4, 5, 7]
+    );
+
+    let mut natives = profiler
+        .natives()
+        .iter()
+        .map(|native| (native.name, native.calls))
+        .collect::<Vec<_>>();
+    natives.sort();
+    assert_eq!(natives, [("Object", 1), ("print", 1)]);
+
+    let mut folded = Vec::new();
+    profiler.write_folded(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let deepest = folded
+        .lines()
+        .map(|line| {
+            let (stack, nanos) = line.rsplit_once(' ').unwrap();
+            nanos.parse::<u64>().expect("Missing the time");
+            let frames = stack.split(';').collect::<Vec<_>>();
+            assert_eq!(frames[0], "fibb.pusl");
+            frames.len()
+        })
+        .max();
+    // The module, then fibbonacci from 10 down to 2
+    assert_eq!(deepest, Some(10));
+}
+
+const COVERAGE_SOURCE: &str = include_str!("../../resources/coverage.pusl");
+
+/// Runs the code with coverage on and returns the lcov report's lines
+fn coverage_report(code: ByteCodeFile, path: &str) -> Vec<String> {
+    let mut coverage = Coverage::new();
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            observers: vec![&mut coverage],
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from(path), ctx);
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::Return(_)));
+    }
+    let mut report = Vec::new();
+    coverage.write_lcov(&mut report).unwrap();
+    String::from_utf8(report)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn run_coverage_test() {
+    let code = linearize_file(parse(lex(COVERAGE_SOURCE.lines())));
+    let report = coverage_report(code, "coverage.pusl");
+    let has = |line: &str| report.iter().any(|reported| reported == line);
+
+    assert_eq!(report[0], "TN:");
+    assert_eq!(report[1], "SF:coverage.pusl");
+    assert_eq!(report.last().unwrap(), "end_of_record");
+    // Functions start at the first line of their body
+    assert!(has("FN:2,fn(num) line 2"));
+    assert!(has("FN:10,fn(num) line 10"));
+    assert!(has("FNDA:2,fn(num) line 2"));
+    assert!(has("FNDA:0,fn(num) line 10"));
+    assert!(has("FNF:2"));
+    assert!(has("FNH:1"));
+
+    // sign is called with 0 and 1, so it never returns "negative", and unused never runs
+    for (line, count) in [(2, 2), (3, 1), (5, 0), (7, 1), (10, 0), (12, 0), (16, 2)] {
+        assert!(has(&format!("DA:{},{}", line, count)), "line {}", line);
+    }
+    assert!(has("LF:14"));
+    assert!(has("LH:10"));
+
+    let branches = report
+        .iter()
+        .filter(|line| line.starts_with("BRDA:"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        branches,
+        [
+            // The while condition, leaving once
+            "BRDA:15,0,0,1",
+            "BRDA:15,0,1,2",
+            "BRDA:2,1,0,1",
+            "BRDA:2,1,1,1",
+            // num < 0 is never true
+            "BRDA:2,2,0,0",
+            "BRDA:2,2,1,1",
+            // Branches in code which never ran are told apart from branches never taken
+            "BRDA:10,3,0,-",
+            "BRDA:10,3,1,-",
+        ]
+    );
+    assert!(has("BRF:8"));
+    assert!(has("BRH:5"));
+}
+
+#[test]
+fn run_comparison_coverage_test() {
+    let code = assemble(include_str!("../../resources/asm/compare.pasm")).unwrap();
+    let report = coverage_report(code, "compare.pasm");
+    let branches = report
+        .iter()
+        .filter(|line| line.starts_with("BRDA:"))
+        .collect::<Vec<_>>();
+    // Greater, less and equal
+    assert_eq!(branches, ["BRDA:2,0,0,0", "BRDA:2,0,1,2", "BRDA:2,0,2,1"]);
+    assert!(report.iter().any(|line| line == "BRH:2"));
+    assert!(report.iter().any(|line| line == "DA:3,2"));
+}
+
+const OBSERVE_SOURCE: &str = include_str!("../../resources/observe.pusl");
+
+/// Keeps the events it sees, besides instructions and heap changes which it only counts
+#[derive(Default)]
+struct EventLog {
+    events: Vec<String>,
+    instructions: usize,
+    allocated: usize,
+    collected: usize,
+}
+
+impl<'a> Observer<'a> for EventLog {
+    fn instruction(&mut self, _state: &ExecutionState<'a>, _index: usize) {
+        self.instructions += 1;
+    }
+
+    fn enter(&mut self, state: &ExecutionState<'a>) {
+        let location = debug::location(state, 0).unwrap();
+        self.events
+            .push(format!("enter line {}", location.line.unwrap()));
+    }
+
+    fn exit(&mut self, state: &ExecutionState<'a>, value: &Value) {
+        let value = debug::describe_value(state, value);
+        self.events.push(format!("exit {}", value));
+    }
+
+    fn native_enter(&mut self, _state: &ExecutionState<'a>, name: &'static str) {
+        self.events.push(format!("native {}", name));
+    }
+
+    fn yeet(&mut self, _state: &ExecutionState<'a>, _error: &Value) {
+        self.events.push("yeet".to_string());
+    }
+
+    fn unwind(&mut self, _state: &ExecutionState<'a>, frames: usize) {
+        self.events.push(format!("unwind {}", frames));
+    }
+
+    fn yielded(&mut self, state: &ExecutionState<'a>, value: &Value) {
+        let value = debug::describe_value(state, value);
+        self.events.push(format!("yield {}", value));
+    }
+
+    fn resumed(&mut self, _state: &ExecutionState<'a>) {
+        self.events.push("resume".to_string());
+    }
+
+    fn allocated(&mut self, _state: &ExecutionState<'a>, bytes: usize) {
+        self.allocated += bytes;
+    }
+
+    fn collected(&mut self, _state: &ExecutionState<'a>, bytes: usize) {
+        self.collected += bytes;
+    }
+}
+
+#[test]
+fn run_observer_test() {
+    let code = linearize_file(parse(lex(OBSERVE_SOURCE.lines())));
+    let mut log = EventLog::default();
+    let mut instructions = EventLog::default();
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            observers: vec![&mut log, &mut instructions],
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("observe.pusl"), ctx);
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::Return(_)));
+    }
+    assert_eq!(String::from_utf8(output).unwrap(), "3\n6\ncaught\n");
+    assert_eq!(
+        log.events,
+        [
+            "native Object",
+            "native Generator.next",
+            "enter line 3",
+            "yield 3",
+            "native println",
+            "native Generator.next",
+            "resume",
+            "yield 6",
+            "native println",
+            "enter line 7",
+            "native Object",
+            "yeet",
+            "unwind 1",
+            "native instance_of",
+            "native println",
+            "exit null",
+        ]
+    );
+    // Every observer sees every event
+    assert_eq!(log.instructions, instructions.instructions);
+    assert!(log.instructions > 0);
+    assert!(log.allocated > 0);
+}
+
+const YOINK_SOURCE: &str = include_str!("../../resources/yoink_filter.pusl");
 
 #[test]
 fn run_yoink_test() {
@@ -116,9 +380,531 @@ fn run_yoink_test() {
     let mut output = Vec::new();
     ctx.stream = Some(&mut output);
 
-    let mut state = startup(code, path, ctx);
-    let _result = execute(&mut state);
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
     let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
 
     compare_test_eq(&actual, "run", "yoink")
 }
+
+const OPTIMIZE_SOURCE: &str = include_str!("../../resources/optimize.pusl");
+
+#[test]
+fn run_optimize_test() {
+    for level in 0..=2 {
+        let lines = OPTIMIZE_SOURCE.lines();
+        let roots = lex(lines);
+        let ast = parse(roots);
+        let mut code = linearize_file(ast);
+        optimize(&mut code, level);
+        let path = PathBuf::from("optimize.pusl");
+
+        let mut ctx = ExecContext::default();
+        let mut output = Vec::new();
+        ctx.stream = Some(&mut output);
+
+        let state = startup(code, path, ctx);
+        let _result = execute(&state);
+        let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+        compare_test_eq(&actual, "run", "optimize")
+    }
+}
+
+#[test]
+fn run_simple_optimized_test() {
+    let lines = SIMPLE_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let mut code = linearize_file(ast);
+    optimize(&mut code, 2);
+    let path = PathBuf::from("simple_program.pusl");
+
+    let mut output = Vec::new();
+    let ctx = ExecContext {
+        resolve: test_resolve,
+        stream: Some(&mut output),
+        ..ExecContext::default()
+    };
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    compare_test_eq(&actual, "run", "small")
+}
+
+const SHAPES_SOURCE: &str = include_str!("../../resources/shapes.pusl");
+
+#[test]
+fn run_shapes_test() {
+    let lines = SHAPES_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("shapes.pusl");
+
+    let mut ctx = ExecContext::default();
+    let mut output = Vec::new();
+    ctx.stream = Some(&mut output);
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    compare_test_eq(&actual, "run", "shapes")
+}
+
+const STRINGS_SOURCE: &str = include_str!("../../resources/strings.pusl");
+
+#[test]
+fn run_strings_test() {
+    let lines = STRINGS_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("strings.pusl");
+
+    let mut ctx = ExecContext::default();
+    let mut output = Vec::new();
+    ctx.stream = Some(&mut output);
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    compare_test_eq(&actual, "run", "strings")
+}
+
+const TAILCALL_SOURCE: &str = include_str!("../../resources/tailcall.pusl");
+
+#[test]
+fn run_tailcall_test() {
+    let lines = TAILCALL_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("tailcall.pusl");
+
+    let mut ctx = ExecContext::default();
+    let mut output = Vec::new();
+    ctx.stream = Some(&mut output);
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    compare_test_eq(&actual, "run", "tailcall")
+}
+
+#[test]
+fn run_trace_tailcall_test() {
+    let source = "\
+let countdown = fn(n, total):
+    if n == 0:
+        return total
+    return self(n - 1, total + 1)
+println(countdown(2, 0))
+";
+    let code = linearize_file(parse(lex(source.lines())));
+    let mut trace = Vec::new();
+    {
+        let mut tracer = Tracer::new(&mut trace, false);
+        let ctx = ExecContext {
+            observers: vec![&mut tracer],
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("countdown.pusl"), ctx);
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::Return(_)));
+    }
+    let trace = String::from_utf8(trace).unwrap();
+    let calls = trace
+        .lines()
+        .filter(|line| line.trim_start().starts_with("e"))
+        .collect::<Vec<_>>();
+    // Each tail call ends the caller as the callee enters
+    assert_eq!(
+        calls,
+        [
+            "  enter countdown.pusl:2 in fn(n, total)",
+            "  exit by tail call",
+            "  enter countdown.pusl:2 in fn(n, total)",
+            "  exit by tail call",
+            "  enter countdown.pusl:2 in fn(n, total)",
+            "  exit with 2",
+            "exit with null",
+        ]
+    );
+}
+
+#[test]
+fn run_profile_tailcall_test() {
+    let code = linearize_file(parse(lex(TAILCALL_SOURCE.lines())));
+    let mut profiler = Profiler::new();
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            observers: vec![&mut profiler],
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("/tmp/tailcall.pusl"), ctx);
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::Return(_)));
+    }
+    profiler.finish();
+    compare_test_eq(&String::from_utf8(output).unwrap(), "run", "tailcall");
+
+    let calls = profiler
+        .functions()
+        .iter()
+        .map(|function| (function.name.as_str(), function.calls))
+        .collect::<Vec<_>>();
+    // Each tail call reuses its caller's frame but is still a call
+    assert_eq!(
+        calls,
+        [
+            ("/tmp/tailcall.pusl", 1),
+            ("fn(n, total) /tmp/tailcall.pusl:2", 100001),
+            ("fn(n) /tmp/tailcall.pusl:9", 1001),
+            ("fn() /tmp/tailcall.pusl:20", 1),
+            ("fn() /tmp/tailcall.pusl:17", 1),
+        ]
+    );
+    for function in profiler.functions() {
+        assert!(
+            function.inclusive >= function.exclusive,
+            "{}",
+            function.name
+        );
+    }
+}
+
+const STACK_OVERFLOW_SOURCE: &str = include_str!("../../resources/stack_overflow.pusl");
+
+#[test]
+fn run_stack_overflow_test() {
+    let lines = STACK_OVERFLOW_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("stack_overflow.pusl");
+
+    let mut output = Vec::new();
+    let ctx = ExecContext {
+        max_call_depth: 1000,
+        stream: Some(&mut output),
+        ..ExecContext::default()
+    };
+
+    let state = startup(code, path, ctx);
+    let _result = execute(&state);
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+
+    compare_test_eq(&actual, "run", "stack_overflow")
+}
+
+const LIMITS_SOURCE: &str = include_str!("../../resources/limits.pusl");
+
+fn run_limited(limit: Limit, configure: fn(&mut ExecContext)) -> String {
+    let lines = LIMITS_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("limits.pusl");
+
+    let mut output = Vec::new();
+    {
+        let mut ctx = ExecContext {
+            stream: Some(&mut output),
+            ..ExecContext::default()
+        };
+        configure(&mut ctx);
+
+        let state = startup(code, path, ctx);
+        let result = execute(&state);
+        assert!(
+            matches!(result, ExecuteReturn::LimitExceeded(exceeded) if exceeded == limit),
+            "Expected {:?} limit, got {:?}",
+            limit,
+            result
+        );
+        // The limit can't be skipped past by executing again
+        let result = execute(&state);
+        assert!(matches!(result, ExecuteReturn::LimitExceeded(exceeded) if exceeded == limit));
+    }
+    String::from_utf8(output).expect("Invalid UTF8 in test output")
+}
+
+#[test]
+fn run_fuel_limit_test() {
+    let actual = run_limited(Limit::Fuel, |ctx| ctx.fuel = Some(10_000));
+    assert_eq!(actual, "start\n");
+}
+
+#[test]
+fn run_heap_limit_test() {
+    let actual = run_limited(Limit::Heap, |ctx| ctx.max_heap = Some(64 * 1024));
+    assert_eq!(actual, "start\n");
+}
+
+const GARBAGE_SOURCE: &str = include_str!("../../resources/garbage.pusl");
+
+#[test]
+fn run_heap_limit_garbage_test() {
+    let code = linearize_file(parse(lex(GARBAGE_SOURCE.lines())));
+    let mut output = Vec::new();
+    {
+        // The same limit as above, but everything made in the loop can be collected
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            max_heap: Some(64 * 1024),
+            ..ExecContext::default()
+        };
+        let state = startup(code, PathBuf::from("garbage.pusl"), ctx);
+        let result = execute(&state);
+        assert!(
+            matches!(result, ExecuteReturn::Return(_)),
+            "Expected to finish, got {:?}",
+            result
+        );
+    }
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+    assert_eq!(actual, "start\n20000\n");
+}
+
+#[test]
+fn run_deadline_limit_test() {
+    let actual = run_limited(Limit::Deadline, |ctx| {
+        ctx.deadline = Some(Instant::now() + Duration::from_millis(50))
+    });
+    assert_eq!(actual, "start\n");
+}
+
+#[test]
+fn run_sliced_test() {
+    let mut generator_output = Vec::new();
+    let mut yoink_output = Vec::new();
+    let mut suspensions = 0;
+    {
+        let generator_ctx = ExecContext {
+            stream: Some(&mut generator_output),
+            ..ExecContext::default()
+        };
+        let generator_code = linearize_file(parse(lex(GENERATOR_SOURCE.lines())));
+        let generator_state = startup(
+            generator_code,
+            PathBuf::from("generator.pusl"),
+            generator_ctx,
+        );
+
+        let yoink_ctx = ExecContext {
+            stream: Some(&mut yoink_output),
+            ..ExecContext::default()
+        };
+        let yoink_code = linearize_file(parse(lex(YOINK_SOURCE.lines())));
+        let yoink_state = startup(yoink_code, PathBuf::from("yoink_filter.pusl"), yoink_ctx);
+
+        // Take turns until both scripts are done
+        let mut running = vec![&generator_state, &yoink_state];
+        while !running.is_empty() {
+            running.retain(|&state| match execute_for(state, 20) {
+                ExecuteReturn::Suspended => {
+                    suspensions += 1;
+                    true
+                }
+                ExecuteReturn::Return(_) => false,
+                other => panic!("Unexpected result {:?}", other),
+            });
+        }
+    }
+    assert!(suspensions > 10);
+
+    let actual = String::from_utf8(generator_output).expect("Invalid UTF8 in test output");
+    compare_test_eq(&actual, "run", "generator");
+    let actual = String::from_utf8(yoink_output).expect("Invalid UTF8 in test output");
+    compare_test_eq(&actual, "run", "yoink");
+}
+
+#[test]
+fn run_collected_test() {
+    let programs = [
+        (GENERATOR_SOURCE, "generator.pusl", "generator"),
+        (SIMPLE_SOURCE, "simple_program.pusl", "small"),
+        (STRINGS_SOURCE, "strings.pusl", "strings"),
+    ];
+    let mut freed = 0;
+    let mut log = EventLog::default();
+    for (source, file_name, tag) in programs {
+        let mut output = Vec::new();
+        {
+            let ctx = ExecContext {
+                resolve: test_resolve,
+                stream: Some(&mut output),
+                observers: vec![&mut log],
+                ..ExecContext::default()
+            };
+            let code = linearize_file(parse(lex(source.lines())));
+            let state = startup(code, PathBuf::from(file_name), ctx);
+            // Collecting between every few instructions frees anything the script still uses
+            loop {
+                match execute_for(&state, 5) {
+                    ExecuteReturn::Suspended => freed += state.borrow_mut().collect_garbage(),
+                    ExecuteReturn::Return(_) => break,
+                    other => panic!("Unexpected result {:?}", other),
+                }
+            }
+        }
+        let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+        compare_test_eq(&actual, "run", tag);
+    }
+    assert!(freed > 0);
+    assert_eq!(log.collected, freed);
+}
+
+const INTERRUPT_SOURCE: &str = include_str!("../../resources/interrupt.pusl");
+
+#[test]
+fn run_interrupt_test() {
+    let lines = INTERRUPT_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("interrupt.pusl");
+
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            ..ExecContext::default()
+        };
+
+        let state = startup(code, path, ctx);
+        let interrupt = state.borrow().interrupt_handle();
+        // Interrupt the first loop, which catches it
+        assert!(matches!(execute_for(&state, 100), ExecuteReturn::Suspended));
+        interrupt.store(true, Ordering::Relaxed);
+        // Then the second loop, which doesn't
+        assert!(matches!(execute_for(&state, 100), ExecuteReturn::Suspended));
+        interrupt.store(true, Ordering::Relaxed);
+        let error = match execute(&state) {
+            ExecuteReturn::Error(error) => error,
+            other => panic!("Expected an error, got {:?}", other),
+        };
+
+        let state = state.borrow();
+        assert_eq!(
+            debug::describe_error(&state, &error),
+            "InterruptedError: Interrupted"
+        );
+        let traceback = debug::traceback(&state);
+        let frames = traceback.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2, "{}", traceback);
+        assert!(frames[0].starts_with("  module interrupt.pusl"));
+        assert!(frames[1].starts_with("  fn()"));
+    }
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+    assert_eq!(actual, "start\ncaught Interrupted\n");
+}
+
+const EXIT_SOURCE: &str = include_str!("../../resources/exit.pusl");
+
+#[test]
+fn run_exit_test() {
+    let lines = EXIT_SOURCE.lines();
+    let roots = lex(lines);
+    let ast = parse(roots);
+    let code = linearize_file(ast);
+    let path = PathBuf::from("exit.pusl");
+    std::env::set_var("PUSL_EXIT_TEST", "set");
+
+    let mut output = Vec::new();
+    {
+        let ctx = ExecContext {
+            stream: Some(&mut output),
+            args: vec!["one".to_string(), "two".to_string()],
+            ..ExecContext::default()
+        };
+
+        let state = startup(code, path, ctx);
+        assert!(matches!(execute(&state), ExecuteReturn::Exit(3)));
+        // Nothing more runs once the script has exited
+        assert!(matches!(execute(&state), ExecuteReturn::Exit(3)));
+        assert_eq!(state.borrow().exit_code(), Some(3));
+    }
+    let actual = String::from_utf8(output).expect("Invalid UTF8 in test output");
+    assert_eq!(actual, "2 one two\nset null\n3\n2\n");
+}
+
+/// Collects the output of every state a script is restored into
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn shared_ctx<'a>(output: &SharedOutput) -> ExecContext<'a> {
+    ExecContext {
+        stream: Some(Box::leak(Box::new(output.clone()))),
+        ..ExecContext::default()
+    }
+}
+
+/// Runs a script in slices, moving it to a freshly restored state after each one
+fn run_restored(source: &str, file_name: &str) -> String {
+    let mut code = Some(linearize_file(parse(lex(source.lines()))));
+    let output = SharedOutput::default();
+    let mut bytes: Option<Vec<u8>> = None;
+    let mut restores = 0;
+    loop {
+        let state = match bytes.take() {
+            None => {
+                let mut ctx = shared_ctx(&output);
+                ctx.resolve = test_resolve;
+                startup(code.take().unwrap(), PathBuf::from(file_name), ctx)
+            }
+            Some(bytes) => {
+                restores += 1;
+                snapshot::restore(&bytes, shared_ctx(&output)).expect("Unable to restore")
+            }
+        };
+        match execute_for(&state, 37) {
+            ExecuteReturn::Suspended => {
+                bytes = Some(snapshot::snapshot(&state.borrow()).expect("Unable to snapshot"));
+            }
+            ExecuteReturn::Return(_) => break,
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+    assert!(restores > 2);
+    let bytes = output.0.borrow().clone();
+    String::from_utf8(bytes).expect("Invalid UTF8 in test output")
+}
+
+#[test]
+fn run_snapshot_test() {
+    compare_test_eq(
+        &run_restored(SIMPLE_SOURCE, "simple_program.pusl"),
+        "run",
+        "small",
+    );
+    compare_test_eq(
+        &run_restored(GENERATOR_SOURCE, "generator.pusl"),
+        "run",
+        "generator",
+    );
+    compare_test_eq(&run_restored(SHAPES_SOURCE, "shapes.pusl"), "run", "shapes");
+    compare_test_eq(
+        &run_restored(YOINK_SOURCE, "yoink_filter.pusl"),
+        "run",
+        "yoink",
+    );
+}